@@ -0,0 +1,39 @@
+// Compares finding chunk boundaries byte by byte against finding them
+// slice by slice. Run with `cargo bench --bench chunker --features
+// bench`; the wasm counterpart is bench_chunker in tests/wasm.rs.
+use replicache_client::prolly::chunker::Chunker;
+use std::time::{Duration, Instant};
+
+const LEN: usize = 16 << 20;
+const RUNS: u32 = 5;
+
+fn time<F: FnMut() -> Vec<usize>>(mut f: F) -> (Duration, Vec<usize>) {
+    let mut best = Duration::from_secs(u64::MAX);
+    let mut ends = Vec::new();
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        ends = f();
+        best = best.min(start.elapsed());
+    }
+    (best, ends)
+}
+
+fn main() {
+    let mut x: u32 = 1;
+    let data: Vec<u8> = (0..LEN)
+        .map(|_| {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (x >> 16) as u8
+        })
+        .collect();
+
+    let (bytewise, expected) = time(|| Chunker::default().boundaries_bytewise(&data));
+    let (slicewise, ends) = time(|| Chunker::default().boundaries(&data).collect());
+    assert_eq!(expected, ends);
+
+    let mb = LEN as f64 / (1 << 20) as f64;
+    println!("{} MB, {} chunks", mb, ends.len());
+    for (name, d) in &[("hash_byte", bytewise), ("boundaries", slicewise)] {
+        println!("{:>10}: {:?} ({:.0} MB/s)", name, d, mb / d.as_secs_f64());
+    }
+}
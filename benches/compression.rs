@@ -0,0 +1,63 @@
+// Reports how well chunk data compresses, and how fast. Run with
+// `cargo bench --bench compression`; tool/release.sh prints this next
+// to the bundle sizes, which include the codec.
+use replicache_client::dag::lz4;
+use std::time::{Duration, Instant};
+
+const RUNS: u32 = 5;
+
+fn time<T, F: FnMut() -> T>(mut f: F) -> (Duration, T) {
+    let mut best = Duration::from_secs(u64::MAX);
+    let mut result = f();
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        result = f();
+        best = best.min(start.elapsed());
+    }
+    (best, result)
+}
+
+// JSON like that of a typical todo app, as stored in chunks.
+fn json(rows: usize) -> Vec<u8> {
+    let rows: Vec<String> = (0..rows)
+        .map(|i| {
+            format!(
+                r#"{{"id":"{:08x}","listId":{},"text":"Todo item {}","complete":{},"order":{}}}"#,
+                i.wrapping_mul(2_654_435_761),
+                i % 7,
+                i,
+                i % 3 == 0,
+                i as f64 / 3.0
+            )
+        })
+        .collect();
+    format!("[{}]", rows.join(",")).into_bytes()
+}
+
+fn random(len: usize) -> Vec<u8> {
+    let mut x: u32 = 1;
+    (0..len)
+        .map(|_| {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (x >> 16) as u8
+        })
+        .collect()
+}
+
+fn main() {
+    for (name, data) in &[("json", json(50_000)), ("random", random(4 << 20))] {
+        let (compress, block) = time(|| lz4::compress(data));
+        let (decompress, out) = time(|| lz4::decompress(&block, data.len()));
+        assert_eq!(Some(data), out.as_ref());
+
+        let mb = data.len() as f64 / (1 << 20) as f64;
+        println!(
+            "{:>6}: {:.1} MB -> {:.1}% ({:.0} MB/s compress, {:.0} MB/s decompress)",
+            name,
+            mb,
+            100.0 * block.len() as f64 / data.len() as f64,
+            mb / compress.as_secs_f64(),
+            mb / decompress.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,269 @@
+// Inspects a database: an archive, as returned by the export RPC, or a
+// kv::filestore::FileStore.
+//
+// Archives are loaded into a MemStore as is, without the checks import
+// does, so that corrupt archives can be inspected too.
+//
+// There is no file system to inspect from wasm, so there is nothing to
+// build there.
+#![cfg_attr(target_arch = "wasm32", no_main)]
+#![cfg(not(target_arch = "wasm32"))]
+
+use async_std::task::block_on;
+use replicache_client::dag::read::Read;
+use replicache_client::dag::write::Write;
+use replicache_client::dag::{self, archive, Error};
+use replicache_client::hash::Hash;
+use replicache_client::kv::filestore::FileStore;
+use replicache_client::kv::memstore::MemStore;
+use replicache_client::kv::{ScanOptions, Store};
+use replicache_client::prolly::{blob, map};
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::process::exit;
+
+const USAGE: &str = "Usage: repc-inspect <db> <command>
+
+<db> is a file with either an archive from the export RPC, raw or
+base64-encoded, or a file store (see kv::filestore).
+
+Commands:
+  heads             List heads and the chunks they point to.
+  tree <head>       Print the chunks reachable from a head, depth first.
+  chunk <hash>      Dump a chunk's data and refs.
+  entries [prefix]  List the entries in the main head's map, and their
+                    values.
+  check             Check the integrity of the dag, see dag::check.";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (path, command, arg) = match args.as_slice() {
+        [path, command] => (path, command.as_str(), None),
+        [path, command, arg] => (path, command.as_str(), Some(arg.as_str())),
+        _ => usage(),
+    };
+    let store = match load(path) {
+        Ok(v) => v,
+        Err(e) => fail(&format!("Could not load {}: {}", path, e)),
+    };
+    let store = store.as_ref();
+    let ok = block_on(async {
+        let read = Read::new(store.read().await.unwrap());
+        match (command, arg) {
+            ("heads", None) => heads(store).await,
+            ("tree", Some(head)) => tree(&read, head).await,
+            ("chunk", Some(hash)) => chunk(&read, hash).await,
+            ("entries", prefix) => entries(&read, prefix.unwrap_or("")).await,
+            ("check", None) => check(store).await,
+            _ => usage(),
+        }
+    });
+    match ok {
+        Ok(true) => (),
+        Ok(false) => exit(1),
+        Err(e) => fail(&format!("{:?}", e)),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    exit(2)
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    exit(1)
+}
+
+fn load(path: &str) -> Result<Box<dyn Store>, String> {
+    let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+    if FileStore::is_file_store(&bytes) {
+        let store = FileStore::open(Path::new(path)).map_err(|e| e.to_string())?;
+        return Ok(Box::new(store));
+    }
+    let bytes = match archive::parse(&bytes) {
+        Ok(_) => bytes,
+        Err(_) => {
+            let text: Vec<u8> = bytes
+                .into_iter()
+                .filter(|b| !b.is_ascii_whitespace())
+                .collect();
+            data_encoding::base64::decode(&text).map_err(|_| "Not an archive".to_string())?
+        }
+    };
+    let archive = archive::parse(&bytes).map_err(|e| e.to_string())?;
+
+    let store = MemStore::new();
+    block_on(async {
+        let mut write = Write::new(store.write().await?);
+        for c in archive.chunks.iter() {
+            write.put_chunk(c).await?;
+        }
+        for (name, hash) in archive.heads.iter() {
+            write.set_head(name, hash).await?;
+        }
+        write.commit().await
+    })
+    .map_err(|e| format!("{:?}", e))?;
+    Ok(Box::new(store))
+}
+
+// Returns the keys in store with prefix, and their values.
+async fn scan(store: &dyn Store, prefix: &str) -> BTreeMap<String, Vec<u8>> {
+    let mut entries = BTreeMap::new();
+    let opts = ScanOptions {
+        prefix,
+        ..Default::default()
+    };
+    let read = store.read().await.unwrap();
+    read.scan(&opts, &mut |k, v| {
+        entries.insert(k.to_string(), v.to_vec());
+        true
+    })
+    .await
+    .unwrap();
+    entries
+}
+
+async fn head_names(store: &dyn Store) -> Vec<String> {
+    scan(store, "h/")
+        .await
+        .keys()
+        .map(|k| k["h/".len()..].to_string())
+        .collect()
+}
+
+async fn heads(store: &dyn Store) -> Result<bool, Error> {
+    let read = Read::new(store.read().await?);
+    for name in head_names(store).await {
+        match read.get_head(&name).await {
+            Ok(Some(h)) => println!("{} {}", h, name),
+            _ => println!("{:32} {} (invalid)", "?", name),
+        }
+    }
+    Ok(true)
+}
+
+async fn tree(read: &Read<'_>, head: &str) -> Result<bool, Error> {
+    let root = match read.get_head(head).await? {
+        Some(h) => h,
+        None => fail(&format!("Unknown head \"{}\"", head)),
+    };
+    let mut seen = HashSet::new();
+    let mut stack = vec![(root, 0)];
+    while let Some((h, depth)) = stack.pop() {
+        let indent = "  ".repeat(depth);
+        if !seen.insert(h) {
+            println!("{}{} (seen)", indent, h);
+            continue;
+        }
+        let c = match read.get_chunk(&h).await {
+            Ok(Some(c)) => c,
+            Ok(None) => {
+                println!("{}{} (missing)", indent, h);
+                continue;
+            }
+            Err(Error::CorruptStore) => {
+                println!("{}{} (invalid meta)", indent, h);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let refs: Vec<Hash> = c.refs().into_iter().flatten().collect();
+        println!(
+            "{}{} {} bytes, {} refs",
+            indent,
+            h,
+            c.data().len(),
+            refs.len()
+        );
+        stack.extend(refs.into_iter().rev().map(|r| (r, depth + 1)));
+    }
+    Ok(true)
+}
+
+async fn chunk(read: &Read<'_>, hash: &str) -> Result<bool, Error> {
+    let hash = match Hash::parse(hash) {
+        Ok(h) => h,
+        Err(_) => fail(&format!("Invalid hash \"{}\"", hash)),
+    };
+    let c = match read.get_chunk(&hash).await? {
+        Some(c) => c,
+        None => fail(&format!("No chunk {}", hash)),
+    };
+    println!("hash: {}", c.hash());
+    if Hash::of(c.data()) != *c.hash() {
+        println!(
+            "  (does not match data, which hashes to {})",
+            Hash::of(c.data())
+        );
+    }
+    println!("meta: {} bytes", c.meta().map_or(0, |m| m.len()));
+    let info = c.info();
+    println!("kind: {:?}", info.kind);
+    if info.uncompressed_size != 0 {
+        println!("uncompressed size: {} bytes", info.uncompressed_size);
+    }
+    if info.created != 0 {
+        println!("created: {} ms since epoch", info.created);
+    }
+    println!("refs:");
+    for r in c.refs().into_iter().flatten() {
+        println!("  {}", r);
+    }
+    println!("data: {} bytes", c.data().len());
+    dump(c.data());
+    Ok(true)
+}
+
+async fn entries(read: &Read<'_>, prefix: &str) -> Result<bool, Error> {
+    let root = match read.get_head("main").await? {
+        Some(h) => h,
+        None => fail("No main head"),
+    };
+    let opts = ScanOptions {
+        prefix,
+        ..Default::default()
+    };
+    let mut entries = Vec::new();
+    map::scan(read, &root, &opts, &mut |k, v| {
+        entries.push((k.to_string(), v.to_vec()));
+        true
+    })
+    .await?;
+    for (key, stored) in entries {
+        let blob = blob::blob_ref(&stored);
+        let value = blob::get_value(read, stored).await?;
+        match blob {
+            Some(h) => println!("{} (blob {}, {} bytes)", key, h, value.len()),
+            None => println!("{}", key),
+        }
+        dump(&value);
+    }
+    Ok(true)
+}
+
+// Prints data as text if it is, or as a hex dump.
+fn dump(data: &[u8]) {
+    if let Ok(s) = std::str::from_utf8(data) {
+        if !s.chars().any(|c| c.is_control() && c != '\n' && c != '\t') {
+            println!("{}", s);
+            return;
+        }
+    }
+    for (i, line) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let text: String = line
+            .iter()
+            .map(|&b| if b.is_ascii_graphic() { b as char } else { '.' })
+            .collect();
+        println!("{:08x}  {:47}  {}", i * 16, hex.join(" "), text);
+    }
+}
+
+async fn check(store: &dyn Store) -> Result<bool, Error> {
+    let kvr = store.read().await?;
+    let report = dag::check::check(kvr.as_ref()).await?;
+    println!("{}", report);
+    Ok(report.is_ok())
+}
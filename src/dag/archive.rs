@@ -0,0 +1,418 @@
+//! A portable, self-describing serialization of part of a dag, for
+//! backing up a database or moving it between browsers and native
+//! tooling.
+//!
+//! An archive is, with integers little-endian:
+//!
+//! ```text
+//! magic     "repcdag\0"
+//! version   u32
+//! chunks    u32 count, then per chunk:
+//!             hash  [u8; 20]
+//!             data  u32 length, bytes
+//!             meta  u32 length + 1 (0 if the chunk has no meta), bytes
+//! heads     u32 count, then per head:
+//!             name  u32 length, utf-8 bytes
+//!             hash  [u8; 20]
+//! ```
+//!
+//! Chunks are content-addressed: a chunk's hash is the hash of its
+//! data. Import relies on this to verify the chunks it is given.
+use super::chunk::Chunk;
+use super::read::Read;
+use super::verify::verify_chunk;
+use super::write::Write;
+use crate::hash::{self, Hash};
+use std::collections::{HashSet, VecDeque};
+use std::convert::TryInto;
+use std::fmt;
+
+const MAGIC: &[u8] = b"repcdag\0";
+const VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum Error {
+    Dag(super::Error),
+    UnknownHead(String),
+    MissingChunk(Hash),
+    // Errors in the archive itself.
+    BadMagic,
+    UnsupportedVersion(u32),
+    Truncated,
+    TrailingBytes,
+    InvalidHeadName,
+    HashMismatch(Hash),
+    InvalidMeta(Hash),
+}
+
+impl From<super::Error> for Error {
+    fn from(err: super::Error) -> Error {
+        Error::Dag(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Dag(e) => write!(f, "{:?}", e),
+            Error::UnknownHead(name) => write!(f, "Unknown head \"{}\"", name),
+            Error::MissingChunk(h) => write!(f, "Missing chunk {}", h),
+            Error::BadMagic => write!(f, "Not an archive"),
+            Error::UnsupportedVersion(v) => write!(f, "Unsupported archive version {}", v),
+            Error::Truncated => write!(f, "Archive is truncated"),
+            Error::TrailingBytes => write!(f, "Unexpected bytes after archive"),
+            Error::InvalidHeadName => write!(f, "Invalid head name in archive"),
+            Error::HashMismatch(h) => write!(f, "Chunk {} does not match its hash", h),
+            Error::InvalidMeta(h) => write!(f, "Chunk {} has invalid meta", h),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Returns an archive of the named head and every chunk reachable
+/// from it.
+pub async fn export(read: &Read<'_>, head: &str) -> Result<Vec<u8>> {
+    let root = match read.get_head(head).await? {
+        Some(h) => h,
+        None => return Err(Error::UnknownHead(head.into())),
+    };
+
+    let mut chunks = Vec::new();
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::new();
+    seen.insert(root);
+    queue.push_back(root);
+    while let Some(h) = queue.pop_front() {
+        let chunk = match read.get_chunk(&h).await? {
+            Some(c) => c,
+            None => return Err(Error::MissingChunk(h)),
+        };
+        if let Some(refs) = chunk.refs() {
+            for r in refs {
+                if seen.insert(r) {
+                    queue.push_back(r);
+                }
+            }
+        }
+        chunks.push(chunk);
+    }
+
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    put_u32(&mut buf, VERSION);
+    put_u32(&mut buf, chunks.len() as u32);
+    for c in chunks.iter() {
+        buf.extend_from_slice(&c.hash().sum);
+        put_bytes(&mut buf, c.data());
+        match c.meta() {
+            None => put_u32(&mut buf, 0),
+            Some(meta) => {
+                put_u32(&mut buf, meta.len() as u32 + 1);
+                buf.extend_from_slice(meta);
+            }
+        }
+    }
+    put_u32(&mut buf, 1);
+    put_bytes(&mut buf, head.as_bytes());
+    buf.extend_from_slice(&root.sum);
+    Ok(buf)
+}
+
+/// Verifies archive and writes its chunks and heads to write, which
+/// the caller commits. Every chunk must match its hash and have valid
+/// meta, and every ref and head must be to a chunk in the archive or
+/// already in the store.
+/// Returns the names of the heads set. Nothing is written on error.
+pub async fn import(write: &mut Write<'_>, archive: &[u8]) -> Result<Vec<String>> {
+    let Archive { chunks, heads } = parse(archive)?;
+
+    for c in chunks.iter() {
+        if Hash::of(c.data()) != *c.hash() {
+            return Err(Error::HashMismatch(*c.hash()));
+        }
+        if !verify_chunk(c) {
+            return Err(Error::InvalidMeta(*c.hash()));
+        }
+    }
+    let hashes: HashSet<Hash> = chunks.iter().map(|c| *c.hash()).collect();
+    let refs = chunks.iter().filter_map(|c| c.refs()).flatten();
+    for h in refs.chain(heads.iter().map(|(_, h)| *h)) {
+        if !hashes.contains(&h) && !write.has_chunk(&h).await? {
+            return Err(Error::MissingChunk(h));
+        }
+    }
+
+    for c in chunks.iter() {
+        write.put_chunk(c).await?;
+    }
+    for (name, h) in heads.iter() {
+        write.set_head(name, h).await?;
+    }
+    Ok(heads.into_iter().map(|(name, _)| name).collect())
+}
+
+pub struct Archive {
+    pub chunks: Vec<Chunk>,
+    pub heads: Vec<(String, Hash)>,
+}
+
+/// Parses archive as is, without verifying its chunks. See import().
+pub fn parse(archive: &[u8]) -> Result<Archive> {
+    let mut r = Reader { buf: archive };
+    if r.bytes(MAGIC.len()).map_err(|_| Error::BadMagic)? != MAGIC {
+        return Err(Error::BadMagic);
+    }
+    let version = r.u32()?;
+    if version != VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+
+    let mut chunks = Vec::new();
+    for _ in 0..r.u32()? {
+        let h = r.hash()?;
+        let data = r.sized_bytes()?.to_vec();
+        let meta = match r.u32()? {
+            0 => None,
+            n => Some(r.bytes(n as usize - 1)?.to_vec()),
+        };
+        chunks.push(Chunk::read(h, data, meta));
+    }
+
+    let mut heads = Vec::new();
+    for _ in 0..r.u32()? {
+        let name = match std::str::from_utf8(r.sized_bytes()?) {
+            Ok(s) => s.to_string(),
+            Err(_) => return Err(Error::InvalidHeadName),
+        };
+        heads.push((name, r.hash()?));
+    }
+
+    if !r.buf.is_empty() {
+        return Err(Error::TrailingBytes);
+    }
+    Ok(Archive { chunks, heads })
+}
+
+fn put_u32(buf: &mut Vec<u8>, n: u32) {
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(buf, bytes.len() as u32);
+    buf.extend_from_slice(bytes);
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        if n > self.buf.len() {
+            return Err(Error::Truncated);
+        }
+        let (bytes, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn sized_bytes(&mut self) -> Result<&'a [u8]> {
+        let n = self.u32()?;
+        self.bytes(n as usize)
+    }
+
+    fn hash(&mut self) -> Result<Hash> {
+        let bytes = self.bytes(hash::BYTE_LENGTH)?;
+        Ok(Hash::from_slice(bytes).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::memstore::MemStore;
+    use crate::kv::Store;
+
+    fn chunk(data: &[u8], refs: &[&Chunk]) -> Chunk {
+        let refs: Vec<Hash> = refs.iter().map(|c| *c.hash()).collect();
+        Chunk::new(Hash::of(data), data.to_vec(), &refs)
+    }
+
+    // Writes a diamond of chunks a -> (b, c) -> d to store, with "main"
+    // pointing at a and "other" at an unrelated chunk. Returns a.
+    async fn diamond(store: &MemStore) -> Chunk {
+        let d = chunk(b"d", &[]);
+        let b = chunk(b"b", &[&d]);
+        let c = chunk(b"c", &[&d]);
+        let a = chunk(b"a", &[&b, &c]);
+        let x = chunk(b"x", &[]);
+        let mut w = Write::new(store.write().await.unwrap());
+        for ch in &[&a, &b, &c, &d, &x] {
+            w.put_chunk(ch).await.unwrap();
+        }
+        w.set_head("main", a.hash()).await.unwrap();
+        w.set_head("other", x.hash()).await.unwrap();
+        w.commit().await.unwrap();
+        a
+    }
+
+    async fn export_main(store: &MemStore) -> Vec<u8> {
+        let read = Read::new(store.read().await.unwrap());
+        export(&read, "main").await.unwrap()
+    }
+
+    async fn import_into(store: &MemStore, archive: &[u8]) -> Result<Vec<String>> {
+        let mut w = Write::new(store.write().await.unwrap());
+        let result = import(&mut w, archive).await;
+        match result {
+            Ok(_) => w.commit().await.unwrap(),
+            Err(_) => w.rollback().await.unwrap(),
+        }
+        result
+    }
+
+    #[async_std::test]
+    async fn round_trip() {
+        let src = MemStore::new();
+        let a = diamond(&src).await;
+        let archive = export_main(&src).await;
+
+        let dst = MemStore::new();
+        assert_eq!(vec!["main"], import_into(&dst, &archive).await.unwrap());
+        let read = Read::new(dst.read().await.unwrap());
+        assert_eq!(Some(*a.hash()), read.get_head("main").await.unwrap());
+        assert_eq!(None, read.get_head("other").await.unwrap());
+        for data in &[&b"a"[..], b"b", b"c", b"d"] {
+            let c = read.get_chunk(&Hash::of(data)).await.unwrap().unwrap();
+            assert_eq!(*data, c.data());
+        }
+        assert!(a.eq(&read.get_chunk(a.hash()).await.unwrap().unwrap()));
+        assert!(!read.has_chunk(&Hash::of(b"x")).await.unwrap());
+
+        // The diamond's shared chunk is only archived once, and archives
+        // are stable.
+        assert_eq!(4, parse(&archive).unwrap().chunks.len());
+        assert_eq!(archive, export_main(&dst).await);
+    }
+
+    #[async_std::test]
+    async fn export_errors() {
+        let store = MemStore::new();
+        let read = Read::new(store.read().await.unwrap());
+        assert!(matches!(
+            export(&read, "main").await,
+            Err(Error::UnknownHead(name)) if name == "main"
+        ));
+
+        let mut w = Write::new(store.write().await.unwrap());
+        let dangling = chunk(b"a", &[&chunk(b"b", &[])]);
+        w.put_chunk(&dangling).await.unwrap();
+        w.set_head("main", dangling.hash()).await.unwrap();
+        w.commit().await.unwrap();
+        let read = Read::new(store.read().await.unwrap());
+        assert!(matches!(
+            export(&read, "main").await,
+            Err(Error::MissingChunk(h)) if h == Hash::of(b"b")
+        ));
+    }
+
+    #[async_std::test]
+    async fn import_errors() {
+        let src = MemStore::new();
+        diamond(&src).await;
+        let archive = export_main(&src).await;
+
+        async fn test(archive: &[u8], expected: &str) {
+            let store = MemStore::new();
+            let err = import_into(&store, archive).await.unwrap_err();
+            assert_eq!(expected, err.to_string());
+            // Nothing was written.
+            let read = store.read().await.unwrap();
+            assert!(!read.has("h/main").await.unwrap());
+        }
+
+        test(b"", "Not an archive").await;
+        test(b"repcdag\x01\x01\0\0\0", "Not an archive").await;
+        let mut v2 = archive.clone();
+        v2[MAGIC.len()] = 2;
+        test(&v2, "Unsupported archive version 2").await;
+        for n in &[MAGIC.len(), MAGIC.len() + 4, 40, archive.len() - 1] {
+            test(&archive[..*n], "Archive is truncated").await;
+        }
+        test(
+            &[&archive[..], &[0]].concat(),
+            "Unexpected bytes after archive",
+        )
+        .await;
+
+        // Corrupt the data of the first chunk, "a".
+        let a_data = MAGIC.len() + 4 + 4 + hash::BYTE_LENGTH + 4;
+        assert_eq!(b'a', archive[a_data]);
+        let mut corrupt = archive.clone();
+        corrupt[a_data] = b'z';
+        test(
+            &corrupt,
+            &format!("Chunk {} does not match its hash", Hash::of(b"a")),
+        )
+        .await;
+
+        // An archive without the chunks that heads and refs point to.
+        let mut missing = Vec::new();
+        missing.extend_from_slice(MAGIC);
+        put_u32(&mut missing, VERSION);
+        put_u32(&mut missing, 0);
+        put_u32(&mut missing, 1);
+        put_bytes(&mut missing, b"main");
+        missing.extend_from_slice(&Hash::of(b"a").sum);
+        test(&missing, &format!("Missing chunk {}", Hash::of(b"a"))).await;
+
+        // A chunk with meta that is not a flatbuffer.
+        let mut invalid = Vec::new();
+        invalid.extend_from_slice(MAGIC);
+        put_u32(&mut invalid, VERSION);
+        put_u32(&mut invalid, 1);
+        invalid.extend_from_slice(&Hash::of(b"a").sum);
+        put_bytes(&mut invalid, b"a");
+        put_u32(&mut invalid, 4);
+        invalid.extend_from_slice(&[0xff, 0, 0]);
+        put_u32(&mut invalid, 0);
+        test(
+            &invalid,
+            &format!("Chunk {} has invalid meta", Hash::of(b"a")),
+        )
+        .await;
+    }
+
+    #[async_std::test]
+    async fn import_onto_existing() {
+        // Refs may point to chunks already in the store.
+        let store = MemStore::new();
+        let b = chunk(b"b", &[]);
+        let a = chunk(b"a", &[&b]);
+        let mut w = Write::new(store.write().await.unwrap());
+        w.put_chunk(&b).await.unwrap();
+        w.commit().await.unwrap();
+
+        let mut archive = Vec::new();
+        archive.extend_from_slice(MAGIC);
+        put_u32(&mut archive, VERSION);
+        put_u32(&mut archive, 1);
+        archive.extend_from_slice(&a.hash().sum);
+        put_bytes(&mut archive, a.data());
+        put_u32(&mut archive, a.meta().unwrap().len() as u32 + 1);
+        archive.extend_from_slice(a.meta().unwrap());
+        put_u32(&mut archive, 1);
+        put_bytes(&mut archive, b"main");
+        archive.extend_from_slice(&a.hash().sum);
+        assert_eq!(vec!["main"], import_into(&store, &archive).await.unwrap());
+
+        let read = Read::new(store.read().await.unwrap());
+        assert_eq!(Some(*a.hash()), read.get_head("main").await.unwrap());
+    }
+}
@@ -0,0 +1,230 @@
+//! A bounded cache of chunks, least recently used first out.
+//!
+//! Chunks are immutable and content-addressed, so a cached chunk never
+//! goes stale and one cache can serve every transaction on a store. Only
+//! committed chunks may be added though: a chunk put in a transaction
+//! that rolls back is not in the store.
+use super::chunk::Chunk;
+use crate::hash::Hash;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// The default size of a cache, in bytes.
+pub const DEFAULT_SIZE: usize = 8 << 20;
+
+// Roughly what an entry costs beyond its chunk's data and meta.
+const ENTRY_OVERHEAD: usize = 64;
+
+pub struct Cache {
+    max_bytes: usize,
+    inner: RefCell<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    // Each chunk and when it was last used.
+    entries: HashMap<Hash, (Chunk, u64)>,
+    by_use: BTreeMap<u64, Hash>,
+    clock: u64,
+    stats: Stats,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+    pub chunks: usize,
+    pub bytes: usize,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} hits, {} misses, {} chunks, {} bytes",
+            self.hits, self.misses, self.chunks, self.bytes
+        )
+    }
+}
+
+impl Cache {
+    /// Returns a cache that holds up to max_bytes of chunks.
+    pub fn new(max_bytes: usize) -> Cache {
+        Cache {
+            max_bytes,
+            inner: RefCell::new(Inner::default()),
+        }
+    }
+
+    pub fn get(&self, hash: &Hash) -> Option<Chunk> {
+        let mut inner = self.inner.borrow_mut();
+        let inner = &mut *inner;
+        inner.clock += 1;
+        match inner.entries.get_mut(hash) {
+            None => {
+                inner.stats.misses += 1;
+                None
+            }
+            Some((c, used)) => {
+                inner.stats.hits += 1;
+                inner.by_use.remove(used);
+                *used = inner.clock;
+                inner.by_use.insert(*used, *hash);
+                Some(c.clone())
+            }
+        }
+    }
+
+    /// Adds c, which must be committed, evicting the least recently used
+    /// chunks to make room. Chunks too large to fit are not added.
+    pub fn put(&self, c: &Chunk) {
+        let size = entry_size(c);
+        if size > self.max_bytes {
+            return;
+        }
+        let mut inner = self.inner.borrow_mut();
+        if inner.entries.contains_key(c.hash()) {
+            return;
+        }
+        while inner.stats.bytes + size > self.max_bytes {
+            inner.evict();
+        }
+        inner.clock += 1;
+        let used = inner.clock;
+        inner.entries.insert(*c.hash(), (c.clone(), used));
+        inner.by_use.insert(used, *c.hash());
+        inner.stats.chunks += 1;
+        inner.stats.bytes += size;
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.inner.borrow().stats
+    }
+}
+
+impl Inner {
+    fn evict(&mut self) {
+        let (used, hash) = match self.by_use.iter().next() {
+            Some((used, hash)) => (*used, *hash),
+            None => return,
+        };
+        self.by_use.remove(&used);
+        if let Some((c, _)) = self.entries.remove(&hash) {
+            self.stats.chunks -= 1;
+            self.stats.bytes -= entry_size(&c);
+        }
+    }
+}
+
+fn entry_size(c: &Chunk) -> usize {
+    c.data().len() + c.meta().map_or(0, |m| m.len()) + ENTRY_OVERHEAD
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dag::read::Read;
+    use crate::dag::write::Write;
+    use crate::kv::memstore::MemStore;
+    use crate::kv::Store;
+
+    fn chunk(data: &[u8]) -> Chunk {
+        Chunk::new(Hash::of(data), data.to_vec(), &[Hash::of(b"r")])
+    }
+
+    #[test]
+    fn get_put() {
+        let cache = Cache::new(DEFAULT_SIZE);
+        let a = chunk(b"a");
+        assert_eq!(None, cache.get(a.hash()));
+        cache.put(&a);
+        cache.put(&a);
+        assert_eq!(Some(&a), cache.get(a.hash()).as_ref());
+        assert_eq!(Some(&a), cache.get(a.hash()).as_ref());
+        assert_eq!(b"a", cache.get(a.hash()).unwrap().data());
+
+        let stats = cache.stats();
+        assert_eq!((3, 1, 1), (stats.hits, stats.misses, stats.chunks));
+        assert_eq!(entry_size(&a), stats.bytes);
+        assert_eq!(
+            format!("3 hits, 1 misses, 1 chunks, {} bytes", stats.bytes),
+            stats.to_string()
+        );
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let data: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 100]).collect();
+        let chunks: Vec<Chunk> = data.iter().map(|d| chunk(d)).collect();
+        let size = entry_size(&chunks[0]);
+        let cache = Cache::new(size * 3);
+        for c in chunks[..3].iter() {
+            cache.put(c);
+        }
+        // Uses 0, so that 1 is the least recently used.
+        assert!(cache.get(chunks[0].hash()).is_some());
+        cache.put(&chunks[3]);
+        assert!(cache.get(chunks[1].hash()).is_none());
+        for i in &[0, 2, 3] {
+            assert!(cache.get(chunks[*i].hash()).is_some());
+        }
+        assert_eq!(3, cache.stats().chunks);
+        assert_eq!(size * 3, cache.stats().bytes);
+
+        // A large chunk evicts as many as it needs to, here 0 and 2.
+        let big = chunk(&[9; 250]);
+        cache.put(&big);
+        assert!(cache.get(big.hash()).is_some());
+        assert!(cache.get(chunks[3].hash()).is_some());
+        assert_eq!(size + entry_size(&big), cache.stats().bytes);
+
+        // Chunks larger than the cache are not added.
+        let huge = chunk(&[7; 1000]);
+        cache.put(&huge);
+        assert!(cache.get(huge.hash()).is_none());
+        assert!(cache.get(big.hash()).is_some());
+    }
+
+    #[async_std::test]
+    async fn read_through() {
+        let store = MemStore::new();
+        let a = chunk(b"a");
+        let mut w = Write::new(store.write().await.unwrap());
+        w.put_chunk(&a).await.unwrap();
+        w.commit().await.unwrap();
+
+        // The cache outlives transactions.
+        let cache = Cache::new(DEFAULT_SIZE);
+        for _ in 0..3 {
+            let r = Read::with_cache(store.read().await.unwrap(), &cache);
+            assert_eq!(Some(&a), r.get_chunk(a.hash()).await.unwrap().as_ref());
+            assert!(r.get_chunk(&Hash::of(b"b")).await.unwrap().is_none());
+        }
+        let stats = cache.stats();
+        assert_eq!((2, 4, 1), (stats.hits, stats.misses, stats.chunks));
+    }
+
+    #[async_std::test]
+    async fn get_chunks() {
+        let store = MemStore::new();
+        let (a, b) = (chunk(b"a"), chunk(b"b"));
+        let mut w = Write::new(store.write().await.unwrap());
+        w.put_chunk(&a).await.unwrap();
+        w.put_chunk(&b).await.unwrap();
+        w.commit().await.unwrap();
+
+        let cache = Cache::new(DEFAULT_SIZE);
+        let r = Read::with_cache(store.read().await.unwrap(), &cache);
+        assert_eq!(Some(&a), r.get_chunk(a.hash()).await.unwrap().as_ref());
+        let hashes = [*b.hash(), Hash::of(b"c"), *a.hash(), *b.hash()];
+        assert_eq!(
+            vec![Some(b.clone()), None, Some(a.clone()), Some(b.clone())],
+            r.get_chunks(&hashes).await.unwrap()
+        );
+        // Only a was cached before, and b is cached after.
+        let stats = cache.stats();
+        assert_eq!((1, 4, 2), (stats.hits, stats.misses, stats.chunks));
+        assert_eq!(Some(b), cache.get(&hashes[0]));
+    }
+}
@@ -0,0 +1,320 @@
+//! Integrity checking of a stored dag, for diagnosing partial or
+//! corrupt writes.
+use super::chunk::Chunk;
+use super::key::Key;
+use super::verify::verify_meta;
+use super::{read, Error, Result};
+use crate::hash::Hash;
+use crate::kv;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    // A key under the chunk prefix that is not a chunk key.
+    InvalidKey(String),
+    HashMismatch(Hash),
+    // Compressed data that does not decompress.
+    CorruptData(Hash),
+    InvalidMeta(Hash),
+    DanglingRef { from: Hash, to: Hash },
+    InvalidHead(String),
+    DanglingHead { name: String, to: Hash },
+    // A chunk not reachable from any head.
+    Orphan(Hash),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::InvalidKey(k) => write!(f, "invalid chunk key \"{}\"", k),
+            Problem::HashMismatch(h) => write!(f, "chunk {} does not match its hash", h),
+            Problem::CorruptData(h) => write!(f, "chunk {} does not decompress", h),
+            Problem::InvalidMeta(h) => write!(f, "chunk {} has invalid meta", h),
+            Problem::DanglingRef { from, to } => {
+                write!(f, "chunk {} refers to missing chunk {}", from, to)
+            }
+            Problem::InvalidHead(name) => write!(f, "head \"{}\" is invalid", name),
+            Problem::DanglingHead { name, to } => {
+                write!(f, "head \"{}\" points to missing chunk {}", name, to)
+            }
+            Problem::Orphan(h) => write!(f, "chunk {} is not reachable", h),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub chunks: usize,
+    pub heads: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for p in self.problems.iter() {
+            writeln!(f, "{}", p)?;
+        }
+        write!(
+            f,
+            "{} chunks, {} heads, {} problems",
+            self.chunks,
+            self.heads,
+            self.problems.len()
+        )
+    }
+}
+
+/// Checks the dag stored in kvr: that every chunk has valid meta and
+/// matches its hash once decompressed, that every ref and head points
+/// to a stored chunk, and that every chunk is reachable from a head.
+///
+/// Chunks are content-addressed, so a chunk's hash must be the hash of
+/// its data.
+pub async fn check(kvr: &dyn kv::Read) -> Result<Report> {
+    let mut report = Report::default();
+
+    let mut data = BTreeSet::new();
+    for key in scan_keys(kvr, "c/").await? {
+        match Key::parse(&key) {
+            Ok(Key::Chunk(h)) => {
+                data.insert(h);
+            }
+            // Including keys of the layout before migrate.
+            _ => report.problems.push(Problem::InvalidKey(key)),
+        }
+    }
+    report.chunks = data.len();
+
+    // Each chunk's refs, if its meta is valid.
+    let mut refs: BTreeMap<Hash, Vec<Hash>> = BTreeMap::new();
+    // Chunks are read raw rather than with read::get_chunk, which fails
+    // on invalid meta.
+    for h in data.iter() {
+        let record = match kvr.get(&Key::Chunk(*h).to_string()).await? {
+            Some(v) => v,
+            None => return Err(Error::CorruptStore),
+        };
+        let c = match Chunk::from_record(*h, record) {
+            Some(c) if !matches!(c.meta(), Some(m) if !verify_meta(m)) => c,
+            _ => {
+                report.problems.push(Problem::InvalidMeta(*h));
+                continue;
+            }
+        };
+        let chunk_refs: Vec<Hash> = c.refs().into_iter().flatten().collect();
+        match c.decompressed() {
+            None => report.problems.push(Problem::CorruptData(*h)),
+            Some(c) if Hash::of(c.data()) != *h => report.problems.push(Problem::HashMismatch(*h)),
+            _ => (),
+        }
+        for r in chunk_refs.iter().filter(|r| !data.contains(r)) {
+            report
+                .problems
+                .push(Problem::DanglingRef { from: *h, to: *r });
+        }
+        refs.insert(*h, chunk_refs);
+    }
+
+    let mut stack: Vec<Hash> = Vec::new();
+    for key in scan_keys(kvr, "h/").await? {
+        let name = &key["h/".len()..];
+        report.heads += 1;
+        match read::get_head(kvr, name).await {
+            Ok(Some(h)) if data.contains(&h) => stack.push(h),
+            Ok(Some(h)) => report.problems.push(Problem::DanglingHead {
+                name: name.into(),
+                to: h,
+            }),
+            _ => report.problems.push(Problem::InvalidHead(name.into())),
+        }
+    }
+    let mut reachable = BTreeSet::new();
+    while let Some(h) = stack.pop() {
+        if reachable.insert(h) {
+            stack.extend(refs.get(&h).into_iter().flatten());
+        }
+    }
+    for h in data.difference(&reachable) {
+        report.problems.push(Problem::Orphan(*h));
+    }
+
+    Ok(report)
+}
+
+async fn scan_keys(kvr: &dyn kv::Read, prefix: &str) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    let opts = kv::ScanOptions {
+        prefix,
+        ..Default::default()
+    };
+    kvr.scan(&opts, &mut |k, _| {
+        keys.push(k.to_string());
+        true
+    })
+    .await?;
+    Ok(keys)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dag::write::Write;
+    use crate::kv::memstore::MemStore;
+    use crate::kv::Store;
+
+    fn chunk(data: &[u8], refs: &[Hash]) -> Chunk {
+        Chunk::new(Hash::of(data), data.to_vec(), refs)
+    }
+
+    // Writes a -> (b, c) -> d with head "main" at a, and returns the
+    // hashes of a, b, c and d.
+    async fn healthy(store: &MemStore) -> [Hash; 4] {
+        let d = chunk(b"d", &[]);
+        let c = chunk(b"c", &[*d.hash()]);
+        let b = chunk(b"b", &[*d.hash()]);
+        let a = chunk(b"a", &[*b.hash(), *c.hash()]);
+        let mut w = Write::new(store.write().await.unwrap());
+        for ch in &[&a, &b, &c, &d] {
+            w.put_chunk(ch).await.unwrap();
+        }
+        w.set_head("main", a.hash()).await.unwrap();
+        w.commit().await.unwrap();
+        [*a.hash(), *b.hash(), *c.hash(), *d.hash()]
+    }
+
+    async fn problems(store: &MemStore) -> Vec<Problem> {
+        let kvr = store.read().await.unwrap();
+        check(kvr.as_ref()).await.unwrap().problems
+    }
+
+    // Problems are in hash order, not necessarily that of expected.
+    fn assert_problems(expected: &[Problem], actual: &[Problem]) {
+        assert_eq!(expected.len(), actual.len(), "{:?}", actual);
+        for p in expected {
+            assert!(actual.contains(p), "{:?} not in {:?}", p, actual);
+        }
+    }
+
+    #[async_std::test]
+    async fn ok() {
+        let store = MemStore::new();
+        let kvr = store.read().await.unwrap();
+        let report = check(kvr.as_ref()).await.unwrap();
+        assert!(report.is_ok());
+        assert_eq!("0 chunks, 0 heads, 0 problems", report.to_string());
+        drop(kvr);
+
+        healthy(&store).await;
+        let kvr = store.read().await.unwrap();
+        let report = check(kvr.as_ref()).await.unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!("4 chunks, 1 heads, 0 problems", report.to_string());
+    }
+
+    #[async_std::test]
+    async fn missing_chunk() {
+        let mut store = MemStore::new();
+        let [a, b, _, d] = healthy(&store).await;
+        let bad = Chunk::read(d, b"".to_vec(), None);
+        store
+            .put(&Key::Chunk(d).to_string(), &bad.to_record())
+            .await
+            .unwrap();
+        let wt = store.write().await.unwrap();
+        wt.del(&Key::Chunk(b).to_string()).await.unwrap();
+        wt.commit().await.unwrap();
+
+        assert_problems(
+            &[
+                Problem::HashMismatch(d),
+                Problem::DanglingRef { from: a, to: b },
+            ],
+            &problems(&store).await,
+        );
+    }
+
+    #[async_std::test]
+    async fn invalid_meta_and_keys() {
+        let mut store = MemStore::new();
+        let [_, b, _, _] = healthy(&store).await;
+        let bad = Chunk::read(b, b"b".to_vec(), Some(vec![1, 2, 3]));
+        store
+            .put(&Key::Chunk(b).to_string(), &bad.to_record())
+            .await
+            .unwrap();
+        store.put("c/nope", b"").await.unwrap();
+        // A key of the layout before migrate.
+        let legacy = Key::ChunkData(b).to_string();
+        store.put(&legacy, b"b").await.unwrap();
+        assert_problems(
+            &[
+                Problem::InvalidKey("c/nope".into()),
+                Problem::InvalidKey(legacy),
+                Problem::InvalidMeta(b),
+            ],
+            &problems(&store).await,
+        );
+    }
+
+    #[async_std::test]
+    async fn compressed() {
+        let mut store = MemStore::new();
+        let big = chunk(&b"compressible ".repeat(100), &[]);
+        let mut w = Write::new(store.write().await.unwrap());
+        w.put_chunk(&big).await.unwrap();
+        w.set_head("main", big.hash()).await.unwrap();
+        w.commit().await.unwrap();
+        assert!(problems(&store).await.is_empty());
+
+        // Keeps the compressed chunk's meta, but not its data.
+        let key = Key::Chunk(*big.hash()).to_string();
+        let mut record = store.get(&key).await.unwrap().unwrap();
+        let meta_len = record.len() - big.compressed().unwrap().data().len();
+        record.truncate(meta_len);
+        record.extend_from_slice(&[0xff; 8]);
+        store.put(&key, &record).await.unwrap();
+        assert_problems(
+            &[Problem::CorruptData(*big.hash())],
+            &problems(&store).await,
+        );
+    }
+
+    #[async_std::test]
+    async fn heads_and_orphans() {
+        let mut store = MemStore::new();
+        let [a, _, _, _] = healthy(&store).await;
+        let orphan = chunk(b"orphan", &[]);
+        let mut w = Write::new(store.write().await.unwrap());
+        w.put_chunk(&orphan).await.unwrap();
+        w.set_head("dangling", &Hash::of(b"nope")).await.unwrap();
+        w.commit().await.unwrap();
+        store.put("h/invalid", b"nope").await.unwrap();
+
+        assert_problems(
+            &[
+                Problem::DanglingHead {
+                    name: "dangling".into(),
+                    to: Hash::of(b"nope"),
+                },
+                Problem::InvalidHead("invalid".into()),
+                Problem::Orphan(*orphan.hash()),
+            ],
+            &problems(&store).await,
+        );
+
+        // Unreachable subtrees are orphans all the way down.
+        store.put("h/main", b"").await.unwrap();
+        let mut expected = problems(&store).await;
+        expected.retain(|p| matches!(p, Problem::Orphan(_)));
+        assert_eq!(5, expected.len());
+        assert!(expected.contains(&Problem::Orphan(a)));
+    }
+}
@@ -1,26 +1,59 @@
+use super::lz4;
 use super::meta_generated::meta;
+use crate::hash::{self, Hash};
 use flatbuffers::FlatBufferBuilder;
+use std::convert::{TryFrom, TryInto};
+
+pub use super::meta_generated::meta::{Compression, Kind};
+
+// Data smaller than this is stored uncompressed.
+const MIN_COMPRESS_SIZE: usize = 256;
+
+/// Optional metadata about a chunk, beyond its refs. Zero values are
+/// not recorded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Info {
+    pub kind: Kind,
+    // The size of the chunk's data before compression.
+    pub uncompressed_size: u64,
+    // When the chunk was created, in milliseconds since the Unix epoch.
+    pub created: u64,
+}
+
+impl Default for Info {
+    fn default() -> Info {
+        Info {
+            kind: Kind::Unknown,
+            uncompressed_size: 0,
+            created: 0,
+        }
+    }
+}
 
 // Chunk is an node in the immutable dag. Each node has a hash,
 // which uniquely identifies it, a blob of data, and zero or more
 // references to other chunks.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Chunk {
-    hash: String,
+    hash: Hash,
     data: Vec<u8>,
     meta: Option<(Vec<u8>, usize)>,
 }
 
 impl Chunk {
-    pub fn new(hash: String, data: Vec<u8>, refs: &[&str]) -> Chunk {
+    pub fn new(hash: Hash, data: Vec<u8>, refs: &[Hash]) -> Chunk {
+        Chunk::with_info(hash, data, refs, &Info::default())
+    }
+
+    pub fn with_info(hash: Hash, data: Vec<u8>, refs: &[Hash], info: &Info) -> Chunk {
         Chunk {
             hash,
             data,
-            meta: Chunk::create_meta(refs),
+            meta: Chunk::create_meta(refs, info, Compression::None),
         }
     }
 
-    pub fn read(hash: String, data: Vec<u8>, meta: Option<Vec<u8>>) -> Chunk {
+    pub fn read(hash: Hash, data: Vec<u8>, meta: Option<Vec<u8>>) -> Chunk {
         Chunk {
             hash,
             data,
@@ -28,7 +61,7 @@ impl Chunk {
         }
     }
 
-    pub fn hash(&self) -> &str {
+    pub fn hash(&self) -> &Hash {
         &self.hash
     }
 
@@ -39,15 +72,106 @@ impl Chunk {
     // TODO: It would be nice to flatten the option down into an empty
     // iterator for caller convenience, but could not find a zero-alloc
     // way to do that.
-    pub fn refs(&self) -> Option<impl Iterator<Item = &str>> {
+    pub fn refs(&self) -> Option<impl Iterator<Item = Hash> + '_> {
         if let Some(buf) = self.meta() {
             if let Some(refs) = meta::get_root_as_meta(buf).refs() {
-                return Some(refs.iter());
+                return Some(
+                    refs.chunks_exact(hash::BYTE_LENGTH)
+                        .filter_map(Hash::from_slice),
+                );
             }
         }
         None
     }
 
+    // Meta written before these fields existed reads as the defaults.
+    pub fn info(&self) -> Info {
+        match self.meta() {
+            None => Info::default(),
+            Some(buf) => {
+                let m = meta::get_root_as_meta(buf);
+                Info {
+                    kind: m.kind(),
+                    uncompressed_size: m.uncompressed_size(),
+                    created: m.created(),
+                }
+            }
+        }
+    }
+
+    /// How the chunk's data is stored. Chunks read with get_chunk are
+    /// always decompressed.
+    pub fn compression(&self) -> Compression {
+        match self.meta() {
+            None => Compression::None,
+            Some(buf) => meta::get_root_as_meta(buf).compression(),
+        }
+    }
+
+    // Returns the chunk as put_chunk stores it, with its data compressed,
+    // or None if compression would not save enough to be worth it.
+    pub(super) fn compressed(&self) -> Option<Chunk> {
+        let len = self.data.len();
+        if len < MIN_COMPRESS_SIZE {
+            return None;
+        }
+        let data = lz4::compress(&self.data);
+        if data.len() > len - len / 8 {
+            return None;
+        }
+        let refs: Vec<Hash> = self.refs().into_iter().flatten().collect();
+        let info = Info {
+            uncompressed_size: len as u64,
+            ..self.info()
+        };
+        Some(Chunk {
+            hash: self.hash,
+            data,
+            meta: Chunk::create_meta(&refs, &info, Compression::Lz4),
+        })
+    }
+
+    // Reverses compressed(), or returns None if the data does not
+    // decompress.
+    pub(super) fn decompressed(self) -> Option<Chunk> {
+        if self.compression() == Compression::None {
+            return Some(self);
+        }
+        let info = self.info();
+        let len = usize::try_from(info.uncompressed_size).ok()?;
+        let data = lz4::decompress(&self.data, len)?;
+        let refs: Vec<Hash> = self.refs().into_iter().flatten().collect();
+        Some(Chunk {
+            hash: self.hash,
+            data,
+            meta: Chunk::create_meta(&refs, &info, Compression::None),
+        })
+    }
+
+    // Returns the record that stores the chunk: the length of its meta
+    // as a little-endian u32 (0 if it has none), the meta, then the data.
+    pub(super) fn to_record(&self) -> Vec<u8> {
+        let meta = self.meta().unwrap_or(&[]);
+        let mut record = Vec::with_capacity(4 + meta.len() + self.data.len());
+        record.extend_from_slice(&(meta.len() as u32).to_le_bytes());
+        record.extend_from_slice(meta);
+        record.extend_from_slice(&self.data);
+        record
+    }
+
+    // Reverses to_record(), or returns None if record is too short. The
+    // meta is not verified.
+    pub(super) fn from_record(hash: Hash, mut record: Vec<u8>) -> Option<Chunk> {
+        let len = u32::from_le_bytes(record.get(..4)?.try_into().ok()?) as usize;
+        let end = len.checked_add(4)?;
+        let meta = match len {
+            0 => None,
+            _ => Some(record.get(4..end)?.to_vec()),
+        };
+        record.drain(..end.min(record.len()));
+        Some(Chunk::read(hash, record, meta))
+    }
+
     pub fn meta(&self) -> Option<&[u8]> {
         match &self.meta {
             None => None,
@@ -55,15 +179,34 @@ impl Chunk {
         }
     }
 
-    fn create_meta(refs: &[&str]) -> Option<(Vec<u8>, usize)> {
-        if refs.is_empty() {
+    fn create_meta(
+        refs: &[Hash],
+        info: &Info,
+        compression: Compression,
+    ) -> Option<(Vec<u8>, usize)> {
+        if refs.is_empty() && *info == Info::default() && compression == Compression::None {
             return None;
         }
         let mut builder = FlatBufferBuilder::default();
-        // TODO: You're supposed to be able to use start_vector() and
-        // push(), but cannot make compiler happy wih that.
-        let refs = builder.create_vector_of_strings(refs);
-        let meta = meta::Meta::create(&mut builder, &meta::MetaArgs { refs: Some(refs) });
+        let refs = if refs.is_empty() {
+            None
+        } else {
+            let mut sums = Vec::with_capacity(refs.len() * hash::BYTE_LENGTH);
+            for r in refs {
+                sums.extend_from_slice(&r.sum);
+            }
+            Some(builder.create_vector(&sums))
+        };
+        let meta = meta::Meta::create(
+            &mut builder,
+            &meta::MetaArgs {
+                refs,
+                kind: info.kind,
+                uncompressed_size: info.uncompressed_size,
+                created: info.created,
+                compression,
+            },
+        );
         builder.finish(meta, None);
         Some(builder.collapse())
     }
@@ -86,26 +229,149 @@ impl Eq for Chunk {}
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rand::rngs::StdRng;
+    use rand::{RngCore, SeedableRng};
 
     #[test]
     fn round_trip() {
-        fn test(hash: String, data: Vec<u8>, refs: &[&str]) {
-            let c = Chunk::new(hash.clone(), data.clone(), refs.clone());
+        fn test(hash: Hash, data: Vec<u8>, refs: &[Hash]) {
+            let c = Chunk::new(hash, data.clone(), refs);
             assert_eq!(&hash, c.hash());
             assert_eq!(data, c.data());
             if refs.is_empty() {
                 assert!(c.refs().is_none());
             } else {
-                assert_eq!(refs, c.refs().unwrap().collect::<Vec<&str>>().as_slice());
+                assert_eq!(refs, c.refs().unwrap().collect::<Vec<Hash>>().as_slice());
             }
 
             let buf = c.meta();
-            let c2 = Chunk::read(hash.clone(), data.clone(), buf.map(|b| b.to_vec()));
+            let c2 = Chunk::read(hash, data.clone(), buf.map(|b| b.to_vec()));
             assert_eq!(c, c2);
         }
 
-        test("".into(), vec![], &vec![]);
-        test("h".into(), vec![0], &vec!["r1"]);
-        test("h1".into(), vec![0, 1], &vec!["r1", "r2"]);
+        test(Hash::empty(), vec![], &[]);
+        test(Hash::of(b"h"), vec![0], &[Hash::of(b"r1")]);
+        test(
+            Hash::of(b"h1"),
+            vec![0, 1],
+            &[Hash::of(b"r1"), Hash::of(b"r2")],
+        );
+    }
+
+    #[test]
+    fn info() {
+        fn test(refs: &[Hash], info: Info) {
+            let c = Chunk::with_info(Hash::of(b"h"), vec![1], refs, &info);
+            assert_eq!(info, c.info());
+            let c2 = Chunk::read(*c.hash(), vec![1], c.meta().map(|b| b.to_vec()));
+            assert_eq!(info, c2.info());
+            assert_eq!(refs.len(), c2.refs().map_or(0, |r| r.count()));
+        }
+
+        test(&[], Info::default());
+        let info = Info {
+            kind: Kind::MapLeaf,
+            uncompressed_size: 1 << 40,
+            created: 1_600_000_000_000,
+        };
+        test(&[], info);
+        test(&[Hash::of(b"r1"), Hash::of(b"r2")], info);
+        test(
+            &[Hash::of(b"r1")],
+            Info {
+                kind: Kind::Blob,
+                ..Default::default()
+            },
+        );
+        assert!(Chunk::new(Hash::of(b"h"), vec![], &[]).meta().is_none());
+    }
+
+    #[test]
+    fn old_meta() {
+        // Meta as written before Info, with refs to Hash::of(b"r1").
+        let meta = vec![
+            12, 0, 0, 0, 8, 0, 8, 0, 0, 0, 4, 0, 8, 0, 0, 0, 4, 0, 0, 0, 20, 0, 0, 0, 213, 246,
+            231, 185, 53, 196, 113, 151, 42, 59, 249, 103, 108, 240, 20, 5, 24, 14, 36, 250,
+        ];
+        let c = Chunk::read(Hash::of(b"h"), vec![], Some(meta));
+        assert_eq!(Info::default(), c.info());
+        assert_eq!(vec![Hash::of(b"r1")], c.refs().unwrap().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn compression() {
+        let refs = [Hash::of(b"r1")];
+        let info = Info {
+            kind: Kind::MapLeaf,
+            created: 1,
+            ..Default::default()
+        };
+        let data = b"{\"a\":1,\"b\":2}".repeat(100);
+        let c = Chunk::with_info(Hash::of(&data), data.clone(), &refs, &info);
+        assert_eq!(Compression::None, c.compression());
+
+        let z = c.compressed().unwrap();
+        assert!(z.data().len() < data.len() / 4);
+        assert_eq!(Compression::Lz4, z.compression());
+        assert_eq!(data.len() as u64, z.info().uncompressed_size);
+        assert_eq!(&refs[..], z.refs().unwrap().collect::<Vec<_>>().as_slice());
+
+        let stored = Chunk::read(*z.hash(), z.data().to_vec(), z.meta().map(|m| m.to_vec()));
+        let c2 = stored.decompressed().unwrap();
+        assert_eq!(Compression::None, c2.compression());
+        assert_eq!(&data[..], c2.data());
+        assert_eq!(c, c2);
+        assert_eq!(Kind::MapLeaf, c2.info().kind);
+
+        // Small or incompressible data is stored as is.
+        assert!(Chunk::new(Hash::of(b"small"), b"small".repeat(10), &[])
+            .compressed()
+            .is_none());
+        let mut random = vec![0u8; 1000];
+        StdRng::seed_from_u64(1).fill_bytes(&mut random);
+        assert!(Chunk::new(Hash::of(&random), random, &[])
+            .compressed()
+            .is_none());
+
+        // Data that does not decompress.
+        let meta = z.meta().map(|m| m.to_vec());
+        let bad = Chunk::read(*z.hash(), vec![0xff; 10], meta);
+        assert!(bad.decompressed().is_none());
+    }
+
+    #[test]
+    fn record() {
+        fn test(data: &[u8], refs: &[Hash]) {
+            let c = Chunk::new(Hash::of(data), data.to_vec(), refs);
+            let record = c.to_record();
+            assert_eq!(
+                4 + c.meta().map_or(0, |m| m.len()) + data.len(),
+                record.len()
+            );
+            let c2 = Chunk::from_record(*c.hash(), record).unwrap();
+            assert_eq!(c.hash(), c2.hash());
+            assert_eq!(data, c2.data());
+            assert_eq!(c.meta(), c2.meta());
+        }
+        test(b"", &[]);
+        test(b"data", &[]);
+        test(b"", &[Hash::of(b"r1")]);
+        test(b"data", &[Hash::of(b"r1"), Hash::of(b"r2")]);
+
+        let h = Hash::of(b"h");
+        assert!(Chunk::from_record(h, vec![]).is_none());
+        assert!(Chunk::from_record(h, vec![0, 0, 0]).is_none());
+        assert!(Chunk::from_record(h, vec![2, 0, 0, 0, 1]).is_none());
+        assert!(Chunk::from_record(h, vec![0xff, 0xff, 0xff, 0xff]).is_none());
+        let c = Chunk::from_record(h, vec![0, 0, 0, 0, 1]).unwrap();
+        assert_eq!((&[1u8][..], None), (c.data(), c.meta()));
+    }
+
+    #[test]
+    fn meta_size() {
+        // Refs are stored as raw sums rather than strings.
+        let refs = [Hash::of(b"r1"), Hash::of(b"r2"), Hash::of(b"r3")];
+        let c = Chunk::new(Hash::of(b"h"), vec![], &refs);
+        assert!(c.meta().unwrap().len() < refs.len() * hash::STRING_LENGTH);
     }
 }
@@ -1,13 +1,22 @@
+use crate::hash::Hash;
 use std::fmt;
 use std::str;
 
 // KVKey is the key we use to store our dag data in the underlying
-// kvstore.
+// kvstore. Every key in a migrated kvstore is one of these: users'
+// entries are in the map at the "main" head (see prolly::map), so none
+// of them can collide with the keys of chunks, heads or settings.
 #[derive(Debug, PartialEq, Eq)]
 pub enum Key<'a> {
-    ChunkData(&'a str),
-    ChunkMeta(&'a str),
+    // A chunk's data and meta, as one record.
+    Chunk(Hash),
+    // The layout before chunks were single records, see dag::migrate.
+    ChunkData(Hash),
+    ChunkMeta(Hash),
     Head(&'a str),
+    ClientID,
+    ChunkerConfig,
+    Layout,
 }
 
 type ParseError = ();
@@ -16,23 +25,32 @@ type ParseError = ();
 // as that is the convention, and then "foo".parse() would work.
 // But I got lost in lifetime goop.
 impl<'a> Key<'_> {
+    #[allow(clippy::result_unit_err)]
     pub fn parse<'b>(s: &'b str) -> Result<Key<'b>, ParseError> {
         let mut parts = s.split::<'b>('/');
         let prefix: &str = parts.next().ok_or(())?;
         let content = parts.next().ok_or(())?;
         match prefix {
             "c" => {
-                let suffix = parts.next().ok_or(())?;
+                let suffix = parts.next();
                 if parts.next().is_some() {
                     return Err(());
                 }
+                let hash = Hash::parse(content).map_err(|_| ())?;
                 match suffix {
-                    "d" => Ok(Key::ChunkData(content)),
-                    "m" => Ok(Key::ChunkMeta(content)),
+                    None => Ok(Key::Chunk(hash)),
+                    Some("d") => Ok(Key::ChunkData(hash)),
+                    Some("m") => Ok(Key::ChunkMeta(hash)),
                     _ => Err(()),
                 }
             }
             "h" => Ok(Key::Head(content)),
+            "sys" => match (content, parts.next()) {
+                ("cid", None) => Ok(Key::ClientID),
+                ("chunker", None) => Ok(Key::ChunkerConfig),
+                ("layout", None) => Ok(Key::Layout),
+                _ => Err(()),
+            },
             _ => Err(()),
         }
     }
@@ -41,9 +59,13 @@ impl<'a> Key<'_> {
 impl<'a> fmt::Display for Key<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            Key::Chunk(hash) => write!(f, "c/{}", hash),
             Key::ChunkData(hash) => write!(f, "c/{}/d", hash),
             Key::ChunkMeta(hash) => write!(f, "c/{}/m", hash),
             Key::Head(name) => write!(f, "h/{}", name),
+            Key::ClientID => write!(f, "sys/cid"),
+            Key::ChunkerConfig => write!(f, "sys/chunker"),
+            Key::Layout => write!(f, "sys/layout"),
         }
     }
 }
@@ -52,20 +74,38 @@ impl<'a> fmt::Display for Key<'a> {
 mod tests {
     use super::*;
 
+    const H1: &str = "rmnjb8cjc5tblj21ed4qs821649eduie";
+
+    fn h1() -> Hash {
+        Hash::parse(H1).unwrap()
+    }
+
     #[test]
     fn to_string() {
         fn test(k: &Key, expected: &str) {
             assert_eq!(expected, k.to_string());
         }
-        test(&Key::ChunkData(""), "c//d");
-        test(&Key::ChunkData("a"), "c/a/d");
-        test(&Key::ChunkData("ab"), "c/ab/d");
-        test(&Key::ChunkMeta(""), "c//m");
-        test(&Key::ChunkMeta("a"), "c/a/m");
-        test(&Key::ChunkMeta("ab"), "c/ab/m");
+        test(
+            &Key::Chunk(Hash::empty()),
+            "c/00000000000000000000000000000000",
+        );
+        test(&Key::Chunk(h1()), &format!("c/{}", H1));
+        test(
+            &Key::ChunkData(Hash::empty()),
+            "c/00000000000000000000000000000000/d",
+        );
+        test(&Key::ChunkData(h1()), &format!("c/{}/d", H1));
+        test(
+            &Key::ChunkMeta(Hash::empty()),
+            "c/00000000000000000000000000000000/m",
+        );
+        test(&Key::ChunkMeta(h1()), &format!("c/{}/m", H1));
         test(&Key::Head(""), "h/");
         test(&Key::Head("a"), "h/a");
         test(&Key::Head("ab"), "h/ab");
+        test(&Key::ClientID, "sys/cid");
+        test(&Key::ChunkerConfig, "sys/chunker");
+        test(&Key::Layout, "sys/layout");
     }
 
     #[test]
@@ -80,26 +120,46 @@ mod tests {
         test(Err(()), "c//");
         test(Err(()), "c/a/");
         test(Err(()), "c/a/a");
-        test(Ok(Key::ChunkData("")), "c//d");
-        test(Ok(Key::ChunkData("a")), "c/a/d");
-        test(Ok(Key::ChunkData("ab")), "c/ab/d");
-        test(Ok(Key::ChunkMeta("")), "c//m");
-        test(Ok(Key::ChunkMeta("a")), "c/a/m");
-        test(Ok(Key::ChunkMeta("ab")), "c/ab/m");
+        test(Err(()), &format!("c/{}/a", H1));
+        test(Err(()), &format!("c/{}/d/", H1));
+        // Chunk keys must contain valid hashes.
+        test(Err(()), "c//d");
+        test(Err(()), "c/a/d");
+        test(Err(()), "c/a/m");
+        test(Err(()), &format!("c/{}0/d", H1));
+        test(Err(()), "c/a");
+        test(Err(()), &format!("c/{}0", H1));
+        test(Ok(Key::Chunk(h1())), &format!("c/{}", H1));
+        test(Ok(Key::ChunkData(h1())), &format!("c/{}/d", H1));
+        test(Ok(Key::ChunkMeta(h1())), &format!("c/{}/m", H1));
         test(Ok(Key::Head("")), "h/");
         test(Ok(Key::Head("a")), "h/a");
         test(Ok(Key::Head("ab")), "h/ab");
+        test(Err(()), "sys");
+        test(Err(()), "sys/");
+        test(Err(()), "sys/a");
+        test(Err(()), "sys/cid/");
+        test(Ok(Key::ClientID), "sys/cid");
+        test(Err(()), "sys/chunker/");
+        test(Ok(Key::ChunkerConfig), "sys/chunker");
+        test(Err(()), "sys/layout/");
+        test(Ok(Key::Layout), "sys/layout");
     }
 
     #[test]
     fn roundtrip() -> Result<(), ParseError> {
         let cases: &[Key] = &[
-            Key::ChunkData("".into()),
-            Key::ChunkData("a".into()),
-            Key::ChunkMeta("".into()),
-            Key::ChunkMeta("a".into()),
+            Key::Chunk(Hash::empty()),
+            Key::Chunk(h1()),
+            Key::ChunkData(Hash::empty()),
+            Key::ChunkData(h1()),
+            Key::ChunkMeta(Hash::empty()),
+            Key::ChunkMeta(h1()),
             Key::Head("".into()),
             Key::Head("a".into()),
+            Key::ClientID,
+            Key::ChunkerConfig,
+            Key::Layout,
         ];
 
         for c in cases {
@@ -0,0 +1,249 @@
+//! Compression of chunk data in the LZ4 block format.
+//!
+//! This is a small greedy compressor rather than a port of the
+//! reference implementation, to keep the wasm bundle small. It trades
+//! some ratio for size, but its output is valid LZ4 and decompress
+//! reads any LZ4 block.
+//!
+//! A block is a sequence of sequences, each a token byte whose high and
+//! low nibbles are the literal and match lengths (extended by bytes of
+//! 255 when 15), the literals, then a two-byte little-endian offset back
+//! to the match. The last sequence has only literals.
+use std::convert::TryInto;
+
+const MIN_MATCH: usize = 4;
+const MAX_OFFSET: usize = 0xffff;
+// The last match must start this far from the end of the input, and
+// the last literals must be at least this long. Decoders of the
+// reference implementation rely on these.
+const MF_LIMIT: usize = 12;
+const LAST_LITERALS: usize = 5;
+const HASH_BITS: u32 = 12;
+
+/// Compresses data into an LZ4 block.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 2 + 16);
+    let mut anchor = 0;
+    if data.len() > MF_LIMIT {
+        // Positions of recently seen four-byte sequences, by hash.
+        let mut table = vec![0u32; 1 << HASH_BITS];
+        let limit = data.len() - MF_LIMIT;
+        let match_limit = data.len() - LAST_LITERALS;
+        let mut i = 0;
+        while i < limit {
+            let seq = read_u32(data, i);
+            let h = hash(seq);
+            let candidate = table[h] as usize;
+            table[h] = i as u32;
+            if candidate >= i || i - candidate > MAX_OFFSET || read_u32(data, candidate) != seq {
+                i += 1;
+                continue;
+            }
+            let mut len = MIN_MATCH;
+            while i + len < match_limit && data[candidate + len] == data[i + len] {
+                len += 1;
+            }
+            put_sequence(&mut out, &data[anchor..i], Some((i - candidate, len)));
+            i += len;
+            anchor = i;
+        }
+    }
+    put_sequence(&mut out, &data[anchor..], None);
+    out
+}
+
+/// Decompresses an LZ4 block that decompresses to exactly len bytes, or
+/// returns None if block is not one.
+pub fn decompress(block: &[u8], len: usize) -> Option<Vec<u8>> {
+    // A byte of block expands to at most 255 bytes, so a larger len
+    // must be corrupt and should not be allocated.
+    if len / 255 > block.len() {
+        return None;
+    }
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    loop {
+        let token = *block.get(i)?;
+        i += 1;
+        let literals = read_len(block, &mut i, (token >> 4) as usize)?;
+        out.extend_from_slice(block.get(i..i.checked_add(literals)?)?);
+        i += literals;
+        if out.len() > len {
+            return None;
+        }
+        if i == block.len() {
+            break;
+        }
+        let offset = u16::from_le_bytes(block.get(i..i + 2)?.try_into().ok()?) as usize;
+        i += 2;
+        if offset == 0 || offset > out.len() {
+            return None;
+        }
+        let match_len = read_len(block, &mut i, (token & 0xf) as usize)?.checked_add(MIN_MATCH)?;
+        if out.len() + match_len > len {
+            return None;
+        }
+        // The match may overlap what it copies, so copy byte by byte.
+        let start = out.len() - offset;
+        for j in start..start + match_len {
+            out.push(out[j]);
+        }
+    }
+    if out.len() == len {
+        Some(out)
+    } else {
+        None
+    }
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn hash(seq: u32) -> usize {
+    (seq.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+fn put_sequence(out: &mut Vec<u8>, literals: &[u8], m: Option<(usize, usize)>) {
+    let match_len = m.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push((literals.len().min(15) << 4 | match_len.min(15)) as u8);
+    put_len(out, literals.len());
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = m {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        put_len(out, match_len);
+    }
+}
+
+// Writes the bytes that extend a length of 15 or more in a token.
+fn put_len(out: &mut Vec<u8>, len: usize) {
+    if len < 15 {
+        return;
+    }
+    let mut rest = len - 15;
+    while rest >= 255 {
+        out.push(255);
+        rest -= 255;
+    }
+    out.push(rest as u8);
+}
+
+fn read_len(block: &[u8], i: &mut usize, nibble: usize) -> Option<usize> {
+    let mut len = nibble;
+    if nibble == 15 {
+        loop {
+            let b = *block.get(*i)?;
+            *i += 1;
+            len = len.checked_add(b as usize)?;
+            if b != 255 {
+                break;
+            }
+        }
+    }
+    Some(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, RngCore, SeedableRng};
+
+    fn round_trip(data: &[u8]) -> usize {
+        let block = compress(data);
+        assert_eq!(Some(data), decompress(&block, data.len()).as_deref());
+        block.len()
+    }
+
+    fn json(n: usize) -> Vec<u8> {
+        let rows: Vec<String> = (0..n)
+            .map(|i| {
+                format!(
+                    r#"{{"id":{},"title":"Item number {}","done":{},"tags":["a","b"]}}"#,
+                    i,
+                    i * 7,
+                    i % 3 == 0
+                )
+            })
+            .collect();
+        format!("[{}]", rows.join(",")).into_bytes()
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for len in 0..100 {
+            let mut data = vec![0u8; len];
+            rng.fill_bytes(&mut data);
+            round_trip(&data);
+            round_trip(&vec![b'a'; len]);
+        }
+        for len in &[1000, 65536, 70000, 300_000] {
+            let mut data = vec![0u8; *len];
+            rng.fill_bytes(&mut data);
+            // Incompressible data grows by little.
+            assert!(round_trip(&data) <= len + len / 255 + 16);
+            assert!(round_trip(&vec![7u8; *len]) < len / 200 + 16);
+        }
+        // Matches further back than an offset can reach.
+        let mut far = vec![0u8; 70000];
+        rng.fill_bytes(&mut far);
+        let copy = far[..1000].to_vec();
+        far.extend_from_slice(&copy);
+        round_trip(&far);
+    }
+
+    #[test]
+    fn ratio() {
+        let data = json(1000);
+        let len = round_trip(&data);
+        assert!(len * 3 < data.len(), "{} of {}", len, data.len());
+    }
+
+    #[test]
+    fn reference_format() {
+        // Blocks as the reference implementation writes them.
+        assert_eq!(Some(vec![]), decompress(&[0], 0));
+        // abc, a 15 byte match at offset 3, then literals abcab.
+        let block = b"\x3babc\x03\x00\x50abcab";
+        assert_eq!(
+            Some(b"abcabcabcabcabcabcabcab".to_vec()),
+            decompress(block, 23)
+        );
+        assert_eq!(block.to_vec(), compress(b"abcabcabcabcabcabcabcab"));
+        // The last five bytes are always literals.
+        let block = compress(&[b'x'; 32]);
+        assert_eq!(
+            &[b'x'; LAST_LITERALS][..],
+            &block[block.len() - LAST_LITERALS..]
+        );
+    }
+
+    #[test]
+    fn corrupt() {
+        let data = json(20);
+        let block = compress(&data);
+        assert_eq!(None, decompress(&block, data.len() - 1));
+        assert_eq!(None, decompress(&block, data.len() + 1));
+        for n in 0..block.len() {
+            assert_eq!(None, decompress(&block[..n], data.len()));
+        }
+        assert_eq!(None, decompress(&[0x10, b'a', 0, 0], 5));
+        assert_eq!(None, decompress(&[0x10, b'a', 2, 0], 5));
+        assert_eq!(None, decompress(&[0xf0, 255, 255], 1000));
+        assert_eq!(None, decompress(&[0], usize::MAX));
+
+        // Random changes never panic.
+        let mut rng = StdRng::seed_from_u64(2);
+        for _ in 0..10_000 {
+            let mut b = block.clone();
+            for _ in 0..rng.gen_range(1, 4) {
+                let i = rng.gen_range(0, b.len());
+                b[i] = rng.gen();
+            }
+            if let Some(v) = decompress(&b, data.len()) {
+                assert_eq!(data.len(), v.len());
+            }
+        }
+    }
+}
@@ -1,4 +1,4 @@
-#![allow(clippy::redundant_field_names)]
+#![allow(clippy::redundant_field_names, clippy::redundant_static_lifetimes)]
 
 // automatically generated by the FlatBuffers compiler, do not modify
 
@@ -17,6 +17,122 @@ pub mod meta {
     extern crate flatbuffers;
     use self::flatbuffers::EndianScalar;
 
+    #[allow(non_camel_case_types)]
+    #[repr(u8)]
+    #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+    pub enum Kind {
+        Unknown = 0,
+        Commit = 1,
+        MapLeaf = 2,
+        MapInternal = 3,
+        Blob = 4,
+    }
+
+    pub const ENUM_MIN_KIND: u8 = 0;
+    pub const ENUM_MAX_KIND: u8 = 4;
+
+    impl<'a> flatbuffers::Follow<'a> for Kind {
+        type Inner = Self;
+        #[inline]
+        fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+            flatbuffers::read_scalar_at::<Self>(buf, loc)
+        }
+    }
+
+    impl flatbuffers::EndianScalar for Kind {
+        #[inline]
+        fn to_little_endian(self) -> Self {
+            let n = u8::to_le(self as u8);
+            let p = &n as *const u8 as *const Kind;
+            unsafe { *p }
+        }
+        #[inline]
+        fn from_little_endian(self) -> Self {
+            let n = u8::from_le(self as u8);
+            let p = &n as *const u8 as *const Kind;
+            unsafe { *p }
+        }
+    }
+
+    impl flatbuffers::Push for Kind {
+        type Output = Kind;
+        #[inline]
+        fn push(&self, dst: &mut [u8], _rest: &[u8]) {
+            flatbuffers::emplace_scalar::<Kind>(dst, *self);
+        }
+    }
+
+    #[allow(non_camel_case_types)]
+    pub const ENUM_VALUES_KIND: [Kind; 5] = [
+        Kind::Unknown,
+        Kind::Commit,
+        Kind::MapLeaf,
+        Kind::MapInternal,
+        Kind::Blob,
+    ];
+
+    #[allow(non_camel_case_types)]
+    pub const ENUM_NAMES_KIND: [&'static str; 5] =
+        ["Unknown", "Commit", "MapLeaf", "MapInternal", "Blob"];
+
+    pub fn enum_name_kind(e: Kind) -> &'static str {
+        let index = e as u8;
+        ENUM_NAMES_KIND[index as usize]
+    }
+
+    #[allow(non_camel_case_types)]
+    #[repr(u8)]
+    #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+    pub enum Compression {
+        None = 0,
+        Lz4 = 1,
+    }
+
+    pub const ENUM_MIN_COMPRESSION: u8 = 0;
+    pub const ENUM_MAX_COMPRESSION: u8 = 1;
+
+    impl<'a> flatbuffers::Follow<'a> for Compression {
+        type Inner = Self;
+        #[inline]
+        fn follow(buf: &'a [u8], loc: usize) -> Self::Inner {
+            flatbuffers::read_scalar_at::<Self>(buf, loc)
+        }
+    }
+
+    impl flatbuffers::EndianScalar for Compression {
+        #[inline]
+        fn to_little_endian(self) -> Self {
+            let n = u8::to_le(self as u8);
+            let p = &n as *const u8 as *const Compression;
+            unsafe { *p }
+        }
+        #[inline]
+        fn from_little_endian(self) -> Self {
+            let n = u8::from_le(self as u8);
+            let p = &n as *const u8 as *const Compression;
+            unsafe { *p }
+        }
+    }
+
+    impl flatbuffers::Push for Compression {
+        type Output = Compression;
+        #[inline]
+        fn push(&self, dst: &mut [u8], _rest: &[u8]) {
+            flatbuffers::emplace_scalar::<Compression>(dst, *self);
+        }
+    }
+
+    #[allow(non_camel_case_types)]
+    pub const ENUM_VALUES_COMPRESSION: [Compression; 2] = [Compression::None, Compression::Lz4];
+
+    #[allow(non_camel_case_types)]
+    pub const ENUM_NAMES_COMPRESSION: [&'static str; 2] = ["None", "Lz4"];
+
+    pub fn enum_name_compression(e: Compression) -> &'static str {
+        let index = e as u8;
+        ENUM_NAMES_COMPRESSION[index as usize]
+    }
+
     pub enum MetaOffset {}
     #[derive(Copy, Clone, Debug, PartialEq)]
 
@@ -45,33 +161,72 @@ pub mod meta {
             args: &'args MetaArgs<'args>,
         ) -> flatbuffers::WIPOffset<Meta<'bldr>> {
             let mut builder = MetaBuilder::new(_fbb);
+            builder.add_created(args.created);
+            builder.add_uncompressed_size(args.uncompressed_size);
             if let Some(x) = args.refs {
                 builder.add_refs(x);
             }
+            builder.add_compression(args.compression);
+            builder.add_kind(args.kind);
             builder.finish()
         }
 
-        pub const VT_REFS: flatbuffers::VOffsetT = 4;
+        pub const VT_REFS: flatbuffers::VOffsetT = 6;
+        pub const VT_KIND: flatbuffers::VOffsetT = 8;
+        pub const VT_UNCOMPRESSED_SIZE: flatbuffers::VOffsetT = 10;
+        pub const VT_CREATED: flatbuffers::VOffsetT = 12;
+        pub const VT_COMPRESSION: flatbuffers::VOffsetT = 14;
 
         #[inline]
-        pub fn refs(
-            &self,
-        ) -> Option<flatbuffers::Vector<'a, flatbuffers::ForwardsUOffset<&'a str>>> {
-            self._tab.get::<flatbuffers::ForwardsUOffset<
-                flatbuffers::Vector<flatbuffers::ForwardsUOffset<&'a str>>,
-            >>(Meta::VT_REFS, None)
+        pub fn refs(&self) -> Option<&'a [u8]> {
+            self._tab
+                .get::<flatbuffers::ForwardsUOffset<flatbuffers::Vector<'a, u8>>>(
+                    Meta::VT_REFS,
+                    None,
+                )
+                .map(|v| v.safe_slice())
+        }
+        #[inline]
+        pub fn kind(&self) -> Kind {
+            self._tab
+                .get::<Kind>(Meta::VT_KIND, Some(Kind::Unknown))
+                .unwrap()
+        }
+        #[inline]
+        pub fn uncompressed_size(&self) -> u64 {
+            self._tab
+                .get::<u64>(Meta::VT_UNCOMPRESSED_SIZE, Some(0))
+                .unwrap()
+        }
+        #[inline]
+        pub fn created(&self) -> u64 {
+            self._tab.get::<u64>(Meta::VT_CREATED, Some(0)).unwrap()
+        }
+        #[inline]
+        pub fn compression(&self) -> Compression {
+            self._tab
+                .get::<Compression>(Meta::VT_COMPRESSION, Some(Compression::None))
+                .unwrap()
         }
     }
 
     pub struct MetaArgs<'a> {
-        pub refs: Option<
-            flatbuffers::WIPOffset<flatbuffers::Vector<'a, flatbuffers::ForwardsUOffset<&'a str>>>,
-        >,
+        pub refs: Option<flatbuffers::WIPOffset<flatbuffers::Vector<'a, u8>>>,
+        pub kind: Kind,
+        pub uncompressed_size: u64,
+        pub created: u64,
+        pub compression: Compression,
     }
     impl<'a> Default for MetaArgs<'a> {
         #[inline]
         fn default() -> Self {
-            MetaArgs { refs: None }
+            MetaArgs {
+                refs: None,
+                kind: Kind::Unknown,
+                uncompressed_size: 0,
+                created: 0,
+                compression: Compression::None,
+            }
         }
     }
     pub struct MetaBuilder<'a: 'b, 'b> {
@@ -80,16 +235,33 @@ pub mod meta {
     }
     impl<'a: 'b, 'b> MetaBuilder<'a, 'b> {
         #[inline]
-        pub fn add_refs(
-            &mut self,
-            refs: flatbuffers::WIPOffset<
-                flatbuffers::Vector<'b, flatbuffers::ForwardsUOffset<&'b str>>,
-            >,
-        ) {
+        pub fn add_refs(&mut self, refs: flatbuffers::WIPOffset<flatbuffers::Vector<'b, u8>>) {
             self.fbb_
                 .push_slot_always::<flatbuffers::WIPOffset<_>>(Meta::VT_REFS, refs);
         }
         #[inline]
+        pub fn add_kind(&mut self, kind: Kind) {
+            self.fbb_
+                .push_slot::<Kind>(Meta::VT_KIND, kind, Kind::Unknown);
+        }
+        #[inline]
+        pub fn add_uncompressed_size(&mut self, uncompressed_size: u64) {
+            self.fbb_
+                .push_slot::<u64>(Meta::VT_UNCOMPRESSED_SIZE, uncompressed_size, 0);
+        }
+        #[inline]
+        pub fn add_created(&mut self, created: u64) {
+            self.fbb_.push_slot::<u64>(Meta::VT_CREATED, created, 0);
+        }
+        #[inline]
+        pub fn add_compression(&mut self, compression: Compression) {
+            self.fbb_.push_slot::<Compression>(
+                Meta::VT_COMPRESSION,
+                compression,
+                Compression::None,
+            );
+        }
+        #[inline]
         pub fn new(_fbb: &'b mut flatbuffers::FlatBufferBuilder<'a>) -> MetaBuilder<'a, 'b> {
             let start = _fbb.start_table();
             MetaBuilder {
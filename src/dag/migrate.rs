@@ -0,0 +1,296 @@
+//! Migration of stored data to the current layout.
+//!
+//! Layouts so far, as recorded under sys/layout:
+//!
+//! - None: chunks are stored as two keys, c/<hash>/d for data and
+//!   c/<hash>/m for meta, which took two round trips to read, and users'
+//!   entries are stored under their own keys, beside the dag's.
+//! - "1": chunks are one record under c/<hash>, see Chunk::to_record.
+//! - "2": entries are in the map at the "main" head, see prolly::map.
+use super::chunk::Chunk;
+use super::key::Key;
+use super::read::Read;
+use super::{write, Result};
+use crate::hash::Hash;
+use crate::kv;
+use crate::prolly::map;
+use std::collections::BTreeSet;
+
+/// The current layout.
+pub const LAYOUT: &str = "2";
+
+// The head of the map of entries, as in dispatch.
+const MAIN: &str = "main";
+
+// The head a main head found when entries are moved is kept under,
+// since only imports set heads before entries moved into a map.
+const LEGACY_MAIN: &str = "legacy-main";
+
+/// How much a migration moved.
+#[derive(Debug, Default, PartialEq)]
+pub struct Moved {
+    pub chunks: usize,
+    pub entries: usize,
+}
+
+/// Moves a store in an older layout to the current one, in one
+/// transaction. Moves nothing if the store is already migrated.
+///
+/// Chunks are moved as stored, without verifying them; dag::check does
+/// that. Entries are every key that is not one of the dag's, see
+/// dag::key: entries whose keys looked like the dag's were never told
+/// apart from it, and stay where they are.
+pub async fn migrate(store: &dyn kv::Store) -> Result<Moved> {
+    let layout_key = Key::Layout.to_string();
+    let wt = store.write().await?;
+    let layout = wt.get(&layout_key).await?;
+    if layout.as_deref() == Some(LAYOUT.as_bytes()) {
+        wt.rollback().await?;
+        return Ok(Moved::default());
+    }
+    let mut moved = Moved::default();
+    if layout.is_none() {
+        moved.chunks = move_chunks(wt.as_ref()).await?;
+    }
+    moved.entries = move_entries(wt.as_ref()).await?;
+    wt.put(&layout_key, LAYOUT.as_bytes()).await?;
+    wt.commit().await?;
+    Ok(moved)
+}
+
+// Moves chunks stored as data and meta keys into records.
+async fn move_chunks(wt: &dyn kv::Write) -> Result<usize> {
+    let mut data = BTreeSet::new();
+    let mut stale = Vec::new();
+    let opts = kv::ScanOptions {
+        prefix: "c/",
+        ..Default::default()
+    };
+    wt.as_read()
+        .scan(&opts, &mut |k, _| {
+            match Key::parse(k) {
+                Ok(Key::ChunkData(h)) => {
+                    data.insert(h);
+                }
+                // Including meta without data, left by torn writes.
+                Ok(Key::ChunkMeta(_)) => stale.push(k.to_string()),
+                _ => (),
+            }
+            true
+        })
+        .await?;
+
+    for h in data.iter() {
+        let c = read_legacy(wt.as_read(), h).await?;
+        wt.put(&Key::Chunk(*h).to_string(), &c.to_record()).await?;
+        wt.del(&Key::ChunkData(*h).to_string()).await?;
+    }
+    for k in stale.iter() {
+        wt.del(k).await?;
+    }
+    Ok(data.len())
+}
+
+// Moves entries stored under their own keys into a map at the main
+// head. Their values are kept as stored: JSON, or blob refs, which
+// blob::get_value reads alike.
+async fn move_entries(wt: &dyn kv::Write) -> Result<usize> {
+    let mut entries = Vec::new();
+    wt.as_read()
+        .scan(&kv::ScanOptions::default(), &mut |k, v| {
+            if Key::parse(k).is_err() {
+                entries.push((k.to_string(), v.to_vec()));
+            }
+            true
+        })
+        .await?;
+    if entries.is_empty() {
+        return Ok(0);
+    }
+
+    for (k, _) in entries.iter() {
+        wt.del(k).await?;
+    }
+    let count = entries.len();
+    let read = Read::new(Box::new(wt.as_read()));
+    if let Some(old) = read.get_head(MAIN).await? {
+        write::set_head(wt, LEGACY_MAIN, &old).await?;
+    }
+    let root = map::put(wt, &read, None, entries).await?;
+    write::set_head(wt, MAIN, &root).await?;
+    Ok(count)
+}
+
+async fn read_legacy(kvr: &dyn kv::Read, hash: &Hash) -> Result<Chunk> {
+    let data = kvr
+        .get(&Key::ChunkData(*hash).to_string())
+        .await?
+        .unwrap_or_default();
+    let meta = kvr.get(&Key::ChunkMeta(*hash).to_string()).await?;
+    Ok(Chunk::read(*hash, data, meta))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::memstore::MemStore;
+    use crate::kv::Store;
+    use crate::prolly::blob;
+    use crate::prolly::chunker::ChunkerConfig;
+
+    async fn put_legacy(store: &mut MemStore, c: &Chunk) {
+        store
+            .put(&Key::ChunkData(*c.hash()).to_string(), c.data())
+            .await
+            .unwrap();
+        if let Some(meta) = c.meta() {
+            store
+                .put(&Key::ChunkMeta(*c.hash()).to_string(), meta)
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn keys(store: &MemStore) -> Vec<String> {
+        let mut keys = Vec::new();
+        let rt = store.read().await.unwrap();
+        rt.scan(&Default::default(), &mut |k, _| {
+            keys.push(k.to_string());
+            true
+        })
+        .await
+        .unwrap();
+        keys
+    }
+
+    #[async_std::test]
+    async fn migrates_once() {
+        let mut store = MemStore::new();
+        let a = Chunk::new(Hash::of(b"a"), b"a".to_vec(), &[]);
+        let b = Chunk::new(Hash::of(b"b"), b"b".to_vec(), &[*a.hash()]);
+        put_legacy(&mut store, &a).await;
+        put_legacy(&mut store, &b).await;
+        let torn = Key::ChunkMeta(Hash::of(b"torn")).to_string();
+        store.put(&torn, b.meta().unwrap()).await.unwrap();
+        store
+            .put("h/main", b.hash().to_string().as_bytes())
+            .await
+            .unwrap();
+
+        let moved = Moved {
+            chunks: 2,
+            entries: 0,
+        };
+        assert_eq!(moved, migrate(&store).await.unwrap());
+        let mut expected = vec![
+            Key::Chunk(*a.hash()).to_string(),
+            Key::Chunk(*b.hash()).to_string(),
+            "h/main".to_string(),
+            Key::Layout.to_string(),
+        ];
+        expected.sort();
+        assert_eq!(expected, keys(&store).await);
+
+        let r = Read::new(store.read().await.unwrap());
+        assert_eq!(Some(&a), r.get_chunk(a.hash()).await.unwrap().as_ref());
+        assert_eq!(Some(&b), r.get_chunk(b.hash()).await.unwrap().as_ref());
+        drop(r);
+
+        // Later chunks in the old layout are not moved.
+        let c = Chunk::new(Hash::of(b"c"), b"c".to_vec(), &[]);
+        put_legacy(&mut store, &c).await;
+        assert_eq!(Moved::default(), migrate(&store).await.unwrap());
+        assert!(store
+            .has(&Key::ChunkData(*c.hash()).to_string())
+            .await
+            .unwrap());
+    }
+
+    #[async_std::test]
+    async fn empty() {
+        let store = MemStore::new();
+        assert_eq!(Moved::default(), migrate(&store).await.unwrap());
+        assert_eq!(vec![Key::Layout.to_string()], keys(&store).await);
+    }
+
+    // Entries as the first layout stored them: under their own keys, with
+    // large values in blobs of chunks in two keys.
+    async fn put_legacy_entries(store: &mut MemStore, big: &[u8]) {
+        let scratch = MemStore::new();
+        let wt = scratch.write().await.unwrap();
+        let stored = blob::put_value(wt.as_ref(), &ChunkerConfig::default(), big)
+            .await
+            .unwrap();
+        wt.commit().await.unwrap();
+        let r = Read::new(scratch.read().await.unwrap());
+        for k in keys(&scratch).await {
+            if let Ok(Key::Chunk(h)) = Key::parse(&k) {
+                put_legacy(store, &r.get_chunk(&h).await.unwrap().unwrap()).await;
+            }
+        }
+        drop(r);
+
+        store.put("a", b"1").await.unwrap();
+        store.put("big", &stored).await.unwrap();
+        // Not a chunk key, for all it starts like one.
+        store.put("c/x", b"\"x\"").await.unwrap();
+    }
+
+    async fn get_entry(store: &MemStore, key: &str) -> Option<Vec<u8>> {
+        let r = Read::new(store.read().await.unwrap());
+        let root = r.get_head(MAIN).await.unwrap().unwrap();
+        let stored = map::get(&r, &root, key).await.unwrap()?;
+        Some(blob::get_value(&r, stored).await.unwrap())
+    }
+
+    #[async_std::test]
+    async fn moves_entries() {
+        let mut store = MemStore::new();
+        let big = vec![b'7'; blob::MIN_BLOB_SIZE * 4];
+        put_legacy_entries(&mut store, &big).await;
+        let imported = Chunk::new(Hash::of(b"i"), b"i".to_vec(), &[]);
+        put_legacy(&mut store, &imported).await;
+        store
+            .put("h/main", imported.hash().to_string().as_bytes())
+            .await
+            .unwrap();
+
+        let moved = migrate(&store).await.unwrap();
+        assert!(moved.chunks > 2, "{:?}", moved);
+        assert_eq!(3, moved.entries);
+        assert_eq!(Some(b"1".to_vec()), get_entry(&store, "a").await);
+        assert_eq!(Some(big), get_entry(&store, "big").await);
+        assert_eq!(Some(b"\"x\"".to_vec()), get_entry(&store, "c/x").await);
+        assert_eq!(None, get_entry(&store, "h/main").await);
+
+        // Only the dag's keys are left, and the main head from before is
+        // kept.
+        for k in keys(&store).await {
+            assert!(Key::parse(&k).is_ok(), "{}", k);
+        }
+        let r = Read::new(store.read().await.unwrap());
+        assert_eq!(
+            Some(*imported.hash()),
+            r.get_head(LEGACY_MAIN).await.unwrap()
+        );
+        let report = crate::dag::check::check(r.kv()).await.unwrap();
+        assert!(report.problems.is_empty(), "{}", report);
+        drop(r);
+        assert_eq!(Moved::default(), migrate(&store).await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn moves_entries_of_records() {
+        // A store in layout 1, with entries but chunks in records already.
+        let mut store = MemStore::new();
+        store.put("a", b"1").await.unwrap();
+        store.put(&Key::Layout.to_string(), b"1").await.unwrap();
+        let moved = Moved {
+            chunks: 0,
+            entries: 1,
+        };
+        assert_eq!(moved, migrate(&store).await.unwrap());
+        assert_eq!(Some(b"1".to_vec()), get_entry(&store, "a").await);
+    }
+}
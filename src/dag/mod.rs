@@ -14,13 +14,20 @@
 //! chunk: put()'ing a chunk with the same hash as some
 //! existing chunk is a no-op, and no error will be
 //! reported.
+pub mod archive;
+pub mod cache;
+pub mod check;
 pub mod chunk;
 pub mod key;
+pub mod lz4;
 #[allow(unused_imports)]
 mod meta_generated;
-mod read;
+pub mod migrate;
+pub mod read;
+pub mod snapshot;
 pub mod store;
-mod write;
+pub mod verify;
+pub mod write;
 
 use crate::kv;
 
@@ -1,55 +1,170 @@
+use super::cache::Cache;
 use super::chunk::Chunk;
 use super::key::Key;
+use super::verify::verify_meta;
 use super::{Error, Result};
+use crate::hash::Hash;
 use crate::kv;
+use async_trait::async_trait;
 use log::error;
 
 #[allow(dead_code)]
 pub struct Read<'a> {
     kvr: Box<dyn kv::Read + 'a>,
+    cache: Option<&'a Cache>,
 }
 
 #[allow(dead_code)]
-impl<'a> Read<'_> {
-    pub fn new(kvr: Box<dyn kv::Read + 'a>) -> Read {
-        Read { kvr }
+impl<'a> Read<'a> {
+    pub fn new(kvr: Box<dyn kv::Read + 'a>) -> Read<'a> {
+        Read { kvr, cache: None }
     }
 
-    pub async fn has_chunk(&self, hash: &str) -> Result<bool> {
+    // Chunks are read through cache, which must only hold chunks that
+    // are committed to the store kvr reads.
+    pub fn with_cache(kvr: Box<dyn kv::Read + 'a>, cache: &'a Cache) -> Read<'a> {
+        Read {
+            kvr,
+            cache: Some(cache),
+        }
+    }
+
+    pub fn kv(&self) -> &dyn kv::Read {
+        self.kvr.as_ref()
+    }
+
+    pub async fn has_chunk(&self, hash: &Hash) -> Result<bool> {
         has_chunk(self.kvr.as_ref(), hash).await
     }
 
-    pub async fn get_chunk(&self, hash: &str) -> Result<Option<Chunk>> {
-        get_chunk(self.kvr.as_ref(), hash).await
+    pub async fn get_chunk(&self, hash: &Hash) -> Result<Option<Chunk>> {
+        let cache = match self.cache {
+            None => return get_chunk(self.kvr.as_ref(), hash).await,
+            Some(cache) => cache,
+        };
+        if let Some(c) = cache.get(hash) {
+            return Ok(Some(c));
+        }
+        let c = get_chunk(self.kvr.as_ref(), hash).await?;
+        if let Some(c) = c.as_ref() {
+            cache.put(c);
+        }
+        Ok(c)
+    }
+
+    /// Gets the chunks with hashes, in the order of hashes, with one
+    /// batch read of the chunks not in the cache.
+    pub async fn get_chunks(&self, hashes: &[Hash]) -> Result<Vec<Option<Chunk>>> {
+        let mut chunks: Vec<Option<Chunk>> = match self.cache {
+            None => vec![None; hashes.len()],
+            Some(cache) => hashes.iter().map(|h| cache.get(h)).collect(),
+        };
+        let missing: Vec<Hash> = hashes
+            .iter()
+            .zip(chunks.iter())
+            .filter(|(_, c)| c.is_none())
+            .map(|(h, _)| *h)
+            .collect();
+        let mut fetched = get_chunks(self.kvr.as_ref(), &missing).await?.into_iter();
+        for c in chunks.iter_mut().filter(|c| c.is_none()) {
+            *c = fetched.next().unwrap_or(None);
+            if let (Some(cache), Some(c)) = (self.cache, c.as_ref()) {
+                cache.put(c);
+            }
+        }
+        Ok(chunks)
     }
 
-    pub async fn get_head(&self, name: &str) -> Result<Option<String>> {
+    pub async fn get_head(&self, name: &str) -> Result<Option<Hash>> {
         get_head(self.kvr.as_ref(), name).await
     }
 }
 
-pub async fn has_chunk(kvr: &dyn kv::Read, hash: &str) -> Result<bool> {
-    Ok(kvr.has(&Key::ChunkData(hash).to_string()).await?)
+/// Where chunks are read from, for readers that do not need heads: a
+/// Read, or a Snapshot.
+#[async_trait(?Send)]
+pub trait Source {
+    async fn get_chunk(&self, hash: &Hash) -> Result<Option<Chunk>>;
+
+    /// Gets the chunks with hashes, in the order of hashes.
+    async fn get_chunks(&self, hashes: &[Hash]) -> Result<Vec<Option<Chunk>>>;
 }
 
-pub async fn get_chunk(kvr: &dyn kv::Read, hash: &str) -> Result<Option<Chunk>> {
-    match kvr.get(&Key::ChunkData(hash).to_string()).await? {
+#[async_trait(?Send)]
+impl Source for Read<'_> {
+    async fn get_chunk(&self, hash: &Hash) -> Result<Option<Chunk>> {
+        Read::get_chunk(self, hash).await
+    }
+
+    async fn get_chunks(&self, hashes: &[Hash]) -> Result<Vec<Option<Chunk>>> {
+        Read::get_chunks(self, hashes).await
+    }
+}
+
+pub async fn has_chunk(kvr: &dyn kv::Read, hash: &Hash) -> Result<bool> {
+    Ok(kvr.has(&Key::Chunk(*hash).to_string()).await?)
+}
+
+pub async fn get_chunk(kvr: &dyn kv::Read, hash: &Hash) -> Result<Option<Chunk>> {
+    match kvr.get(&Key::Chunk(*hash).to_string()).await? {
         None => Ok(None),
-        Some(data) => {
-            let meta = kvr.get(&Key::ChunkMeta(hash).to_string()).await?;
-            Ok(Some(Chunk::read(hash.into(), data, meta)))
+        Some(record) => Ok(Some(decode(hash, record)?)),
+    }
+}
+
+pub async fn get_chunks(kvr: &dyn kv::Read, hashes: &[Hash]) -> Result<Vec<Option<Chunk>>> {
+    let keys: Vec<String> = hashes.iter().map(|h| Key::Chunk(*h).to_string()).collect();
+    let keys: Vec<&str> = keys.iter().map(|k| k.as_str()).collect();
+    let records = kvr.get_many(&keys).await?;
+    let mut chunks = Vec::with_capacity(records.len());
+    for (hash, record) in hashes.iter().zip(records) {
+        chunks.push(match record {
+            None => None,
+            Some(record) => Some(decode(hash, record)?),
+        });
+    }
+    Ok(chunks)
+}
+
+// Decodes the record of the chunk with hash, as written by put_chunk.
+fn decode(hash: &Hash, record: Vec<u8>) -> Result<Chunk> {
+    let c = match Chunk::from_record(*hash, record) {
+        Some(c) => c,
+        None => {
+            error!("Invalid record for chunk: {}", hash);
+            return Err(Error::CorruptStore);
+        }
+    };
+    if let Some(meta) = c.meta() {
+        if !verify_meta(meta) {
+            error!("Invalid meta for chunk: {}", hash);
+            return Err(Error::CorruptStore);
+        }
+    }
+    match c.decompressed() {
+        Some(c) => Ok(c),
+        None => {
+            error!("Could not decompress chunk: {}", hash);
+            Err(Error::CorruptStore)
         }
     }
 }
 
-pub async fn get_head(kvr: &dyn kv::Read, name: &str) -> Result<Option<String>> {
+pub async fn get_head(kvr: &dyn kv::Read, name: &str) -> Result<Option<Hash>> {
     if let Some(bytes) = kvr.get(&Key::Head(name).to_string()).await? {
-        match String::from_utf8(bytes) {
-            Ok(s) => return Ok(Some(s)),
+        let s = match String::from_utf8(bytes) {
+            Ok(s) => s,
             Err(e) => {
                 error!("Could not decode head: {}: {}", name, e);
                 return Err(Error::CorruptStore);
             }
+        };
+        match Hash::parse(&s) {
+            Ok(h) => return Ok(Some(h)),
+            Err(e) => {
+                error!("Could not parse head: {}: {:?}", name, e);
+                return Err(Error::CorruptStore);
+            }
         }
     }
     Ok(None)
@@ -0,0 +1,144 @@
+//! Read-only snapshots of a dag at a head, for readers that outlive a
+//! transaction.
+//!
+//! IDB transactions close once the event loop idles, so a reader that
+//! awaits anything but its own requests (a UI iterating a scan, say)
+//! cannot hold one. A Snapshot instead pins the hash a head pointed to
+//! and opens a transaction for each read. Chunks are immutable, so every
+//! transaction sees the same chunks for the hashes reachable from the
+//! pinned one, however the head has moved since.
+use super::cache::Cache;
+use super::chunk::Chunk;
+use super::read::{self, Read, Source};
+use super::Result;
+use crate::hash::Hash;
+use crate::kv;
+use async_trait::async_trait;
+
+pub struct Snapshot<'a> {
+    kv: &'a dyn kv::Store,
+    cache: &'a Cache,
+    hash: Hash,
+}
+
+impl<'a> Snapshot<'a> {
+    /// Returns a snapshot of the dag in kv at hash, reading chunks through
+    /// cache.
+    pub fn new(kv: &'a dyn kv::Store, cache: &'a Cache, hash: Hash) -> Snapshot<'a> {
+        Snapshot { kv, cache, hash }
+    }
+
+    /// Returns a snapshot at the hash head points to, or None if there is
+    /// no such head.
+    pub async fn at_head(
+        kv: &'a dyn kv::Store,
+        cache: &'a Cache,
+        head: &str,
+    ) -> Result<Option<Snapshot<'a>>> {
+        let kvr = kv.read().await?;
+        Ok(read::get_head(kvr.as_ref(), head)
+            .await?
+            .map(|hash| Snapshot::new(kv, cache, hash)))
+    }
+
+    /// The hash the snapshot is pinned to.
+    pub fn hash(&self) -> &Hash {
+        &self.hash
+    }
+
+    /// Gets a chunk, opening a transaction only if it is not cached.
+    pub async fn get_chunk(&self, hash: &Hash) -> Result<Option<Chunk>> {
+        if let Some(c) = self.cache.get(hash) {
+            return Ok(Some(c));
+        }
+        let kvr = self.kv.read().await?;
+        let c = read::get_chunk(kvr.as_ref(), hash).await?;
+        if let Some(c) = c.as_ref() {
+            self.cache.put(c);
+        }
+        Ok(c)
+    }
+
+    pub async fn get_chunks(&self, hashes: &[Hash]) -> Result<Vec<Option<Chunk>>> {
+        let read = Read::with_cache(self.kv.read().await?, self.cache);
+        read.get_chunks(hashes).await
+    }
+}
+
+#[async_trait(?Send)]
+impl Source for Snapshot<'_> {
+    async fn get_chunk(&self, hash: &Hash) -> Result<Option<Chunk>> {
+        Snapshot::get_chunk(self, hash).await
+    }
+
+    async fn get_chunks(&self, hashes: &[Hash]) -> Result<Vec<Option<Chunk>>> {
+        Snapshot::get_chunks(self, hashes).await
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dag::cache::DEFAULT_SIZE;
+    use crate::dag::write::Write;
+    use crate::kv::memstore::MemStore;
+    use crate::kv::Store;
+
+    fn chunk(data: &[u8], refs: &[Hash]) -> Chunk {
+        Chunk::new(Hash::of(data), data.to_vec(), refs)
+    }
+
+    async fn put(store: &MemStore, head: &str, chunks: &[&Chunk]) {
+        let mut w = Write::new(store.write().await.unwrap());
+        for c in chunks {
+            w.put_chunk(c).await.unwrap();
+        }
+        w.set_head(head, chunks[chunks.len() - 1].hash())
+            .await
+            .unwrap();
+        w.commit().await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn pinned_to_head() {
+        let store = MemStore::new();
+        let cache = Cache::new(DEFAULT_SIZE);
+        let leaf = chunk(b"leaf", &[]);
+        let root = chunk(b"root", &[*leaf.hash()]);
+        put(&store, "main", &[&leaf, &root]).await;
+
+        assert!(Snapshot::at_head(&store, &cache, "nope")
+            .await
+            .unwrap()
+            .is_none());
+        let snapshot = Snapshot::at_head(&store, &cache, "main")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(root.hash(), snapshot.hash());
+
+        // The head moves on, but the snapshot still reads what it pinned,
+        // each read in a transaction of its own.
+        let root2 = chunk(b"root2", &[]);
+        put(&store, "main", &[&root2]).await;
+        for _ in 0..2 {
+            let c = snapshot.get_chunk(snapshot.hash()).await.unwrap();
+            assert_eq!(Some(&root), c.as_ref());
+            let refs: Vec<Hash> = c.unwrap().refs().into_iter().flatten().collect();
+            assert_eq!(
+                vec![Some(leaf.clone())],
+                snapshot.get_chunks(&refs).await.unwrap()
+            );
+        }
+        assert!(snapshot
+            .get_chunk(&Hash::of(b"nope"))
+            .await
+            .unwrap()
+            .is_none());
+
+        // Chunks read before are served from the cache.
+        let stats = cache.stats();
+        assert_eq!((2, 3, 2), (stats.hits, stats.misses, stats.chunks));
+    }
+}
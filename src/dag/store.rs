@@ -1,24 +1,49 @@
+use super::cache::{self, Cache};
 use super::read::Read;
+use super::snapshot::Snapshot;
 use super::write::Write;
 use super::Result;
+use crate::hash::Hash;
 use crate::kv;
 
-#[allow(dead_code)]
 pub struct Store {
     kv: Box<dyn kv::Store>,
+    // Shared by reads, see Read::with_cache().
+    cache: Cache,
 }
 
-#[allow(dead_code)]
 impl Store {
     pub fn new(kv: Box<dyn kv::Store>) -> Store {
-        Store { kv }
+        Store {
+            kv,
+            cache: Cache::new(cache::DEFAULT_SIZE),
+        }
+    }
+
+    pub fn kv(&self) -> &dyn kv::Store {
+        self.kv.as_ref()
     }
 
     pub async fn read(&self) -> Result<Read<'_>> {
-        Ok(Read::new(self.kv.read().await?))
+        Ok(Read::with_cache(self.kv.read().await?, &self.cache))
+    }
+
+    /// Returns a read-only snapshot at the hash head points to, or None if
+    /// there is no such head. Unlike a Read, a snapshot may be held
+    /// across awaits and writes, see dag::snapshot.
+    pub async fn snapshot(&self, head: &str) -> Result<Option<Snapshot<'_>>> {
+        Snapshot::at_head(self.kv.as_ref(), &self.cache, head).await
+    }
+
+    pub fn snapshot_at(&self, hash: Hash) -> Snapshot<'_> {
+        Snapshot::new(self.kv.as_ref(), &self.cache, hash)
+    }
+
+    pub fn cache_stats(&self) -> cache::Stats {
+        self.cache.stats()
     }
 
-    pub async fn write(&mut self) -> Result<Write<'_>> {
-        Ok(Write::new(self.kv.write().await?))
+    pub async fn write(&self) -> Result<Write<'_>> {
+        Ok(Write::with_cache(self.kv.write().await?, &self.cache))
     }
 }
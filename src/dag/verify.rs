@@ -0,0 +1,313 @@
+//! Verification of chunk meta read from storage.
+//!
+//! The flatbuffers crate trusts its input: accessors on a malformed
+//! buffer panic or read garbage. Meta comes from storage that may have
+//! been corrupted, so it must be verified before it is accessed.
+//!
+//! This follows the checks of the verifiers flatc generates for other
+//! languages: offsets and lengths must stay in bounds, scalars must be
+//! aligned, and strings must be null-terminated UTF-8.
+use super::chunk::{Chunk, Compression};
+use super::meta_generated::meta;
+use crate::hash;
+use std::convert::{TryFrom, TryInto};
+
+// The slot of the deprecated string_refs field, which flatc no longer
+// generates accessors for but which old meta may still contain.
+const VT_STRING_REFS: usize = 4;
+
+/// Returns whether meta is a well-formed Meta flatbuffer that can be
+/// safely accessed.
+pub fn verify_meta(meta: &[u8]) -> bool {
+    verify(&Verifier { buf: meta }).is_some()
+}
+
+/// Returns whether c, which comes from outside the store (an archive or
+/// a peer) rather than from get_chunk, has valid meta and is not
+/// compressed.
+pub fn verify_chunk(c: &Chunk) -> bool {
+    match c.meta() {
+        None => true,
+        Some(meta) => verify_meta(meta) && c.compression() == Compression::None,
+    }
+}
+
+fn verify(v: &Verifier) -> Option<()> {
+    let t = v.table(v.offset(0)?)?;
+    if let Some(f) = v.field(&t, VT_STRING_REFS, 4)? {
+        let (start, len) = v.vector(v.offset(f)?, 4)?;
+        for i in 0..len {
+            v.string(v.offset(start + i * 4)?)?;
+        }
+    }
+    if let Some(f) = v.field(&t, meta::Meta::VT_REFS as usize, 4)? {
+        let (_, len) = v.vector(v.offset(f)?, 1)?;
+        if len % hash::BYTE_LENGTH != 0 {
+            return None;
+        }
+    }
+    // The accessors transmute enums, so they must be known values.
+    if let Some(f) = v.field(&t, meta::Meta::VT_KIND as usize, 1)? {
+        if v.bytes(f, 1)?[0] > meta::ENUM_MAX_KIND {
+            return None;
+        }
+    }
+    if let Some(f) = v.field(&t, meta::Meta::VT_COMPRESSION as usize, 1)? {
+        if v.bytes(f, 1)?[0] > meta::ENUM_MAX_COMPRESSION {
+            return None;
+        }
+    }
+    v.field(&t, meta::Meta::VT_UNCOMPRESSED_SIZE as usize, 8)?;
+    v.field(&t, meta::Meta::VT_CREATED as usize, 8)?;
+    Some(())
+}
+
+struct Verifier<'a> {
+    buf: &'a [u8],
+}
+
+struct Table {
+    pos: usize,
+    vtable: usize,
+    vtable_len: usize,
+    table_len: usize,
+}
+
+// Each method returns None if what it reads is malformed.
+impl<'a> Verifier<'a> {
+    fn bytes(&self, at: usize, n: usize) -> Option<&'a [u8]> {
+        self.buf.get(at..at.checked_add(n)?)
+    }
+
+    // n must be a power of two.
+    fn aligned(&self, at: usize, n: usize) -> Option<()> {
+        if at & (n - 1) == 0 {
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    fn u16(&self, at: usize) -> Option<usize> {
+        self.aligned(at, 2)?;
+        Some(u16::from_le_bytes(self.bytes(at, 2)?.try_into().ok()?) as usize)
+    }
+
+    fn u32(&self, at: usize) -> Option<usize> {
+        self.aligned(at, 4)?;
+        Some(u32::from_le_bytes(self.bytes(at, 4)?.try_into().ok()?) as usize)
+    }
+
+    fn i32(&self, at: usize) -> Option<i64> {
+        self.aligned(at, 4)?;
+        Some(i32::from_le_bytes(self.bytes(at, 4)?.try_into().ok()?) as i64)
+    }
+
+    // Follows the unsigned offset at at.
+    fn offset(&self, at: usize) -> Option<usize> {
+        at.checked_add(self.u32(at)?)
+    }
+
+    fn table(&self, pos: usize) -> Option<Table> {
+        let vtable = usize::try_from((pos as i64).checked_sub(self.i32(pos)?)?).ok()?;
+        let vtable_len = self.u16(vtable)?;
+        let table_len = self.u16(vtable + 2)?;
+        if vtable_len < 4 || vtable_len % 2 != 0 || table_len < 4 {
+            return None;
+        }
+        self.bytes(vtable, vtable_len)?;
+        self.bytes(pos, table_len)?;
+        Some(Table {
+            pos,
+            vtable,
+            vtable_len,
+            table_len,
+        })
+    }
+
+    // Returns the position of the field in slot of t, which is size
+    // bytes, or None inside the Some if t does not have the field.
+    fn field(&self, t: &Table, slot: usize, size: usize) -> Option<Option<usize>> {
+        if slot + 2 > t.vtable_len {
+            return Some(None);
+        }
+        let offset = self.u16(t.vtable + slot)?;
+        if offset == 0 {
+            return Some(None);
+        }
+        if offset + size > t.table_len {
+            return None;
+        }
+        self.aligned(t.pos + offset, size)?;
+        Some(Some(t.pos + offset))
+    }
+
+    // Returns the position and length of the vector at pos, whose
+    // elements are elem_size bytes.
+    fn vector(&self, pos: usize, elem_size: usize) -> Option<(usize, usize)> {
+        let len = self.u32(pos)?;
+        let start = pos + 4;
+        self.bytes(start, len.checked_mul(elem_size)?)?;
+        Some((start, len))
+    }
+
+    fn string(&self, pos: usize) -> Option<&'a str> {
+        let (start, len) = self.vector(pos, 1)?;
+        if self.bytes(start + len, 1)? != [0] {
+            return None;
+        }
+        std::str::from_utf8(self.bytes(start, len)?).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dag::chunk::{Info, Kind};
+    use crate::hash::Hash;
+    use flatbuffers::FlatBufferBuilder;
+    use rand::rngs::StdRng;
+    use rand::{Rng, RngCore, SeedableRng};
+
+    fn valid_meta(refs: usize) -> Vec<u8> {
+        let refs: Vec<Hash> = (0..refs).map(|i| Hash::of(&[i as u8])).collect();
+        let info = Info {
+            kind: Kind::Blob,
+            uncompressed_size: 1 << refs.len(),
+            created: 1,
+        };
+        let c = Chunk::with_info(Hash::of(b"h"), vec![], &refs, &info);
+        c.meta().map_or_else(Vec::new, |m| m.to_vec())
+    }
+
+    // Meta with the deprecated string_refs field, as older versions
+    // wrote it.
+    fn string_refs_meta(refs: &[&str]) -> Vec<u8> {
+        let mut builder = FlatBufferBuilder::default();
+        let strings: Vec<_> = refs.iter().map(|s| builder.create_string(s)).collect();
+        let vector = builder.create_vector(&strings);
+        let start = builder.start_table();
+        builder.push_slot_always(VT_STRING_REFS as flatbuffers::VOffsetT, vector);
+        let table = builder.end_table(start);
+        builder.finish(table, None);
+        builder.finished_data().to_vec()
+    }
+
+    // Checks that accessing meta does not panic if it verifies.
+    fn check(meta: &[u8]) -> bool {
+        let ok = verify_meta(meta);
+        if ok {
+            let c = Chunk::read(Hash::empty(), vec![], Some(meta.to_vec()));
+            c.info();
+            c.compression();
+            let refs = c.refs().map_or(0, |refs| refs.count());
+            assert!(refs <= meta.len() / hash::BYTE_LENGTH);
+        }
+        ok
+    }
+
+    #[test]
+    fn valid() {
+        for n in 0..4 {
+            assert!(verify_meta(&valid_meta(n)));
+        }
+        assert!(verify_meta(&string_refs_meta(&[])));
+        assert!(verify_meta(&string_refs_meta(&["a", "bc"])));
+
+        // A table without refs.
+        let mut builder = FlatBufferBuilder::default();
+        let m = meta::Meta::create(&mut builder, &Default::default());
+        builder.finish(m, None);
+        assert!(verify_meta(builder.finished_data()));
+    }
+
+    #[test]
+    fn invalid() {
+        for n in 0..4 {
+            let meta = valid_meta(n);
+            for len in 0..meta.len() {
+                assert!(!check(&meta[..len]), "{} of {}", len, meta.len());
+            }
+        }
+
+        // A refs vector that is not a whole number of hashes.
+        let mut builder = FlatBufferBuilder::default();
+        let refs = builder.create_vector(&[0u8; hash::BYTE_LENGTH + 1]);
+        let args = meta::MetaArgs {
+            refs: Some(refs),
+            ..Default::default()
+        };
+        let m = meta::Meta::create(&mut builder, &args);
+        builder.finish(m, None);
+        assert!(!check(builder.finished_data()));
+
+        // A kind that is not in the enum.
+        let mut meta = valid_meta(0);
+        let v = Verifier { buf: &meta };
+        let t = v.table(v.offset(0).unwrap()).unwrap();
+        let at = v
+            .field(&t, meta::Meta::VT_KIND as usize, 1)
+            .unwrap()
+            .unwrap();
+        assert_eq!(Kind::Blob as u8, meta[at]);
+        meta[at] = meta::ENUM_MAX_KIND + 1;
+        assert!(!check(&meta));
+
+        // Strings that are not UTF-8, or not terminated.
+        let meta = string_refs_meta(&["abc"]);
+        let at = meta.windows(3).position(|w| w == b"abc").unwrap();
+        let mut bad = meta.clone();
+        bad[at + 1] = 0xff;
+        assert!(!check(&bad));
+        let mut bad = meta;
+        bad[at + 3] = b'd';
+        assert!(!check(&bad));
+
+        assert!(!check(&[]));
+        assert!(!check(&[0xff; 16]));
+        // An offset to itself, and a table whose vtable is itself.
+        assert!(!check(&[0, 0, 0, 0]));
+        assert!(!check(&[4, 0, 0, 0, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn fuzz_random() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut valid = 0;
+        for _ in 0..100_000 {
+            let mut buf = vec![0u8; rng.gen_range(0, 64)];
+            rng.fill_bytes(&mut buf);
+            // Small offsets make it past the first checks more often.
+            if buf.len() >= 4 && rng.gen() {
+                buf[0] = rng.gen_range(0, buf.len() as u8);
+                buf[1..4].copy_from_slice(&[0, 0, 0]);
+            }
+            if check(&buf) {
+                valid += 1;
+            }
+        }
+        assert!(valid < 1000, "{}", valid);
+    }
+
+    #[test]
+    fn fuzz_mutations() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let metas = [
+            valid_meta(1),
+            valid_meta(3),
+            string_refs_meta(&["a", "bcd"]),
+        ];
+        for _ in 0..100_000 {
+            let mut meta = metas[rng.gen_range(0, metas.len())].clone();
+            for _ in 0..rng.gen_range(1, 4) {
+                let i = rng.gen_range(0, meta.len());
+                meta[i] = match rng.gen_range(0, 3) {
+                    0 => meta[i] ^ (1 << rng.gen_range(0, 8)),
+                    1 => rng.gen(),
+                    _ => 0xff,
+                };
+            }
+            check(&meta);
+        }
+    }
+}
@@ -1,48 +1,64 @@
+use super::cache::Cache;
 use super::chunk::Chunk;
 use super::key::Key;
-use super::{read, Result};
+use super::read::{self, Read};
+use super::Result;
+use crate::hash::Hash;
 use crate::kv;
 
 #[allow(dead_code)]
 pub struct Write<'a> {
     kvw: Box<dyn kv::Write + 'a>,
+    cache: Option<&'a Cache>,
 }
 
 #[allow(dead_code)]
-impl<'a> Write<'_> {
-    pub fn new(kvw: Box<dyn kv::Write + 'a>) -> Write {
-        Write { kvw }
+impl<'a> Write<'a> {
+    pub fn new(kvw: Box<dyn kv::Write + 'a>) -> Write<'a> {
+        Write { kvw, cache: None }
     }
 
-    pub async fn has_chunk(&mut self, hash: &str) -> Result<bool> {
+    // Reads through read() go through cache, which, as for
+    // Read::with_cache(), must only hold committed chunks: chunks put in
+    // this write must not be read through read() before it commits.
+    pub fn with_cache(kvw: Box<dyn kv::Write + 'a>, cache: &'a Cache) -> Write<'a> {
+        Write {
+            kvw,
+            cache: Some(cache),
+        }
+    }
+
+    pub fn kv(&self) -> &dyn kv::Write {
+        self.kvw.as_ref()
+    }
+
+    /// Returns a Read of the store as this write sees it.
+    pub fn read(&self) -> Read<'_> {
+        let kvr = Box::new(self.kvw.as_read());
+        match self.cache {
+            None => Read::new(kvr),
+            Some(cache) => Read::with_cache(kvr, cache),
+        }
+    }
+
+    pub async fn has_chunk(&mut self, hash: &Hash) -> Result<bool> {
         read::has_chunk(self.kvw.as_read(), hash).await
     }
 
-    pub async fn get_chunk(&mut self, hash: &str) -> Result<Option<Chunk>> {
+    pub async fn get_chunk(&mut self, hash: &Hash) -> Result<Option<Chunk>> {
         read::get_chunk(self.kvw.as_read(), hash).await
     }
 
-    pub async fn get_head(&mut self, name: &str) -> Result<Option<String>> {
+    pub async fn get_head(&mut self, name: &str) -> Result<Option<Hash>> {
         read::get_head(self.kvw.as_read(), name).await
     }
 
     pub async fn put_chunk(&mut self, c: &Chunk) -> Result<()> {
-        self.kvw
-            .put(&Key::ChunkData(c.hash()).to_string(), c.data())
-            .await?;
-        if let Some(meta) = c.meta() {
-            self.kvw
-                .put(&Key::ChunkMeta(c.hash()).to_string(), meta)
-                .await?;
-        }
-        Ok(())
+        put_chunk(self.kvw.as_ref(), c).await
     }
 
-    pub async fn set_head(&mut self, name: &str, hash: &str) -> Result<()> {
-        Ok(self
-            .kvw
-            .put(&Key::Head(name).to_string(), hash.as_bytes())
-            .await?)
+    pub async fn set_head(&mut self, name: &str, hash: &Hash) -> Result<()> {
+        set_head(self.kvw.as_ref(), name, hash).await
     }
 
     pub async fn commit(self) -> Result<()> {
@@ -54,80 +70,162 @@ impl<'a> Write<'_> {
     }
 }
 
+/// Writes c, compressing its data if that is worth it. c must not be
+/// compressed already.
+pub async fn put_chunk(kvw: &dyn kv::Write, c: &Chunk) -> Result<()> {
+    let compressed = c.compressed();
+    let c = compressed.as_ref().unwrap_or(c);
+    kvw.put(&Key::Chunk(*c.hash()).to_string(), &c.to_record())
+        .await?;
+    Ok(())
+}
+
+pub async fn set_head(kvw: &dyn kv::Write, name: &str, hash: &Hash) -> Result<()> {
+    Ok(kvw
+        .put(&Key::Head(name).to_string(), hash.to_string().as_bytes())
+        .await?)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::dag::chunk::Compression;
     use crate::kv::memstore::MemStore;
     use crate::kv::Store;
 
     #[async_std::test]
     async fn put_chunk() {
-        async fn test(hash: &str, data: &[u8], refs: &[&str]) {
+        async fn test(hash: Hash, data: &[u8], refs: &[Hash]) {
             let kv = MemStore::new();
             let kvw = kv.write().await.unwrap();
-            let mut w = Write { kvw };
+            let mut w = Write::new(kvw);
 
-            let c = Chunk::new(hash.into(), data.to_vec(), refs);
+            let c = Chunk::new(hash, data.to_vec(), refs);
             w.put_chunk(&c).await.unwrap();
 
-            let kd = Key::ChunkData(hash).to_string();
-            let km = Key::ChunkMeta(hash).to_string();
-
-            // The chunk data should always be there.
-            assert_eq!(w.kvw.get(&kd).await.unwrap().unwrap().as_slice(), c.data());
+            // The data and meta are stored in one record.
+            let record = w.kvw.get(&Key::Chunk(hash).to_string()).await;
+            assert_eq!(c.to_record(), record.unwrap().unwrap());
+            let meta_len = c.meta().map_or(0, |m| m.len());
+            assert_eq!(refs.is_empty(), meta_len == 0);
 
-            // The chunk meta should only be there if there were refs.
-            if refs.is_empty() {
-                assert!(!w.kvw.has(&km).await.unwrap());
-            } else {
-                assert_eq!(
-                    w.kvw.get(&km).await.unwrap().unwrap().as_slice(),
-                    c.meta().unwrap()
-                );
-            }
+            let read = w.get_chunk(&hash).await.unwrap().unwrap();
+            assert_eq!(data, read.data());
+            assert_eq!(c, read);
         }
 
-        test("", &vec![], &vec![]).await;
-        test("h1", &vec![0], &vec!["r1"]).await;
-        test("h2", &vec![0, 1], &vec!["r1", "r2"]).await;
+        test(Hash::empty(), &[], &[]).await;
+        test(Hash::of(b"h1"), &[0], &[Hash::of(b"r1")]).await;
+        test(
+            Hash::of(b"h2"),
+            &[0, 1],
+            &[Hash::of(b"r1"), Hash::of(b"r2")],
+        )
+        .await;
+    }
+
+    #[async_std::test]
+    async fn put_compressed_chunk() {
+        let kv = MemStore::new();
+        let kvw = kv.write().await.unwrap();
+        let mut w = Write::new(kvw);
+        let data = br#"{"id":1,"title":"compressible"}"#.repeat(50);
+        let refs = [Hash::of(b"r1")];
+        let c = Chunk::new(Hash::of(&data), data.clone(), &refs);
+        w.put_chunk(&c).await.unwrap();
+
+        let stored = w.kvw.get(&Key::Chunk(*c.hash()).to_string()).await;
+        assert!(stored.unwrap().unwrap().len() < data.len() / 4);
+        let read = w.get_chunk(c.hash()).await.unwrap().unwrap();
+        assert_eq!(&data[..], read.data());
+        assert_eq!(c, read);
+        assert_eq!(Compression::None, read.compression());
+        assert_eq!(data.len() as u64, read.info().uncompressed_size);
     }
 
     #[async_std::test]
     async fn set_head() {
-        async fn test(name: &str, hash: &str) {
+        async fn test(name: &str, hash: Hash) {
             let kv = MemStore::new();
             let kvw = kv.write().await.unwrap();
-            let mut w = Write { kvw };
-            w.set_head(name, hash).await.unwrap();
+            let mut w = Write::new(kvw);
+            w.set_head(name, &hash).await.unwrap();
             assert_eq!(
-                hash,
+                hash.to_string(),
                 String::from_utf8(w.kvw.get(&format!("h/{}", name)).await.unwrap().unwrap())
                     .unwrap()
             );
+            assert_eq!(Some(hash), w.get_head(name).await.unwrap());
         }
 
-        test("", "").await;
-        test("", "h1").await;
-        test("n1", "").await;
-        test("n1", "h1").await;
+        test("", Hash::empty()).await;
+        test("", Hash::of(b"h1")).await;
+        test("n1", Hash::empty()).await;
+        test("n1", Hash::of(b"h1")).await;
+    }
+
+    #[async_std::test]
+    async fn corrupt_head() {
+        async fn test(value: &[u8]) {
+            let kv = MemStore::new();
+            let kvw = kv.write().await.unwrap();
+            let mut w = Write::new(kvw);
+            w.kvw.put("h/n1", value).await.unwrap();
+            assert!(matches!(
+                w.get_head("n1").await,
+                Err(super::super::Error::CorruptStore)
+            ));
+        }
+
+        test(b"").await;
+        test(b"h1").await;
+        test(&[0xff; 32]).await;
+        test(Hash::of(b"h1").sum.as_ref()).await;
+    }
+
+    #[async_std::test]
+    async fn corrupt_record() {
+        let kv = MemStore::new();
+        let kvw = kv.write().await.unwrap();
+        let mut w = Write::new(kvw);
+        let h = Hash::of(b"data");
+        for meta in &[&[1, 2, 3][..], &[0xff; 32]] {
+            let c = Chunk::read(h, b"data".to_vec(), Some(meta.to_vec()));
+            w.kvw
+                .put(&Key::Chunk(h).to_string(), &c.to_record())
+                .await
+                .unwrap();
+            assert!(matches!(
+                w.get_chunk(&h).await,
+                Err(super::super::Error::CorruptStore)
+            ));
+        }
+        for record in &[&[][..], &[1, 0, 0], &[9, 0, 0, 0, 1]] {
+            w.kvw.put(&Key::Chunk(h).to_string(), record).await.unwrap();
+            assert!(matches!(
+                w.get_chunk(&h).await,
+                Err(super::super::Error::CorruptStore)
+            ));
+        }
     }
 
     #[async_std::test]
     async fn commit_rollback() {
         async fn test(commit: bool) {
+            let kd = Key::Chunk(Hash::of(b"h1")).to_string();
             let kv = MemStore::new();
             {
                 let kvw = kv.write().await.unwrap();
-                let mut w = Write { kvw };
-                let c = Chunk::new("h1".into(), vec![0, 1], &vec![]);
+                let mut w = Write::new(kvw);
+                let c = Chunk::new(Hash::of(b"h1"), vec![0, 1], &[]);
                 w.put_chunk(&c).await.unwrap();
 
                 // The changes should be present inside the tx.
-                assert!(w.kvw.has("c/h1/d").await.unwrap());
+                assert!(w.kvw.has(&kd).await.unwrap());
 
                 // But not outside the tx.
                 let kvr = kv.read().await.unwrap();
-                assert!(!kvr.has("c/h1/d").await.unwrap());
+                assert!(!kvr.has(&kd).await.unwrap());
 
                 if commit {
                     w.commit().await.unwrap();
@@ -138,7 +236,7 @@ mod tests {
 
             // The data should now be visible if it was committed.
             let kvr = kv.read().await.unwrap();
-            assert_eq!(commit, kvr.has("c/h1/d").await.unwrap());
+            assert_eq!(commit, kvr.has(&kd).await.unwrap());
         }
 
         test(true).await;
@@ -147,17 +245,17 @@ mod tests {
 
     #[async_std::test]
     async fn roundtrip() {
-        async fn test(name: &str, hash: &str, data: &[u8], refs: &[&str]) {
+        async fn test(name: &str, hash: Hash, data: &[u8], refs: &[Hash]) {
             let kv = MemStore::new();
-            let c = Chunk::new(hash.into(), data.to_vec(), refs);
+            let c = Chunk::new(hash, data.to_vec(), refs);
             {
                 let kvw = kv.write().await.unwrap();
-                let mut w = Write { kvw };
+                let mut w = Write::new(kvw);
                 w.put_chunk(&c).await.unwrap();
-                w.set_head(name, hash).await.unwrap();
+                w.set_head(name, &hash).await.unwrap();
 
                 // Read the changes inside the tx.
-                let c2 = w.get_chunk(hash).await.unwrap().unwrap();
+                let c2 = w.get_chunk(&hash).await.unwrap().unwrap();
                 let h = w.get_head(name).await.unwrap().unwrap();
                 assert_eq!(c, c2);
                 assert_eq!(h, hash);
@@ -167,14 +265,20 @@ mod tests {
 
             // Read the changes outside the tx.
             let r = read::Read::new(kv.read().await.unwrap());
-            let c2 = r.get_chunk(hash).await.unwrap().unwrap();
+            let c2 = r.get_chunk(&hash).await.unwrap().unwrap();
             let h = r.get_head(name).await.unwrap().unwrap();
             assert_eq!(c, c2);
             assert_eq!(h, hash);
         }
 
-        test("", "", &vec![], &vec![]).await;
-        test("n1", "h1", &vec![0], &vec!["r1"]).await;
-        test("n2", "h2", &vec![0, 1], &vec!["r1", "r2"]).await;
+        test("", Hash::empty(), &[], &[]).await;
+        test("n1", Hash::of(b"h1"), &[0], &[Hash::of(b"r1")]).await;
+        test(
+            "n2",
+            Hash::of(b"h2"),
+            &[0, 1],
+            &[Hash::of(b"r1"), Hash::of(b"r2")],
+        )
+        .await;
     }
 }
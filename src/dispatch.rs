@@ -1,70 +1,248 @@
-#![allow(clippy::redundant_pattern_matching)] // For derive(DeJson).
+#![allow(clippy::question_mark, clippy::redundant_pattern_matching)] // For derive(DeJson).
 
+use crate::dag;
+use crate::hash::Hash;
+use crate::json;
 use crate::kv::idbstore::IdbStore;
-use crate::kv::Store;
-use async_std::sync::{channel, Receiver, Sender};
-use log::warn;
+use crate::kv::memstore::MemStore;
+use crate::kv::{ScanOptions, Store, StoreError};
+use crate::prolly::{blob, chunker, map};
+use crate::sync::client_id;
+use crate::trace;
+use async_std::sync::{channel, Sender};
+use futures::channel::mpsc;
+use futures::StreamExt;
 use nanoserde::{DeJson, SerJson};
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Mutex;
 use wasm_bindgen_futures::spawn_local;
 
 struct Request {
     db_name: String,
-    rpc: String,
-    data: String,
-    response: Sender<Response>,
+    rpc: Rpc,
+    response: Sender<Result<Reply, String>>,
 }
 
 type Response = Result<String, String>;
 
+/// An RPC with its arguments, decoded from the string API by dispatch()
+/// or made by the class API in wasm.rs.
+pub enum Rpc {
+    Open { backend: Option<String> },
+    Close,
+    Drop,
+    List,
+    Debug(String),
+    GetClientId,
+    // The key, and the root of the map to read it from if not the main
+    // head's.
+    Has(String, Option<Hash>),
+    Get(String, Option<Hash>),
+    // Keys and JSON values, put in one transaction.
+    Put(Vec<(String, String)>),
+    // The root of the main head's map, for the class API's transactions
+    // to read from.
+    Root,
+    // Like Put, but failing unless the main head's map still has the
+    // given root, as a transaction that read at that root commits.
+    Commit(Vec<(String, String)>, Option<Hash>),
+    Scan(Scan),
+    // The head to archive, see Dispatcher::export().
+    Export(String),
+    Import(Vec<u8>),
+    // The level to log at, and how many events to keep for debug, see
+    // trace::set_level().
+    SetLogLevel(log::LevelFilter, Option<usize>),
+    // An RPC that did not decode, which fails once its database is
+    // known to be open.
+    Invalid(String),
+}
+
+impl Rpc {
+    /// The name of the RPC in the string API.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rpc::Open { .. } => "open",
+            Rpc::Close => "close",
+            Rpc::Drop => "drop",
+            Rpc::List => "list",
+            Rpc::Debug(_) => "debug",
+            Rpc::GetClientId => "getClientId",
+            Rpc::Has(..) => "has",
+            Rpc::Get(..) => "get",
+            Rpc::Put(_) => "put",
+            Rpc::Root => "root",
+            Rpc::Commit(..) => "commit",
+            Rpc::Scan(_) => "scan",
+            Rpc::Export(_) => "export",
+            Rpc::Import(_) => "import",
+            Rpc::SetLogLevel(..) => "setLogLevel",
+            Rpc::Invalid(_) => "invalid",
+        }
+    }
+}
+
+// Mirrors the options of scan() in the Replicache JS SDK.
+#[derive(Default)]
+pub struct Scan {
+    pub prefix: String,
+    pub start_key: Option<String>,
+    pub start_exclusive: bool,
+    pub limit: Option<u64>,
+    pub index_name: Option<String>,
+    // The root of the map to scan, as returned by a previous page, so
+    // that every page sees the same entries. The main head's if None.
+    pub root: Option<Hash>,
+}
+
+pub enum Reply {
+    Open {
+        client_id: String,
+        backend: String,
+    },
+    Empty,
+    Databases(Vec<String>),
+    Debug(String),
+    ClientId(String),
+    Has(bool),
+    // A JSON value, if the key has one.
+    Get(Option<String>),
+    Root(Option<Hash>),
+    Scan {
+        items: Vec<(String, String)>,
+        // The key of the last item, if there are more items to scan.
+        // Pass it as an exclusive start key to resume.
+        cursor: Option<String>,
+        // The root of the map scanned, if there is one. Pass it too to
+        // resume over the same entries.
+        root: Option<Hash>,
+    },
+    Archive(Vec<u8>),
+}
+
 lazy_static! {
-    static ref SENDER: Mutex<Sender::<Request>> = {
-        let (tx, rx) = channel::<Request>(1);
+    // Unbounded so that requests reach dispatch_loop in the order
+    // dispatch() is called.
+    static ref SENDER: Mutex<mpsc::UnboundedSender::<Request>> = {
+        let (tx, rx) = mpsc::unbounded::<Request>();
         spawn_local(dispatch_loop(rx));
         Mutex::new(tx)
     };
 }
 
-async fn dispatch_loop(rx: Receiver<Request>) {
+// Requests are handled as follows:
+//
+// - open, close, drop, list, debug and setLogLevel run in the dispatch
+//   loop itself, one at a time and in the order they arrive.
+// - Writes (put, commit and import) are queued per database and run one
+//   at a time in the order they arrive, so each write sees every write
+//   dispatched before it.
+// - Every other request is a read and runs concurrently with any other
+//   request, as soon as it arrives. A read sees every write that
+//   completed before the read was dispatched, and none dispatched after
+//   it.
+// - A read dispatched while a write to the same database is in flight
+//   does not wait for it and sees the database as it was before the
+//   write, unless the write has already opened its kv transaction on an
+//   IndexedDB backend: then the read waits for the write to end and sees
+//   it. Put opens its transaction only once its values are chunked, and
+//   after the reads dispatched along with it have opened theirs.
+// - close (and drop) waits for the reads and writes in flight on the
+//   database to end. Writes still queued fail.
+async fn dispatch_loop(mut rx: mpsc::UnboundedReceiver<Request>) {
     let mut dispatcher = Dispatcher {
         connections: HashMap::new(),
+        registry: None,
     };
 
-    loop {
-        match rx.recv().await {
-            Err(why) => warn!("Dispatch loop recv failed: {}", why),
-            Ok(req) => {
-                let response = match req.rpc.as_str() {
-                    "open" => Some(dispatcher.open(&req).await),
-                    "close" => Some(dispatcher.close(&req).await),
-                    "debug" => Some(dispatcher.debug(&req).await),
-                    _ => None,
-                };
-                if let Some(response) = response {
-                    req.response.send(response).await;
-                    continue;
-                }
-                let db = match dispatcher.connections.get_mut(&req.db_name[..]) {
-                    Some(v) => v,
-                    None => {
-                        let err = Err(format!("\"{}\" not open", req.db_name));
-                        req.response.send(err).await;
-                        continue;
-                    }
-                };
-                let response = match req.rpc.as_str() {
-                    "has" => Dispatcher::has(&**db, &req.data).await,
-                    "get" => Dispatcher::get(&**db, &req.data).await,
-                    "put" => Dispatcher::put(&mut **db, &req.data).await,
-                    _ => Err("Unsupported rpc name".into()),
-                };
-                req.response.send(response).await;
+    while let Some(req) = rx.next().await {
+        let response = match &req.rpc {
+            Rpc::Open { backend } => Some(dispatcher.open(&req.db_name, backend).await),
+            Rpc::Close => Some(dispatcher.close(&req.db_name).await),
+            Rpc::Drop => Some(dispatcher.drop(&req.db_name).await),
+            Rpc::List => Some(dispatcher.list().await),
+            Rpc::Debug(command) => Some(dispatcher.debug(&req.db_name, command).await),
+            Rpc::SetLogLevel(level, capacity) => {
+                trace::set_level(*level, *capacity);
+                Some(Ok(Reply::Empty))
             }
+            _ => None,
+        };
+        if let Some(response) = response {
+            req.response.send(response).await;
+            continue;
         }
+        let conn = match dispatcher.connections.get(&req.db_name[..]) {
+            Some(v) => v.clone(),
+            None => {
+                let err = Err(format!("\"{}\" not open", req.db_name));
+                req.response.send(err).await;
+                continue;
+            }
+        };
+        if !matches!(req.rpc, Rpc::Put(_) | Rpc::Commit(..) | Rpc::Import(_)) {
+            spawn_local(async move {
+                let response = execute(&conn, &req).await;
+                req.response.send(response).await;
+            });
+        } else if let Err(e) = conn.writes.unbounded_send(req) {
+            let req = e.into_inner();
+            let err = Err(format!("\"{}\" not open", req.db_name));
+            req.response.send(err).await;
+        }
+    }
+}
+
+// Runs the writes queued for a connection, one at a time, until the
+// connection is closed.
+async fn write_loop(conn: Weak<Connection>, mut writes: mpsc::UnboundedReceiver<Request>) {
+    while let Some(req) = writes.next().await {
+        let response = match conn.upgrade() {
+            Some(conn) => execute(&conn, &req).await,
+            None => Err(format!("\"{}\" not open", req.db_name)),
+        };
+        req.response.send(response).await;
+    }
+}
+
+async fn execute(conn: &Connection, req: &Request) -> Result<Reply, String> {
+    let store = &conn.store;
+    match &req.rpc {
+        Rpc::GetClientId => Ok(Reply::ClientId(conn.client_id.clone())),
+        Rpc::Has(key, root) => Dispatcher::has(store, key, *root).await,
+        Rpc::Get(key, root) => Dispatcher::get(store, key, *root).await,
+        Rpc::Put(entries) => Dispatcher::put(store, &conn.chunker, entries, None).await,
+        Rpc::Root => Dispatcher::root(store).await,
+        Rpc::Commit(entries, root) => {
+            Dispatcher::put(store, &conn.chunker, entries, Some(*root)).await
+        }
+        Rpc::Scan(scan) => Dispatcher::scan(store, scan).await,
+        Rpc::Export(head) => Dispatcher::export(store, head).await,
+        Rpc::Import(archive) => Dispatcher::import(store, archive).await,
+        Rpc::Invalid(e) => Err(e.clone()),
+        _ => Err("Unsupported rpc name".into()),
     }
 }
 
+// The requests and responses of the string API, see decode() and
+// encode().
+
+#[derive(DeJson, Default)]
+struct OpenRequest {
+    // "idb" (the default) or "mem".
+    backend: Option<String>,
+}
+
+#[derive(SerJson)]
+struct OpenResponse {
+    #[nserde(rename = "clientId")]
+    client_id: String,
+    backend: String,
+}
+
 #[derive(DeJson)]
 struct GetRequest {
     key: String,
@@ -82,104 +260,578 @@ struct PutRequest {
     value: String,
 }
 
+#[derive(DeJson)]
+struct ScanRequest {
+    prefix: Option<String>,
+    start: Option<ScanStart>,
+    limit: Option<u64>,
+    #[nserde(rename = "indexName")]
+    index_name: Option<String>,
+    // The root of a previous page, to scan the same entries.
+    root: Option<String>,
+}
+
+#[derive(DeJson)]
+struct ScanStart {
+    key: String,
+    exclusive: Option<bool>,
+}
+
+#[derive(SerJson)]
+struct ScanResponse {
+    cursor: Option<String>,
+    root: Option<String>,
+    items: Vec<ScanItem>, // Last to avoid trailing comma if root == None.
+}
+
+#[derive(SerJson)]
+struct ScanItem {
+    key: String,
+    value: String,
+}
+
+#[derive(DeJson)]
+struct SetLogLevelRequest {
+    // "off", "error", "warn", "info", "debug" or "trace".
+    level: String,
+    // How many recent events to keep for debug's "events" command.
+    #[nserde(rename = "bufferSize")]
+    buffer_size: Option<usize>,
+}
+
+#[derive(DeJson)]
+struct ExportRequest {
+    // The head to archive, "main" (the database's entries) by default.
+    head: Option<String>,
+}
+
+// Archives are base64-encoded, as responses are strings. The response is
+// formatted by hand, see encode().
+#[derive(DeJson)]
+struct ImportRequest {
+    archive: String,
+}
+
+// Where a database is stored, picked with the backend option of open.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Backend {
+    // IndexedDB, the default.
+    Idb,
+    // Memory, gone once closed. For tests and environments without
+    // IndexedDB.
+    Mem,
+}
+
+impl Backend {
+    fn parse(name: &str) -> Option<Backend> {
+        match name {
+            "idb" => Some(Backend::Idb),
+            "mem" => Some(Backend::Mem),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Backend::Idb => "idb",
+            Backend::Mem => "mem",
+        }
+    }
+
+    async fn open(self, db_name: &str) -> Result<Box<dyn Store>, StoreError> {
+        match self {
+            Backend::Idb => match IdbStore::new(db_name).await? {
+                Some(v) => Ok(Box::new(v)),
+                None => Err(StoreError::Str("IndexedDB is not available".into())),
+            },
+            Backend::Mem => Ok(Box::new(MemStore::new())),
+        }
+    }
+}
+
+struct Connection {
+    // Reads chunks through its cache, see dag::cache.
+    store: dag::store::Store,
+    backend: Backend,
+    client_id: String,
+    chunker: chunker::ChunkerConfig,
+    // Queue of write_loop.
+    writes: mpsc::UnboundedSender<Request>,
+}
+
 struct Dispatcher {
-    connections: HashMap<String, Box<dyn Store>>,
+    // Shared with the reads and writes in flight on each connection.
+    connections: HashMap<String, Rc<Connection>>,
+    // The names of the IndexedDB databases opened, see registry().
+    registry: Option<IdbStore>,
 }
 
+// The head of the map of entries that has, get, put and scan work on,
+// see prolly::map. Values in the map are stored by blob::put_value.
+const MAIN: &str = "main";
+
+// The IndexedDB database that registry() keeps in. IndexedDB cannot list
+// databases in every browser.
+const REGISTRY: &str = "repc-databases";
+
 impl Dispatcher {
-    async fn open(&mut self, req: &Request) -> Response {
-        if req.db_name.is_empty() {
+    async fn open(&mut self, db_name: &str, backend: &Option<String>) -> Result<Reply, String> {
+        if db_name.is_empty() {
             return Err("db_name must be non-empty".into());
         }
-        if self.connections.contains_key(&req.db_name[..]) {
-            return Ok("".into());
+        if db_name == REGISTRY {
+            return Err(format!("\"{}\" is reserved", REGISTRY));
         }
-        match IdbStore::new(&req.db_name[..]).await {
-            Err(e) => {
-                return Err(format!("Failed to open \"{}\": {}", req.db_name, e));
+        let backend = match backend.as_deref() {
+            None => Backend::Idb,
+            Some(name) => match Backend::parse(name) {
+                Some(v) => v,
+                None => return Err(format!("Unknown backend \"{}\"", name)),
+            },
+        };
+        if let Some(conn) = self.connections.get(db_name) {
+            if conn.backend != backend {
+                return Err(format!(
+                    "\"{}\" is already open with backend \"{}\"",
+                    db_name,
+                    conn.backend.name()
+                ));
             }
-            Ok(v) => {
-                if let Some(v) = v {
-                    self.connections.insert(req.db_name.clone(), Box::new(v));
-                }
+            return Ok(Dispatcher::open_reply(conn));
+        }
+        let store = match backend.open(db_name).await {
+            Ok(v) => v,
+            Err(e) => return Err(format!("Failed to open \"{}\": {}", db_name, e)),
+        };
+        let client_id = match client_id::init(store.as_ref()).await {
+            Ok(v) => v,
+            Err(e) => return Err(format!("Failed to open \"{}\": {}", db_name, e)),
+        };
+        let chunker = match chunker::init(store.as_ref()).await {
+            Ok(v) => v,
+            Err(e) => return Err(format!("Failed to open \"{}\": {}", db_name, e)),
+        };
+        if let Err(e) = dag::migrate::migrate(store.as_ref()).await {
+            return Err(format!("Failed to open \"{}\": {:?}", db_name, e));
+        }
+        if backend == Backend::Idb {
+            let registry = self.registry().await?;
+            let wt = match registry.write().await {
+                Ok(v) => v,
+                Err(e) => return Err(format!("{}", e)),
+            };
+            if let Err(e) = wt.put(db_name, b"").await {
+                return Err(format!("{}", e));
+            }
+            if let Err(e) = wt.commit().await {
+                return Err(format!("{}", e));
             }
         }
-        Ok("".into())
+        let (writes, rx) = mpsc::unbounded();
+        let conn = Rc::new(Connection {
+            store: dag::store::Store::new(store),
+            backend,
+            client_id,
+            chunker,
+            writes,
+        });
+        spawn_local(write_loop(Rc::downgrade(&conn), rx));
+        let reply = Dispatcher::open_reply(&conn);
+        self.connections.insert(db_name.into(), conn);
+        Ok(reply)
     }
 
-    async fn close(&mut self, req: &Request) -> Response {
-        if !self.connections.contains_key(&req.db_name[..]) {
-            return Ok("".into());
+    fn open_reply(conn: &Connection) -> Reply {
+        Reply::Open {
+            client_id: conn.client_id.clone(),
+            backend: conn.backend.name().into(),
         }
-        self.connections.remove(&req.db_name);
+    }
 
-        Ok("".into())
+    // Waits for the requests in flight on db_name to end, then closes it.
+    // Requests queued behind them fail.
+    async fn close(&mut self, db_name: &str) -> Result<Reply, String> {
+        let conn = match self.connections.remove(db_name) {
+            Some(v) => v,
+            None => return Ok(Reply::Empty),
+        };
+        match conn.store.kv().close().await {
+            Ok(_) => Ok(Reply::Empty),
+            Err(e) => Err(format!("Failed to close \"{}\": {}", db_name, e)),
+        }
     }
 
-    async fn has(db: &dyn Store, data: &str) -> Response {
-        let req: GetRequest = match DeJson::deserialize_json(data) {
+    // Closes db_name and deletes its storage.
+    async fn drop(&mut self, db_name: &str) -> Result<Reply, String> {
+        if db_name.is_empty() {
+            return Err("db_name must be non-empty".into());
+        }
+        if db_name == REGISTRY {
+            return Err(format!("\"{}\" is reserved", REGISTRY));
+        }
+        if let Some(conn) = self.connections.get(db_name) {
+            let backend = conn.backend;
+            self.close(db_name).await?;
+            if backend == Backend::Mem {
+                return Ok(Reply::Empty);
+            }
+        }
+        let deleted = match IdbStore::delete(db_name).await {
             Ok(v) => v,
-            Err(_) => return Err("Failed to parse request".into()),
+            Err(e) => return Err(format!("Failed to drop \"{}\": {}", db_name, e)),
         };
-        match db.has(&req.key).await {
-            Ok(v) => Ok(SerJson::serialize_json(&GetResponse {
-                has: v,
-                value: None,
-            })),
-            Err(e) => Err(format!("{}", e)),
+        // A blocked delete still goes on once the database is closed
+        // elsewhere, so its name is unlisted either way.
+        let registry = self.registry().await?;
+        let wt = match registry.write().await {
+            Ok(v) => v,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        if let Err(e) = wt.del(db_name).await {
+            return Err(format!("{}", e));
+        }
+        if let Err(e) = wt.commit().await {
+            return Err(format!("{}", e));
+        }
+        match deleted {
+            true => Ok(Reply::Empty),
+            false => Err(format!(
+                "Failed to drop \"{}\": it is open elsewhere, and will be deleted once closed",
+                db_name
+            )),
+        }
+    }
+
+    // Lists the IndexedDB databases opened and not dropped since, and the
+    // memory databases open now. IndexedDB databases last opened before
+    // the registry was added are not listed.
+    async fn list(&mut self) -> Result<Reply, String> {
+        let mut names: Vec<String> = self
+            .connections
+            .iter()
+            .filter(|(_, c)| c.backend == Backend::Mem)
+            .map(|(name, _)| name.clone())
+            .collect();
+        let rt = match self.registry().await?.read().await {
+            Ok(v) => v,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        let result = rt
+            .scan(&ScanOptions::default(), &mut |key, _| {
+                names.push(key.to_string());
+                true
+            })
+            .await;
+        if let Err(e) = result {
+            return Err(format!("{}", e));
+        }
+        names.sort();
+        names.dedup();
+        Ok(Reply::Databases(names))
+    }
+
+    // Returns the store of the names of IndexedDB databases opened, as
+    // keys, opening it if need be.
+    async fn registry(&mut self) -> Result<&IdbStore, String> {
+        if self.registry.is_none() {
+            self.registry = match IdbStore::new(REGISTRY).await {
+                Ok(Some(v)) => Some(v),
+                Ok(None) => return Err("IndexedDB is not available".into()),
+                Err(e) => return Err(format!("Failed to open database list: {}", e)),
+            };
+        }
+        Ok(self.registry.as_ref().unwrap())
+    }
+
+    async fn has(
+        store: &dag::store::Store,
+        key: &str,
+        root: Option<Hash>,
+    ) -> Result<Reply, String> {
+        let read = match store.read().await {
+            Ok(v) => v,
+            Err(e) => return Err(format!("{:?}", e)),
+        };
+        let root = match root {
+            Some(root) => Ok(Some(root)),
+            None => read.get_head(MAIN).await,
+        };
+        let has = match root {
+            Ok(Some(root)) => map::get(&read, &root, key).await.map(|v| v.is_some()),
+            Ok(None) => Ok(false),
+            Err(e) => Err(e),
+        };
+        match has {
+            Ok(v) => Ok(Reply::Has(v)),
+            Err(e) => Err(format!("{:?}", e)),
         }
     }
 
-    async fn get(db: &dyn Store, data: &str) -> Response {
-        let req: GetRequest = match DeJson::deserialize_json(data) {
+    async fn get(
+        store: &dag::store::Store,
+        key: &str,
+        root: Option<Hash>,
+    ) -> Result<Reply, String> {
+        let read = match store.read().await {
             Ok(v) => v,
-            Err(_) => return Err("Failed to parse request".into()),
-        };
-        match db.get(&req.key).await {
-            Ok(Some(v)) => match std::str::from_utf8(&v[..]) {
-                Ok(v) => Ok(SerJson::serialize_json(&GetResponse {
-                    has: true,
-                    value: Some(v.into()),
-                })),
+            Err(e) => return Err(format!("{:?}", e)),
+        };
+        let root = match root {
+            Some(root) => Ok(Some(root)),
+            None => read.get_head(MAIN).await,
+        };
+        let stored = match root {
+            Ok(Some(root)) => map::get(&read, &root, key).await,
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        };
+        let value = match stored {
+            Ok(Some(v)) => blob::get_value(&read, v).await.map(Some),
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        };
+        match value {
+            Ok(Some(v)) => match String::from_utf8(v) {
+                Ok(v) => Ok(Reply::Get(Some(v))),
                 Err(e) => Err(e.to_string()),
             },
-            Ok(None) => Ok(SerJson::serialize_json(&GetResponse {
-                has: false,
-                value: None,
-            })),
-            Err(e) => Err(format!("{}", e)),
+            Ok(None) => Ok(Reply::Get(None)),
+            Err(e) => Err(format!("{:?}", e)),
         }
     }
 
-    async fn put(db: &mut dyn Store, data: &str) -> Response {
-        let req: PutRequest = match DeJson::deserialize_json(data) {
+    async fn root(store: &dag::store::Store) -> Result<Reply, String> {
+        let read = match store.read().await {
             Ok(v) => v,
-            Err(_) => return Err("Failed to parse request".into()),
+            Err(e) => return Err(format!("{:?}", e)),
         };
-        match db.put(&req.key, &req.value.into_bytes()).await {
-            Ok(_) => Ok("".into()),
+        match read.get_head(MAIN).await {
+            Ok(root) => Ok(Reply::Root(root)),
+            Err(e) => Err(format!("{:?}", e)),
+        }
+    }
+
+    // Puts entries, if base is None or the main head's map still has the
+    // root base holds.
+    async fn put(
+        store: &dag::store::Store,
+        chunker: &chunker::ChunkerConfig,
+        entries: &[(String, String)],
+        base: Option<Option<Hash>>,
+    ) -> Result<Reply, String> {
+        // Reads dispatched along with this write run first, so that they
+        // open their transactions before it opens its own: IndexedDB
+        // holds back a read transaction created after an overlapping write
+        // until the write is done. Values are chunked before the write
+        // opens, too, so that later reads are not held back by that.
+        async_std::task::yield_now().await;
+        let mut stored = Vec::with_capacity(entries.len());
+        let mut chunks = Vec::new();
+        for (key, value) in entries {
+            let value = match json::canonicalize(value) {
+                Ok(v) => v,
+                Err(e) => return Err(format!("Invalid JSON value: {}", e)),
+            };
+            let (v, c) = blob::encode_value(chunker, &value);
+            stored.push((key.clone(), v));
+            chunks.extend(c);
+        }
+        let write = match store.write().await {
+            Ok(v) => v,
+            Err(e) => return Err(format!("{:?}", e)),
+        };
+        for c in chunks.iter() {
+            if let Err(e) = dag::write::put_chunk(write.kv(), c).await {
+                return Err(format!("{:?}", e));
+            }
+        }
+        // Writes run one at a time, so the nodes of the map read here are
+        // committed ones, which the cache may hold.
+        let read = write.read();
+        let root = match read.get_head(MAIN).await {
+            Ok(root) if base.is_some() && base != Some(root) => {
+                return Err("Transaction conflicts with a write made since it began".into())
+            }
+            Ok(root) => map::put(write.kv(), &read, root.as_ref(), stored).await,
+            Err(e) => Err(e),
+        };
+        let result = match root {
+            Ok(root) => dag::write::set_head(write.kv(), MAIN, &root).await,
+            Err(e) => Err(e),
+        };
+        drop(read);
+        if let Err(e) = result {
+            return Err(format!("{:?}", e));
+        }
+        match write.commit().await {
+            Ok(_) => Ok(Reply::Empty),
+            Err(e) => Err(format!("{:?}", e)),
+        }
+    }
+
+    async fn scan(store: &dag::store::Store, scan: &Scan) -> Result<Reply, String> {
+        if let Some(name) = &scan.index_name {
+            return Err(format!("Unknown index \"{}\"", name));
+        }
+        let opts = ScanOptions {
+            prefix: &scan.prefix,
+            start_key: scan.start_key.as_deref(),
+            start_exclusive: scan.start_exclusive,
+        };
+        let limit = match scan.limit {
+            Some(limit) => usize::try_from(limit).unwrap_or(usize::MAX),
+            None => usize::MAX,
+        };
+
+        // Visit one more item than the limit to find out whether to
+        // return a cursor.
+        let mut items = Vec::new();
+        let mut more = false;
+        let snapshot = match scan.root {
+            Some(root) => store.snapshot_at(root),
+            None => match store.snapshot(MAIN).await {
+                Ok(Some(v)) => v,
+                Ok(None) => {
+                    return Ok(Reply::Scan {
+                        items: Vec::new(),
+                        cursor: None,
+                        root: None,
+                    })
+                }
+                Err(e) => return Err(format!("{:?}", e)),
+            },
+        };
+        let result = map::scan(&snapshot, snapshot.hash(), &opts, &mut |key, value| {
+            if items.len() == limit {
+                more = true;
+                return false;
+            }
+            items.push((key.to_string(), value.to_vec()));
+            true
+        })
+        .await;
+        if let Err(e) = result {
+            return Err(format!("{:?}", e));
+        }
+
+        // Blobs are read in batches rather than value by value.
+        let (keys, stored): (Vec<String>, Vec<Vec<u8>>) = items.into_iter().unzip();
+        let values = match blob::get_values(&snapshot, stored).await {
+            Ok(v) => v,
+            Err(e) => return Err(format!("{:?}", e)),
+        };
+        let mut items = Vec::with_capacity(keys.len());
+        for (key, value) in keys.into_iter().zip(values) {
+            match String::from_utf8(value) {
+                Ok(value) => items.push((key, value)),
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        let cursor = match more {
+            true => items.last().map(|(key, _)| key.clone()),
+            false => None,
+        };
+        Ok(Reply::Scan {
+            items,
+            cursor,
+            root: Some(*snapshot.hash()),
+        })
+    }
+
+    // Archives head and the chunks reachable from it. The main head's are
+    // the database's entries and the blobs of their values, so importing
+    // its archive into another database restores them there.
+    async fn export(store: &dag::store::Store, head: &str) -> Result<Reply, String> {
+        let read = match store.read().await {
+            Ok(v) => v,
+            Err(e) => return Err(format!("{:?}", e)),
+        };
+        match dag::archive::export(&read, head).await {
+            Ok(archive) => Ok(Reply::Archive(archive)),
             Err(e) => Err(format!("{}", e)),
         }
     }
 
-    async fn debug(&self, req: &Request) -> Response {
-        match req.data.as_str() {
+    // Writes the chunks of archive and sets its heads, replacing the
+    // database's entries if it has a main head.
+    async fn import(store: &dag::store::Store, archive: &[u8]) -> Result<Reply, String> {
+        let mut write = match store.write().await {
+            Ok(v) => v,
+            Err(e) => return Err(format!("{:?}", e)),
+        };
+        if let Err(e) = dag::archive::import(&mut write, archive).await {
+            return Err(format!("{}", e));
+        }
+        match write.commit().await {
+            Ok(_) => Ok(Reply::Empty),
+            Err(e) => Err(format!("{:?}", e)),
+        }
+    }
+
+    async fn debug(&self, db_name: &str, command: &str) -> Result<Reply, String> {
+        let text = match command {
             "open_dbs" => Ok(format!("{:?}", self.connections.keys())),
+            "check" => match self.connections.get(db_name) {
+                Some(conn) => Dispatcher::check(conn.store.kv()).await,
+                None => Err(format!("\"{}\" not open", db_name)),
+            },
+            "events" => Ok(trace::events().join("\n")),
+            "cache" => match self.connections.get(db_name) {
+                Some(conn) => Ok(conn.store.cache_stats().to_string()),
+                None => Err(format!("\"{}\" not open", db_name)),
+            },
             _ => Err("Debug command not defined".into()),
+        };
+        text.map(Reply::Debug)
+    }
+
+    // Checks the integrity of the dag in db. Puts leave the nodes of the
+    // map they replace unreachable, and nothing collects those yet, so
+    // unreachable chunks are not reported.
+    async fn check(db: &dyn Store) -> Response {
+        let rt = match db.read().await {
+            Ok(v) => v,
+            Err(e) => return Err(format!("{}", e)),
+        };
+        match dag::check::check(rt.as_ref()).await {
+            Ok(mut report) => {
+                report
+                    .problems
+                    .retain(|p| !matches!(p, dag::check::Problem::Orphan(_)));
+                Ok(report.to_string())
+            }
+            Err(e) => Err(format!("{:?}", e)),
         }
     }
 }
 
-pub async fn dispatch(db_name: String, rpc: String, data: String) -> Response {
-    let (tx, rx) = channel::<Response>(1);
+// Numbers requests, to tell them apart in logs.
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Sends rpc on db_name to the dispatch loop, and returns its reply.
+pub async fn call(db_name: String, rpc: Rpc) -> Result<Reply, String> {
+    let id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+    let mut span = trace::Span::new(|| format!("request {} {} \"{}\"", id, rpc.name(), db_name));
+    let result = send(db_name, rpc).await;
+    if let Err(e) = &result {
+        span.fail(e);
+    }
+    result
+}
+
+async fn send(db_name: String, rpc: Rpc) -> Result<Reply, String> {
+    let (tx, rx) = channel::<Result<Reply, String>>(1);
     let request = Request {
         db_name,
         rpc,
-        data,
         response: tx,
     };
     match SENDER.lock() {
-        Ok(v) => v.send(request).await,
+        Ok(v) => {
+            if let Err(e) = v.unbounded_send(request) {
+                return Err(e.to_string());
+            }
+        }
         Err(e) => return Err(e.to_string()),
     }
     match rx.recv().await {
@@ -187,3 +839,319 @@ pub async fn dispatch(db_name: String, rpc: String, data: String) -> Response {
         Ok(v) => v,
     }
 }
+
+/// The string API: decodes the JSON in data as the arguments of rpc,
+/// calls it, and encodes its reply as JSON.
+pub async fn dispatch(db_name: String, rpc: String, data: String) -> Response {
+    let rpc = match decode(&rpc, &data) {
+        Ok(v) => v,
+        // A database's RPCs fail as not open before failing to decode.
+        Err(e) if rpc != "open" && rpc != "setLogLevel" => Rpc::Invalid(e),
+        Err(e) => return Err(e),
+    };
+    call(db_name, rpc).await.map(encode)
+}
+
+fn decode(rpc: &str, data: &str) -> Result<Rpc, String> {
+    fn parse<T: DeJson>(data: &str) -> Result<T, String> {
+        DeJson::deserialize_json(data).map_err(|_| "Failed to parse request".to_string())
+    }
+    Ok(match rpc {
+        // Options are optional, for callers from before there were any.
+        "open" if data.is_empty() => Rpc::Open { backend: None },
+        "open" => Rpc::Open {
+            backend: parse::<OpenRequest>(data)?.backend,
+        },
+        "close" => Rpc::Close,
+        "drop" => Rpc::Drop,
+        "list" => Rpc::List,
+        "debug" => Rpc::Debug(data.into()),
+        "getClientId" => Rpc::GetClientId,
+        "has" => Rpc::Has(parse::<GetRequest>(data)?.key, None),
+        "get" => Rpc::Get(parse::<GetRequest>(data)?.key, None),
+        "put" => {
+            let req: PutRequest = parse(data)?;
+            Rpc::Put(vec![(req.key, req.value)])
+        }
+        "scan" => {
+            let req: ScanRequest = parse(data)?;
+            let root = match req.root.as_deref().map(Hash::parse) {
+                Some(Ok(v)) => Some(v),
+                Some(Err(_)) => return Err("Invalid root".into()),
+                None => None,
+            };
+            Rpc::Scan(Scan {
+                prefix: req.prefix.unwrap_or_default(),
+                start_key: req.start.as_ref().map(|s| s.key.clone()),
+                start_exclusive: req.start.and_then(|s| s.exclusive) == Some(true),
+                limit: req.limit,
+                index_name: req.index_name,
+                root,
+            })
+        }
+        "setLogLevel" => {
+            let req: SetLogLevelRequest = parse(data)?;
+            match req.level.parse() {
+                Ok(level) => Rpc::SetLogLevel(level, req.buffer_size),
+                Err(_) => return Err(format!("Unknown log level \"{}\"", req.level)),
+            }
+        }
+        "export" => Rpc::Export(
+            parse::<ExportRequest>(data)?
+                .head
+                .unwrap_or_else(|| MAIN.into()),
+        ),
+        "import" => {
+            let req: ImportRequest = parse(data)?;
+            match data_encoding::base64::decode(req.archive.as_bytes()) {
+                Ok(v) => Rpc::Import(v),
+                Err(e) => return Err(format!("Invalid archive encoding: {}", e)),
+            }
+        }
+        _ => return Err("Unsupported rpc name".into()),
+    })
+}
+
+fn encode(reply: Reply) -> String {
+    match reply {
+        Reply::Open { client_id, backend } => {
+            SerJson::serialize_json(&OpenResponse { client_id, backend })
+        }
+        Reply::Empty => "".into(),
+        // Formatted by hand, like ClientId below; SerJson escapes the names.
+        Reply::Databases(names) => format!("{{\"databases\":{}}}", SerJson::serialize_json(&names)),
+        Reply::Debug(text) => text,
+        // Formatted by hand because nanoserde serializes single-field
+        // structs as "{}". Client ids never need escaping.
+        Reply::ClientId(client_id) => format!("{{\"clientId\":\"{}\"}}", client_id),
+        Reply::Has(has) => SerJson::serialize_json(&GetResponse { has, value: None }),
+        Reply::Get(value) => SerJson::serialize_json(&GetResponse {
+            has: value.is_some(),
+            value,
+        }),
+        // Only the class API asks for roots.
+        Reply::Root(root) => match root {
+            Some(root) => format!("{{\"root\":\"{}\"}}", root),
+            None => "{}".into(),
+        },
+        Reply::Scan {
+            items,
+            cursor,
+            root,
+        } => SerJson::serialize_json(&ScanResponse {
+            cursor,
+            root: root.map(|h| h.to_string()),
+            items: items
+                .into_iter()
+                .map(|(key, value)| ScanItem { key, value })
+                .collect(),
+        }),
+        Reply::Archive(archive) => format!(
+            "{{\"archive\":\"{}\"}}",
+            data_encoding::base64::encode(&archive)
+        ),
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dag::store::Store as DagStore;
+
+    async fn scan(db: &DagStore, scan: Scan) -> Vec<(String, String)> {
+        match Dispatcher::scan(db, &scan).await {
+            Ok(Reply::Scan { items, .. }) => items,
+            _ => panic!("scan failed"),
+        }
+    }
+
+    #[async_std::test]
+    async fn entries_apart_from_chunks() {
+        let db = DagStore::new(Box::new(MemStore::new()));
+        let big = format!("\"{}\"", "x".repeat(blob::MIN_BLOB_SIZE));
+        let entries: Vec<(String, String)> = [
+            ("a", "1"),
+            ("big", big.as_str()),
+            ("c/x", "2"),
+            ("h/main", "3"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let config = chunker::ChunkerConfig::default();
+        assert!(Dispatcher::put(&db, &config, &entries, None).await.is_ok());
+
+        // Scans see the entries, including the blob, and not the chunks
+        // or heads that store them.
+        assert_eq!(entries, scan(&db, Scan::default()).await);
+        let scan_c = Scan {
+            prefix: "c/".into(),
+            ..Default::default()
+        };
+        assert_eq!(entries[2..3], scan(&db, scan_c).await[..]);
+        match Dispatcher::get(&db, "big", None).await {
+            Ok(Reply::Get(Some(v))) => assert_eq!(big, v),
+            _ => panic!("get failed"),
+        }
+        assert!(matches!(
+            Dispatcher::has(&db, "c/", None).await,
+            Ok(Reply::Has(false))
+        ));
+
+        // Keys that look like the dag's are entries like any other.
+        let report = Dispatcher::check(db.kv()).await.unwrap();
+        assert!(report.ends_with(" 0 problems"), "{}", report);
+    }
+
+    #[async_std::test]
+    async fn scan_pinned_root() {
+        let db = DagStore::new(Box::new(MemStore::new()));
+        let config = chunker::ChunkerConfig::default();
+        assert!(matches!(
+            Dispatcher::scan(&db, &Scan::default()).await,
+            Ok(Reply::Scan { root: None, .. })
+        ));
+        let entries = vec![("a".to_string(), "1".to_string())];
+        assert!(Dispatcher::put(&db, &config, &entries, None).await.is_ok());
+        let root = match Dispatcher::scan(&db, &Scan::default()).await {
+            Ok(Reply::Scan { root, .. }) => root,
+            _ => panic!("scan failed"),
+        };
+        assert!(root.is_some());
+
+        // Scans of the root returned see the entries it had, however
+        // main has moved on since.
+        let more = vec![("b".to_string(), "2".to_string())];
+        assert!(Dispatcher::put(&db, &config, &more, None).await.is_ok());
+        let pinned = Scan {
+            root,
+            ..Default::default()
+        };
+        assert_eq!(entries, scan(&db, pinned).await);
+        assert_eq!(2, scan(&db, Scan::default()).await.len());
+    }
+
+    #[async_std::test]
+    async fn commit_at_root() {
+        let db = DagStore::new(Box::new(MemStore::new()));
+        let config = chunker::ChunkerConfig::default();
+        let root = || async {
+            match Dispatcher::root(&db).await {
+                Ok(Reply::Root(root)) => root,
+                _ => panic!("root failed"),
+            }
+        };
+        let entries = |key: &str, value: &str| vec![(key.to_string(), value.to_string())];
+
+        // A commit goes ahead if main has not moved since its root.
+        let empty = root().await;
+        assert_eq!(None, empty);
+        assert!(
+            Dispatcher::put(&db, &config, &entries("a", "1"), Some(empty))
+                .await
+                .is_ok()
+        );
+        let first = root().await;
+        assert!(first.is_some());
+
+        // Reads at a root see the entries it had.
+        assert!(Dispatcher::put(&db, &config, &entries("a", "2"), None)
+            .await
+            .is_ok());
+        match Dispatcher::get(&db, "a", first).await {
+            Ok(Reply::Get(Some(v))) => assert_eq!("1", v),
+            _ => panic!("get failed"),
+        }
+        assert!(matches!(
+            Dispatcher::has(&db, "a", first).await,
+            Ok(Reply::Has(true))
+        ));
+
+        // Otherwise it fails, and writes nothing.
+        for base in [empty, first].iter() {
+            assert_eq!(
+                Err("Transaction conflicts with a write made since it began".into()),
+                Dispatcher::put(&db, &config, &entries("b", "3"), Some(*base))
+                    .await
+                    .map(|_| ())
+            );
+        }
+        assert!(matches!(
+            Dispatcher::has(&db, "b", None).await,
+            Ok(Reply::Has(false))
+        ));
+    }
+
+    #[test]
+    fn scan_root_json() {
+        let root = Hash::of(b"root");
+        let json = encode(Reply::Scan {
+            items: vec![],
+            cursor: Some("a".into()),
+            root: Some(root),
+        });
+        assert_eq!(
+            format!("{{\"cursor\":\"a\",\"root\":\"{}\",\"items\":[]}}", root),
+            json
+        );
+        let data = format!("{{\"root\": \"{}\", \"limit\": 4294967296}}", root);
+        match decode("scan", &data) {
+            Ok(Rpc::Scan(scan)) => {
+                assert_eq!((Some(root), Some(1 << 32)), (scan.root, scan.limit));
+            }
+            _ => panic!("decode failed"),
+        }
+        assert!(decode("scan", "{\"root\": \"nope\"}").is_err());
+        assert_eq!(
+            "{\"items\":[]}",
+            encode(Reply::Scan {
+                items: vec![],
+                cursor: None,
+                root: None,
+            })
+        );
+    }
+
+    #[async_std::test]
+    async fn archive_round_trip() {
+        let db = DagStore::new(Box::new(MemStore::new()));
+        let big = format!("\"{}\"", "x".repeat(blob::MIN_BLOB_SIZE));
+        let entries: Vec<(String, String)> = [("a", "1"), ("big", big.as_str()), ("c", "[]")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let config = chunker::ChunkerConfig::default();
+        assert!(Dispatcher::put(&db, &config, &entries, None).await.is_ok());
+        let archive = match Dispatcher::export(&db, MAIN).await {
+            Ok(Reply::Archive(v)) => v,
+            _ => panic!("export failed"),
+        };
+
+        let copy = DagStore::new(Box::new(MemStore::new()));
+        assert!(Dispatcher::import(&copy, &archive).await.is_ok());
+        assert_eq!(entries, scan(&copy, Scan::default()).await);
+        match Dispatcher::get(&copy, "big", None).await {
+            Ok(Reply::Get(Some(v))) => assert_eq!(big, v),
+            _ => panic!("get failed"),
+        }
+        let report = Dispatcher::check(copy.kv()).await.unwrap();
+        assert!(report.ends_with(" 0 problems"), "{}", report);
+    }
+
+    #[async_std::test]
+    async fn system_keys_apart() {
+        let db = DagStore::new(Box::new(MemStore::new()));
+        let id = client_id::init(db.kv()).await.unwrap();
+        let entries: Vec<(String, String)> = [("sys/chunker", "1"), ("sys/cid", "\"mine\"")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let config = chunker::ChunkerConfig::default();
+        assert!(Dispatcher::put(&db, &config, &entries, None).await.is_ok());
+
+        assert_eq!(id, client_id::init(db.kv()).await.unwrap());
+        assert_eq!(config, chunker::init(db.kv()).await.unwrap());
+        assert_eq!(entries, scan(&db, Scan::default()).await);
+    }
+}
@@ -3,8 +3,10 @@ use data_encoding::decode;
 use data_encoding::encode;
 use sha2::{Digest, Sha512};
 use std::fmt;
+use std::io;
 
 pub const BYTE_LENGTH: usize = 20;
+pub const STRING_LENGTH: usize = 32;
 const NOMS_ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuv";
 
 struct Base32 {}
@@ -18,6 +20,7 @@ impl base::Base for Base32 {
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Hash {
     pub sum: [u8; BYTE_LENGTH],
 }
@@ -39,8 +42,21 @@ impl Hash {
         Hash { sum }
     }
 
-    #[allow(dead_code)]
+    /// Returns the hash whose sum is the first BYTE_LENGTH bytes of
+    /// buf, or None if buf is too short.
+    pub fn from_slice(buf: &[u8]) -> Option<Hash> {
+        if buf.len() < BYTE_LENGTH {
+            return None;
+        }
+        let mut h = Hash::empty();
+        h.sum.copy_from_slice(&buf[..BYTE_LENGTH]);
+        Some(h)
+    }
+
     pub fn parse(s: &str) -> Result<Hash, Error> {
+        if s.len() != STRING_LENGTH {
+            return Err(Error::InvalidHashSerialization);
+        }
         let mut h = Hash::empty();
         match decode::decode_mut(&Base32 {}, s.as_bytes(), &mut h.sum) {
             Err(_) => Err(Error::InvalidHashSerialization),
@@ -48,14 +64,10 @@ impl Hash {
         }
     }
 
-    #[allow(dead_code)]
     pub fn of(data: &[u8]) -> Hash {
-        let mut hasher = Sha512::new();
-        hasher.input(data);
-        let result = hasher.result();
-        let mut h = Hash::empty();
-        h.sum.copy_from_slice(&result[..BYTE_LENGTH]);
-        h
+        let mut hasher = Hasher::new();
+        hasher.update(data);
+        hasher.finish()
     }
 
     #[allow(dead_code)]
@@ -70,6 +82,49 @@ impl fmt::Display for Hash {
     }
 }
 
+/// Computes a Hash incrementally, so that data need not be in one
+/// buffer. Hasher::new() followed by update(a) and update(b) yields the
+/// same hash as Hash::of(a + b).
+pub struct Hasher {
+    inner: Sha512,
+}
+
+impl Hasher {
+    pub fn new() -> Hasher {
+        Hasher {
+            inner: Sha512::new(),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.input(data);
+    }
+
+    pub fn finish(self) -> Hash {
+        let result = self.inner.result();
+        let mut h = Hash::empty();
+        h.sum.copy_from_slice(&result[..BYTE_LENGTH]);
+        h
+    }
+}
+
+impl Default for Hasher {
+    fn default() -> Self {
+        Hasher::new()
+    }
+}
+
+impl io::Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -86,5 +141,66 @@ mod tests {
 
         let h2 = Hash::parse("rmnjb8cjc5tblj21ed4qs821649eduie").unwrap();
         assert_eq!(h2.to_string(), h.to_string());
+        assert_eq!(h2, h);
+    }
+
+    #[test]
+    fn test_hasher() {
+        fn test(parts: &[&[u8]]) {
+            let mut hasher = Hasher::new();
+            for p in parts {
+                hasher.update(p);
+            }
+            assert_eq!(Hash::of(&parts.concat()), hasher.finish());
+        }
+        test(&[]);
+        test(&[b""]);
+        test(&[b"abc"]);
+        test(&[b"a", b"bc"]);
+        test(&[b"a", b"", b"b", b"c"]);
+        test(&[&[7; 1000], &[8; 1], &[9; 4096]]);
+
+        let mut hasher = Hasher::default();
+        std::io::copy(&mut &b"abc"[..], &mut hasher).unwrap();
+        assert_eq!(
+            "rmnjb8cjc5tblj21ed4qs821649eduie",
+            hasher.finish().to_string()
+        );
+    }
+
+    #[test]
+    fn test_map_key() {
+        use std::collections::{BTreeSet, HashSet};
+
+        let (a, b) = (Hash::of(b"a"), Hash::of(b"b"));
+        let hs: HashSet<Hash> = [a, b, a].iter().copied().collect();
+        assert_eq!(2, hs.len());
+        assert!(hs.contains(&b));
+
+        let bs: BTreeSet<Hash> = [b, a, Hash::empty()].iter().copied().collect();
+        assert_eq!(Some(&Hash::empty()), bs.iter().next());
+        assert_eq!(a < b, a.sum < b.sum);
+    }
+
+    #[test]
+    fn test_parse() {
+        fn test(s: &str, ok: bool) {
+            assert_eq!(ok, Hash::parse(s).is_ok(), "{}", s);
+        }
+        test("00000000000000000000000000000000", true);
+        test("rmnjb8cjc5tblj21ed4qs821649eduie", true);
+        test("", false);
+        test("rmnjb8cjc5tblj21ed4qs821649edui", false);
+        test("rmnjb8cjc5tblj21ed4qs821649eduiee", false);
+        test("rmnjb8cjc5tblj21ed4qs821649eduie00000000", false);
+        test("rmnjb8cjc5tblj21ed4qs821649eduiw", false);
+        test("RMNJB8CJC5TBLJ21ED4QS821649EDUIE", false);
+    }
+
+    #[test]
+    fn test_from_slice() {
+        let h = Hash::of(b"abc");
+        assert_eq!(Some(h), Hash::from_slice(&h.sum));
+        assert_eq!(None, Hash::from_slice(&h.sum[1..]));
     }
 }
@@ -0,0 +1,505 @@
+//! A JSON value model with a canonical encoding.
+//!
+//! Values stored in Replicache are JSON. To make hashing, diffing and
+//! indexing well defined, every value is parsed on the way in and
+//! re-encoded canonically, so that two values that are equal as JSON
+//! always produce identical bytes (and therefore identical hashes):
+//!
+//!  * insignificant whitespace is removed,
+//!  * object keys are sorted (by their UTF-8 bytes), and duplicate
+//!    keys are rejected,
+//!  * strings only escape `"`, `\` and control characters,
+//!  * numbers are IEEE-754 doubles written the way ECMAScript's
+//!    `Number.prototype.toString()` writes them (so `1.0`, `1e0` and
+//!    `1` are all `1`, and `-0` is `0`).
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Write;
+
+// Deeper documents are rejected rather than risk overflowing the stack.
+const MAX_DEPTH: usize = 512;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(BTreeMap<String, Value>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    UnexpectedEnd,
+    UnexpectedChar(usize),
+    InvalidNumber(usize),
+    InvalidEscape(usize),
+    InvalidUnicode(usize),
+    DuplicateKey(String),
+    TooDeep,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnexpectedEnd => write!(f, "unexpected end of input"),
+            Error::UnexpectedChar(pos) => write!(f, "unexpected character at {}", pos),
+            Error::InvalidNumber(pos) => write!(f, "invalid number at {}", pos),
+            Error::InvalidEscape(pos) => write!(f, "invalid escape at {}", pos),
+            Error::InvalidUnicode(pos) => write!(f, "invalid unicode at {}", pos),
+            Error::DuplicateKey(key) => write!(f, "duplicate key \"{}\"", key),
+            Error::TooDeep => write!(f, "nesting deeper than {}", MAX_DEPTH),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+impl Value {
+    /// Parses a complete JSON document, which may be any JSON value
+    /// (not only an object or array).
+    pub fn parse(s: &str) -> Result<Value> {
+        let mut p = Parser {
+            buf: s.as_bytes(),
+            pos: 0,
+        };
+        let v = p.parse_value(0)?;
+        p.skip_whitespace();
+        if p.pos < p.buf.len() {
+            return Err(Error::UnexpectedChar(p.pos));
+        }
+        Ok(v)
+    }
+
+    /// Returns the canonical encoding of the value.
+    pub fn to_canonical(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+}
+
+/// Parses `s` and returns its canonical encoding.
+pub fn canonicalize(s: &str) -> Result<Vec<u8>> {
+    Ok(Value::parse(s)?.to_canonical())
+}
+
+// Display writes the canonical encoding.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) => write_number(f, *n),
+            Value::String(s) => write_string(f, s),
+            Value::Array(items) => {
+                f.write_char('[')?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_char(']')
+            }
+            Value::Object(entries) => {
+                f.write_char('{')?;
+                for (i, (k, v)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_string(f, k)?;
+                    f.write_char(':')?;
+                    write!(f, "{}", v)?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\u{08}' => f.write_str("\\b")?,
+            '\u{0c}' => f.write_str("\\f")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if c < ' ' => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+// Writes n as ECMAScript's Number.prototype.toString() would. Values
+// only come from the parser, so n is always finite.
+fn write_number(f: &mut fmt::Formatter<'_>, n: f64) -> fmt::Result {
+    if n == 0.0 {
+        return f.write_char('0');
+    }
+    if n < 0.0 {
+        f.write_char('-')?;
+    }
+    // {:e} gives the shortest digits that round trip, eg "1.2345e2".
+    let sci = format!("{:e}", n.abs());
+    let (mantissa, exp) = sci.split_at(sci.find('e').unwrap_or(sci.len()));
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+    let k = digits.len() as i32;
+    // The decimal point goes after the n'th digit.
+    let n = exp[1..].parse::<i32>().unwrap_or(0) + 1;
+    if k <= n && n <= 21 {
+        f.write_str(&digits)?;
+        for _ in 0..n - k {
+            f.write_char('0')?;
+        }
+        Ok(())
+    } else if 0 < n && n <= 21 {
+        let (int, frac) = digits.split_at(n as usize);
+        write!(f, "{}.{}", int, frac)
+    } else if -6 < n && n <= 0 {
+        f.write_str("0.")?;
+        for _ in 0..-n {
+            f.write_char('0')?;
+        }
+        f.write_str(&digits)
+    } else {
+        let (first, rest) = digits.split_at(1);
+        f.write_str(first)?;
+        if !rest.is_empty() {
+            write!(f, ".{}", rest)?;
+        }
+        let e = n - 1;
+        write!(f, "e{}{}", if e < 0 { '-' } else { '+' }, e.abs())
+    }
+}
+
+struct Parser<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.buf.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<u8> {
+        let b = self.peek().ok_or(Error::UnexpectedEnd)?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn expect(&mut self, b: u8) -> Result<()> {
+        let pos = self.pos;
+        if self.next()? != b {
+            return Err(Error::UnexpectedChar(pos));
+        }
+        Ok(())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn parse_value(&mut self, depth: usize) -> Result<Value> {
+        if depth > MAX_DEPTH {
+            return Err(Error::TooDeep);
+        }
+        self.skip_whitespace();
+        match self.peek().ok_or(Error::UnexpectedEnd)? {
+            b'n' => self.parse_literal("null", Value::Null),
+            b't' => self.parse_literal("true", Value::Bool(true)),
+            b'f' => self.parse_literal("false", Value::Bool(false)),
+            b'"' => Ok(Value::String(self.parse_string()?)),
+            b'[' => self.parse_array(depth),
+            b'{' => self.parse_object(depth),
+            b'-' | b'0'..=b'9' => self.parse_number(),
+            _ => Err(Error::UnexpectedChar(self.pos)),
+        }
+    }
+
+    fn parse_literal(&mut self, lit: &str, v: Value) -> Result<Value> {
+        for b in lit.bytes() {
+            self.expect(b)?;
+        }
+        Ok(v)
+    }
+
+    fn parse_array(&mut self, depth: usize) -> Result<Value> {
+        self.expect(b'[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.parse_value(depth + 1)?);
+            self.skip_whitespace();
+            let pos = self.pos;
+            match self.next()? {
+                b',' => continue,
+                b']' => return Ok(Value::Array(items)),
+                _ => return Err(Error::UnexpectedChar(pos)),
+            }
+        }
+    }
+
+    fn parse_object(&mut self, depth: usize) -> Result<Value> {
+        self.expect(b'{')?;
+        let mut entries = BTreeMap::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Value::Object(entries));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.parse_string()?;
+            self.skip_whitespace();
+            self.expect(b':')?;
+            let value = self.parse_value(depth + 1)?;
+            if entries.contains_key(&key) {
+                return Err(Error::DuplicateKey(key));
+            }
+            entries.insert(key, value);
+            self.skip_whitespace();
+            let pos = self.pos;
+            match self.next()? {
+                b',' => continue,
+                b'}' => return Ok(Value::Object(entries)),
+                _ => return Err(Error::UnexpectedChar(pos)),
+            }
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<Value> {
+        let start = self.pos;
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        // Int part: a lone zero or a non-zero digit followed by digits.
+        match self.next()? {
+            b'0' => (),
+            b'1'..=b'9' => {
+                self.skip_digits();
+            }
+            _ => return Err(Error::InvalidNumber(start)),
+        }
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            if !self.skip_digits() {
+                return Err(Error::InvalidNumber(start));
+            }
+        }
+        if let Some(b'e') | Some(b'E') = self.peek() {
+            self.pos += 1;
+            if let Some(b'+') | Some(b'-') = self.peek() {
+                self.pos += 1;
+            }
+            if !self.skip_digits() {
+                return Err(Error::InvalidNumber(start));
+            }
+        }
+        // The slice is ASCII, and the grammar above is a subset of what
+        // f64::from_str accepts.
+        let s = std::str::from_utf8(&self.buf[start..self.pos])
+            .map_err(|_| Error::InvalidNumber(start))?;
+        match s.parse::<f64>() {
+            Ok(n) if n.is_finite() => Ok(Value::Number(n)),
+            _ => Err(Error::InvalidNumber(start)),
+        }
+    }
+
+    // Returns true if at least one digit was skipped.
+    fn skip_digits(&mut self) -> bool {
+        let start = self.pos;
+        while let Some(b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+        }
+        self.pos > start
+    }
+
+    fn parse_string(&mut self) -> Result<String> {
+        self.expect(b'"')?;
+        let mut out = Vec::new();
+        loop {
+            let pos = self.pos;
+            match self.next()? {
+                b'"' => break,
+                b'\\' => {
+                    let c = match self.next()? {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{08}',
+                        b'f' => '\u{0c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.parse_unicode_escape(pos)?,
+                        _ => return Err(Error::InvalidEscape(pos)),
+                    };
+                    let mut tmp = [0; 4];
+                    out.extend_from_slice(c.encode_utf8(&mut tmp).as_bytes());
+                }
+                b if b < b' ' => return Err(Error::UnexpectedChar(pos)),
+                b => out.push(b),
+            }
+        }
+        // The input was a &str, so unescaped bytes are valid UTF-8.
+        String::from_utf8(out).map_err(|_| Error::InvalidUnicode(self.pos))
+    }
+
+    // Parses the XXXX of a \uXXXX escape, along with a following low
+    // surrogate escape if XXXX is a high surrogate. Lone surrogates
+    // cannot be represented in a String and are rejected.
+    fn parse_unicode_escape(&mut self, pos: usize) -> Result<char> {
+        let hi = self.parse_hex4(pos)?;
+        let code = match hi {
+            0xd800..=0xdbff => {
+                self.expect(b'\\').map_err(|_| Error::InvalidUnicode(pos))?;
+                self.expect(b'u').map_err(|_| Error::InvalidUnicode(pos))?;
+                let lo = self.parse_hex4(pos)?;
+                if !(0xdc00..=0xdfff).contains(&lo) {
+                    return Err(Error::InvalidUnicode(pos));
+                }
+                0x10000 + ((hi - 0xd800) << 10) + (lo - 0xdc00)
+            }
+            0xdc00..=0xdfff => return Err(Error::InvalidUnicode(pos)),
+            _ => hi,
+        };
+        std::char::from_u32(code).ok_or(Error::InvalidUnicode(pos))
+    }
+
+    fn parse_hex4(&mut self, pos: usize) -> Result<u32> {
+        let mut v = 0;
+        for _ in 0..4 {
+            let d = match self.next()? {
+                b @ b'0'..=b'9' => b - b'0',
+                b @ b'a'..=b'f' => b - b'a' + 10,
+                b @ b'A'..=b'F' => b - b'A' + 10,
+                _ => return Err(Error::InvalidEscape(pos)),
+            };
+            v = v << 4 | d as u32;
+        }
+        Ok(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::Hash;
+
+    #[test]
+    fn canonical() {
+        fn test(input: &str, expected: &str) {
+            let v = Value::parse(input).unwrap();
+            assert_eq!(expected, v.to_string(), "input: {}", input);
+            // Canonical encodings are fixed points.
+            assert_eq!(v, Value::parse(expected).unwrap());
+        }
+        test("null", "null");
+        test(" true ", "true");
+        test("false", "false");
+        test("\"\"", "\"\"");
+        test("\"a\\/b\"", "\"a/b\"");
+        test("\"\\u0041\\u00e9\"", "\"Aé\"");
+        test("\"世界\"", "\"世界\"");
+        test("\"\\ud83d\\ude00\"", "\"😀\"");
+        test("\"\\u0000\\u001f\\n\\t\"", "\"\\u0000\\u001f\\n\\t\"");
+        test("\"\\\"\\\\\"", "\"\\\"\\\\\"");
+        test("[ ]", "[]");
+        test("[1, [2, [ ]], {}]", "[1,[2,[]],{}]");
+        test("{ }", "{}");
+        test(
+            "{\"b\": 1, \"a\": {\"d\": 2, \"c\": 3}}",
+            "{\"a\":{\"c\":3,\"d\":2},\"b\":1}",
+        );
+        test(
+            "{\"é\": 1, \"z\": 2, \"Z\": 3}",
+            "{\"Z\":3,\"z\":2,\"é\":1}",
+        );
+    }
+
+    #[test]
+    fn numbers() {
+        fn test(input: &str, expected: &str) {
+            assert_eq!(expected, Value::parse(input).unwrap().to_string());
+        }
+        test("0", "0");
+        test("-0", "0");
+        test("-0.0e5", "0");
+        test("1", "1");
+        test("1.0", "1");
+        test("1e0", "1");
+        test("10E-1", "1");
+        test("-1.5", "-1.5");
+        test("123.456", "123.456");
+        test("0.1", "0.1");
+        test("0.000001", "0.000001");
+        test("0.0000001", "1e-7");
+        test("1.5e-7", "1.5e-7");
+        test("100", "100");
+        test("1e21", "1e+21");
+        test("1e20", "100000000000000000000");
+        test("2.5e25", "2.5e+25");
+        test("-1.5e-10", "-1.5e-10");
+        test("1E100", "1e+100");
+        test("9007199254740993", "9007199254740992");
+        test("5e-324", "5e-324");
+        test("1.7976931348623157e308", "1.7976931348623157e+308");
+    }
+
+    #[test]
+    fn invalid() {
+        fn test(input: &str, expected: Error) {
+            assert_eq!(Err(expected), Value::parse(input), "input: {}", input);
+        }
+        test("", Error::UnexpectedEnd);
+        test("   ", Error::UnexpectedEnd);
+        test("nul", Error::UnexpectedEnd);
+        test("nulx", Error::UnexpectedChar(3));
+        test("True", Error::UnexpectedChar(0));
+        test("1 2", Error::UnexpectedChar(2));
+        test("[1,]", Error::UnexpectedChar(3));
+        test("[1 2]", Error::UnexpectedChar(3));
+        test("{\"a\":1,}", Error::UnexpectedChar(7));
+        test("{a:1}", Error::UnexpectedChar(1));
+        test("{\"a\" 1}", Error::UnexpectedChar(5));
+        test("{\"a\":1,\"a\":2}", Error::DuplicateKey("a".into()));
+        test("01", Error::UnexpectedChar(1));
+        test("-", Error::UnexpectedEnd);
+        test("-a", Error::InvalidNumber(0));
+        test("1.", Error::InvalidNumber(0));
+        test("1.e1", Error::InvalidNumber(0));
+        test("1e", Error::InvalidNumber(0));
+        test("+1", Error::UnexpectedChar(0));
+        test(".5", Error::UnexpectedChar(0));
+        test("1e400", Error::InvalidNumber(0));
+        test("NaN", Error::UnexpectedChar(0));
+        test("\"abc", Error::UnexpectedEnd);
+        test("\"a\nb\"", Error::UnexpectedChar(2));
+        test("\"\\x\"", Error::InvalidEscape(1));
+        test("\"\\u12\"", Error::InvalidEscape(1));
+        test("\"\\ud83d\"", Error::InvalidUnicode(1));
+        test("\"\\ud83d\\u0041\"", Error::InvalidUnicode(1));
+        test("\"\\ude00\"", Error::InvalidUnicode(1));
+        test(&"[".repeat(MAX_DEPTH + 2), Error::TooDeep);
+    }
+
+    #[test]
+    fn hash_stable() {
+        let a = canonicalize("{\"b\": [1.0, 2e0], \"a\": \"x\"}").unwrap();
+        let b = canonicalize("{\"a\":\"x\",\"b\":[1,2]}").unwrap();
+        assert_eq!(a, b);
+        assert_eq!(Hash::of(&a).to_string(), Hash::of(&b).to_string());
+    }
+}
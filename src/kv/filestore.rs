@@ -0,0 +1,297 @@
+//! A Store kept in a file, for native tools.
+//!
+//! The whole store is held in memory, like a MemStore, and the file is
+//! rewritten with every commit: fine for inspecting and testing
+//! databases, not for large ones. The file is, with integers
+//! little-endian:
+//!
+//! ```text
+//! magic    "repckv\0\0"
+//! entries  u32 count, then per entry:
+//!            key    u32 length, utf-8 bytes
+//!            value  u32 length, bytes
+//! ```
+use crate::kv::tracker::{Guard, Tracker};
+use crate::kv::{
+    get_many_pending, scan_map, scan_pending, Read, Result, ScanOptions, Store, StoreError,
+    Visitor, Write,
+};
+use async_std::sync::Mutex;
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8] = b"repckv\0\0";
+
+pub struct FileStore {
+    path: PathBuf,
+    map: Mutex<BTreeMap<String, Vec<u8>>>,
+    tracker: Tracker,
+}
+
+impl FileStore {
+    /// Opens the store in the file at path, which is created with the
+    /// first commit if it does not exist.
+    pub fn open(path: &Path) -> Result<FileStore> {
+        let map = match fs::read(path) {
+            Ok(bytes) => match decode(&bytes) {
+                Some(v) => v,
+                None => {
+                    return Err(StoreError::Str(format!(
+                        "{}: not a file store",
+                        path.display()
+                    )))
+                }
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(io_error(path, e)),
+        };
+        Ok(FileStore {
+            path: path.into(),
+            map: Mutex::new(map),
+            tracker: Tracker::default(),
+        })
+    }
+
+    /// Whether bytes look like the contents of a file store.
+    pub fn is_file_store(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    // Writes map to a temporary file and renames it over path, so that
+    // the file is never partly written.
+    fn save(&self, map: &BTreeMap<String, Vec<u8>>) -> Result<()> {
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&(map.len() as u32).to_le_bytes());
+        for (key, value) in map.iter() {
+            for bytes in [key.as_bytes(), value].iter() {
+                buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+                buf.extend_from_slice(bytes);
+            }
+        }
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, &buf).map_err(|e| io_error(Path::new(&tmp), e))?;
+        fs::rename(&tmp, &self.path).map_err(|e| io_error(&self.path, e))
+    }
+}
+
+#[async_trait(?Send)]
+impl Store for FileStore {
+    async fn read<'a>(&'a self) -> Result<Box<dyn Read + 'a>> {
+        Ok(Box::new(ReadTransaction::new(self, "read")?))
+    }
+
+    async fn write<'a>(&'a self) -> Result<Box<dyn Write + 'a>> {
+        Ok(Box::new(WriteTransaction::new(self)?))
+    }
+
+    async fn close(&self) -> Result<()> {
+        self.tracker.close().await
+    }
+}
+
+struct ReadTransaction<'a> {
+    store: &'a FileStore,
+    _guard: Guard<'a>,
+}
+
+impl ReadTransaction<'_> {
+    // kind is "read" or "write", see Tracker::begin().
+    fn new<'a>(store: &'a FileStore, kind: &'static str) -> Result<ReadTransaction<'a>> {
+        Ok(ReadTransaction {
+            store,
+            _guard: store.tracker.begin(kind)?,
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl Read for ReadTransaction<'_> {
+    async fn has(&self, key: &str) -> Result<bool> {
+        Ok(self.store.map.lock().await.contains_key(key))
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.store.map.lock().await.get(key).cloned())
+    }
+
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<Vec<u8>>>> {
+        let map = self.store.map.lock().await;
+        Ok(keys.iter().map(|k| map.get(*k).cloned()).collect())
+    }
+
+    async fn scan(&self, opts: &ScanOptions<'_>, visit: &mut Visitor<'_>) -> Result<()> {
+        scan_map(&*self.store.map.lock().await, opts, visit);
+        Ok(())
+    }
+}
+
+struct WriteTransaction<'a> {
+    rt: ReadTransaction<'a>,
+    pending: Mutex<BTreeMap<String, Option<Vec<u8>>>>,
+}
+
+impl WriteTransaction<'_> {
+    fn new(store: &FileStore) -> Result<WriteTransaction<'_>> {
+        Ok(WriteTransaction {
+            rt: ReadTransaction::new(store, "write")?,
+            pending: Mutex::new(BTreeMap::new()),
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl Read for WriteTransaction<'_> {
+    async fn has(&self, key: &str) -> Result<bool> {
+        match self.pending.lock().await.get(key) {
+            Some(v) => Ok(v.is_some()),
+            None => self.rt.has(key).await,
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.pending.lock().await.get(key) {
+            Some(v) => Ok(v.clone()),
+            None => self.rt.get(key).await,
+        }
+    }
+
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<Vec<u8>>>> {
+        get_many_pending(&self.rt, &*self.pending.lock().await, keys).await
+    }
+
+    async fn scan(&self, opts: &ScanOptions<'_>, visit: &mut Visitor<'_>) -> Result<()> {
+        scan_pending(&self.rt, &*self.pending.lock().await, opts, visit).await
+    }
+}
+
+#[async_trait(?Send)]
+impl Write for WriteTransaction<'_> {
+    fn as_read(&self) -> &dyn Read {
+        self
+    }
+
+    async fn put(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.pending
+            .lock()
+            .await
+            .insert(key.into(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    async fn del(&self, key: &str) -> Result<()> {
+        self.pending.lock().await.insert(key.into(), None);
+        Ok(())
+    }
+
+    // Saves the file before updating the map, so that a commit that
+    // fails to save leaves the store as it was.
+    async fn commit(self: Box<Self>) -> Result<()> {
+        let pending = self.pending.lock().await;
+        let mut map = self.rt.store.map.lock().await;
+        let mut updated = map.clone();
+        for (key, value) in pending.iter() {
+            match value {
+                Some(v) => updated.insert(key.clone(), v.clone()),
+                None => updated.remove(key),
+            };
+        }
+        self.rt.store.save(&updated)?;
+        *map = updated;
+        Ok(())
+    }
+
+    async fn rollback(self: Box<Self>) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn io_error(path: &Path, e: std::io::Error) -> StoreError {
+    StoreError::Str(format!("{}: {}", path.display(), e))
+}
+
+fn decode(bytes: &[u8]) -> Option<BTreeMap<String, Vec<u8>>> {
+    fn take<'a>(bytes: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+        if bytes.len() < n {
+            return None;
+        }
+        let (v, rest) = bytes.split_at(n);
+        *bytes = rest;
+        Some(v)
+    }
+    fn take_sized<'a>(bytes: &mut &'a [u8]) -> Option<&'a [u8]> {
+        let n = u32::from_le_bytes(take(bytes, 4)?.try_into().ok()?);
+        take(bytes, n as usize)
+    }
+
+    let mut bytes = bytes;
+    if take(&mut bytes, MAGIC.len())? != MAGIC {
+        return None;
+    }
+    let count = u32::from_le_bytes(take(&mut bytes, 4)?.try_into().ok()?);
+    let mut map = BTreeMap::new();
+    for _ in 0..count {
+        let key = String::from_utf8(take_sized(&mut bytes)?.to_vec()).ok()?;
+        let value = take_sized(&mut bytes)?.to_vec();
+        map.insert(key, value);
+    }
+    if !bytes.is_empty() {
+        return None;
+    }
+    Some(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("repc-filestore-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[async_std::test]
+    async fn persists() -> std::result::Result<(), StoreError> {
+        let path = temp_path("persists");
+        let mut store = FileStore::open(&path)?;
+        assert!(!path.exists());
+        store.put("a", b"1").await?;
+        store.put("b", b"").await?;
+        let wt = store.write().await?;
+        wt.put("c", b"3").await?;
+        wt.del("a").await?;
+        assert_eq!(Some(b"3".to_vec()), wt.get("c").await?);
+        wt.commit().await?;
+        let wt = store.write().await?;
+        wt.put("d", b"4").await?;
+        wt.rollback().await?;
+        store.close().await?;
+        assert!(FileStore::is_file_store(&fs::read(&path).unwrap()));
+
+        let store = FileStore::open(&path)?;
+        let rt = store.read().await?;
+        assert_eq!(
+            vec![None, Some(vec![]), Some(b"3".to_vec()), None],
+            rt.get_many(&["a", "b", "c", "d"]).await?
+        );
+        drop(rt);
+        fs::remove_file(&path).unwrap();
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn invalid_file() {
+        let path = temp_path("invalid");
+        fs::write(&path, b"repckv\0\0\x01\0\0\0").unwrap();
+        assert!(FileStore::open(&path).is_err());
+        fs::write(&path, b"nope").unwrap();
+        assert!(FileStore::open(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
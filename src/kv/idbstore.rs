@@ -1,14 +1,18 @@
-use crate::kv::{Read, Result, Store, StoreError, Write};
+use crate::kv::tracker::{Guard, Tracker};
+use crate::kv::{
+    get_many_pending, scan_pending, Read, Result, ScanOptions, Store, StoreError, Visitor, Write,
+};
 use async_std::sync::{Arc, Condvar, Mutex};
 use async_std::task;
 use async_trait::async_trait;
-use futures::channel::oneshot;
+use futures::channel::{mpsc, oneshot};
 use futures::future::join_all;
+use futures::StreamExt;
 use log::warn;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use wasm_bindgen::closure::Closure;
 use wasm_bindgen::{JsCast, JsValue};
-use web_sys::{IdbDatabase, IdbObjectStore, IdbTransaction};
+use web_sys::{IdbCursorWithValue, IdbDatabase, IdbKeyRange, IdbObjectStore, IdbTransaction};
 
 impl From<String> for StoreError {
     fn from(err: String) -> StoreError {
@@ -31,17 +35,22 @@ impl From<futures::channel::oneshot::Canceled> for StoreError {
 
 pub struct IdbStore {
     db: IdbDatabase,
+    _onversionchange: Closure<dyn FnMut()>,
+    tracker: Tracker,
 }
 
 const OBJECT_STORE: &str = "chunks";
 
+#[derive(Clone, Copy)]
+enum DeleteEvent {
+    Success,
+    Error,
+    Blocked,
+}
+
 impl IdbStore {
     pub async fn new(name: &str) -> Result<Option<IdbStore>> {
-        let window = match web_sys::window() {
-            Some(w) => w,
-            None => return Ok(None),
-        };
-        let factory = match window.indexed_db()? {
+        let factory = match IdbStore::factory()? {
             Some(f) => f,
             None => return Ok(None),
         };
@@ -66,11 +75,73 @@ impl IdbStore {
         request.set_onerror(Some(callback.as_ref().unchecked_ref()));
         request.set_onupgradeneeded(Some(onupgradeneeded.as_ref().unchecked_ref()));
         receiver.await?;
+        let db: IdbDatabase = request.result()?.into();
+        // Closes the connection when another wants to delete (or
+        // upgrade) the database, rather than blocking it. Later
+        // transactions fail.
+        let db_copy = db.clone();
+        let onversionchange = Closure::wrap(Box::new(move || {
+            warn!("Closing database for a version change");
+            db_copy.close();
+        }) as Box<dyn FnMut()>);
+        db.set_onversionchange(Some(onversionchange.as_ref().unchecked_ref()));
         Ok(Some(IdbStore {
-            db: request.result()?.into(),
+            db,
+            _onversionchange: onversionchange,
+            tracker: Tracker::default(),
         }))
     }
 
+    /// Deletes the database name, if it exists. Connections to it made by
+    /// new() close first, but a connection that does not close blocks the
+    /// delete, and delete returns false. The database is still deleted
+    /// once that connection closes.
+    pub async fn delete(name: &str) -> Result<bool> {
+        let factory = match IdbStore::factory()? {
+            Some(f) => f,
+            None => return Err(StoreError::Str("IndexedDB is not available".into())),
+        };
+        let request = factory.delete_database(name)?;
+        let (sender, mut receiver) = mpsc::unbounded::<DeleteEvent>();
+        let callback = |event: DeleteEvent| {
+            let sender = sender.clone();
+            Closure::wrap(Box::new(move || {
+                if sender.unbounded_send(event).is_err() {
+                    warn!("delete send failed");
+                }
+            }) as Box<dyn FnMut()>)
+        };
+        let (onsuccess, onerror, onblocked) = (
+            callback(DeleteEvent::Success),
+            callback(DeleteEvent::Error),
+            callback(DeleteEvent::Blocked),
+        );
+        request.set_onsuccess(Some(onsuccess.as_ref().unchecked_ref()));
+        request.set_onerror(Some(onerror.as_ref().unchecked_ref()));
+        request.set_onblocked(Some(onblocked.as_ref().unchecked_ref()));
+        let event = receiver.next().await;
+        // The callbacks are dropped on return, and must not be called
+        // after, should the delete go on once unblocked.
+        request.set_onsuccess(None);
+        request.set_onerror(None);
+        request.set_onblocked(None);
+        match event {
+            Some(DeleteEvent::Success) => Ok(true),
+            Some(DeleteEvent::Blocked) => Ok(false),
+            _ => match request.error()? {
+                Some(e) => Err(format!("{:?}", e).into()),
+                None => Err(StoreError::Str("Delete failed".into())),
+            },
+        }
+    }
+
+    fn factory() -> Result<Option<web_sys::IdbFactory>> {
+        match web_sys::window() {
+            Some(w) => Ok(w.indexed_db()?),
+            None => Ok(None),
+        }
+    }
+
     /// Returns a oneshot callback and a Receiver to await it being called.
     ///
     /// Intended for use with Idb request callbacks, and may be registered for
@@ -86,6 +157,14 @@ impl IdbStore {
     }
 }
 
+// Transactions borrow the store, so none are open by the time it drops.
+impl Drop for IdbStore {
+    fn drop(&mut self) {
+        self.db.set_onversionchange(None);
+        self.db.close();
+    }
+}
+
 #[async_trait(?Send)]
 impl Store for IdbStore {
     async fn read<'a>(&'a self) -> Result<Box<dyn Read + 'a>> {
@@ -95,25 +174,37 @@ impl Store for IdbStore {
     async fn write<'a>(&'a self) -> Result<Box<dyn Write + 'a>> {
         Ok(Box::new(WriteTransaction::new(self)?))
     }
+
+    // IDB closes the connection once its transactions end, but the ends
+    // of ours are their commits and rollbacks, which are awaited first.
+    async fn close(&self) -> Result<()> {
+        self.tracker.close().await?;
+        self.db.set_onversionchange(None);
+        self.db.close();
+        Ok(())
+    }
 }
 
-struct ReadTransaction {
+struct ReadTransaction<'a> {
     tx: IdbTransaction,
     store: IdbObjectStore,
+    _guard: Guard<'a>,
 }
 
-impl ReadTransaction {
-    fn new(store: &IdbStore) -> Result<ReadTransaction> {
+impl ReadTransaction<'_> {
+    fn new(store: &IdbStore) -> Result<ReadTransaction<'_>> {
+        let guard = store.tracker.begin("read")?;
         let tx = store.db.transaction_with_str(OBJECT_STORE)?;
         Ok(ReadTransaction {
             store: tx.object_store(OBJECT_STORE)?,
             tx,
+            _guard: guard,
         })
     }
 }
 
 #[async_trait(?Send)]
-impl Read for ReadTransaction {
+impl Read for ReadTransaction<'_> {
     async fn has(&self, key: &str) -> Result<bool> {
         let request = self.store.count_with_key(&key.into())?;
         let (callback, receiver) = IdbStore::oneshot_callback();
@@ -142,6 +233,72 @@ impl Read for ReadTransaction {
             v => Some(js_sys::Uint8Array::new(&v).to_vec()),
         })
     }
+
+    // All the requests are made before awaiting any, so that IDB can
+    // serve them in parallel.
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut requests = Vec::with_capacity(keys.len());
+        let mut callbacks = Vec::with_capacity(keys.len());
+        let mut receivers = Vec::with_capacity(keys.len());
+        for key in keys {
+            let request = self.store.get(&(*key).into())?;
+            let (callback, receiver) = IdbStore::oneshot_callback();
+            request.set_onsuccess(Some(callback.as_ref().unchecked_ref()));
+            request.set_onerror(Some(callback.as_ref().unchecked_ref()));
+            requests.push(request);
+            callbacks.push(callback);
+            receivers.push(receiver);
+        }
+        for received in join_all(receivers).await {
+            received?;
+        }
+        let mut values = Vec::with_capacity(keys.len());
+        for request in requests {
+            values.push(match request.result()? {
+                v if v.is_undefined() => None,
+                v => Some(js_sys::Uint8Array::new(&v).to_vec()),
+            });
+        }
+        Ok(values)
+    }
+
+    // Note that IDB orders string keys by UTF-16 code unit, which only
+    // differs from Rust's str ordering for keys with characters above
+    // U+FFFF.
+    async fn scan(&self, opts: &ScanOptions<'_>, visit: &mut Visitor<'_>) -> Result<()> {
+        let (lower, exclusive) = opts.lower_bound();
+        let range = IdbKeyRange::lower_bound_with_open(&lower.into(), exclusive)?;
+        let request = self.store.open_cursor_with_range(&range)?;
+        // The request's callbacks fire once per step of the cursor.
+        let (sender, mut receiver) = mpsc::unbounded::<()>();
+        let callback = Closure::wrap(Box::new(move || {
+            if sender.unbounded_send(()).is_err() {
+                warn!("cursor send failed");
+            }
+        }) as Box<dyn FnMut()>);
+        request.set_onsuccess(Some(callback.as_ref().unchecked_ref()));
+        request.set_onerror(Some(callback.as_ref().unchecked_ref()));
+        loop {
+            receiver.next().await;
+            if let Some(e) = request.error()? {
+                return Err(format!("{:?}", e).into());
+            }
+            let result = request.result()?;
+            if result.is_null() || result.is_undefined() {
+                return Ok(());
+            }
+            let cursor = IdbCursorWithValue::unchecked_from_js(result);
+            let key = match cursor.key()?.as_string() {
+                Some(k) => k,
+                None => return Err(StoreError::Str("IdbStore cursor key not a string".into())),
+            };
+            let value = js_sys::Uint8Array::new(&cursor.value()?).to_vec();
+            if !key.starts_with(opts.prefix) || !visit(&key, &value) {
+                return Ok(());
+            }
+            cursor.continue_()?;
+        }
+    }
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -152,15 +309,16 @@ enum WriteState {
     Errored,
 }
 
-struct WriteTransaction {
-    rt: ReadTransaction,
-    pending: Mutex<HashMap<String, Option<Vec<u8>>>>,
+struct WriteTransaction<'a> {
+    rt: ReadTransaction<'a>,
+    pending: Mutex<BTreeMap<String, Option<Vec<u8>>>>,
     pair: Arc<(Mutex<WriteState>, Condvar)>,
     callbacks: Vec<Closure<dyn FnMut()>>,
 }
 
-impl WriteTransaction {
-    fn new(store: &IdbStore) -> Result<WriteTransaction> {
+impl WriteTransaction<'_> {
+    fn new(store: &IdbStore) -> Result<WriteTransaction<'_>> {
+        let guard = store.tracker.begin("write")?;
         let tx = store
             .db
             .transaction_with_str_and_mode(OBJECT_STORE, web_sys::IdbTransactionMode::Readwrite)?;
@@ -168,9 +326,10 @@ impl WriteTransaction {
             rt: ReadTransaction {
                 store: tx.object_store(OBJECT_STORE)?,
                 tx,
+                _guard: guard,
             },
             pair: Arc::new((Mutex::new(WriteState::Open), Condvar::new())),
-            pending: Mutex::new(HashMap::new()),
+            pending: Mutex::new(BTreeMap::new()),
             callbacks: Vec::with_capacity(3),
         };
 
@@ -204,7 +363,7 @@ impl WriteTransaction {
 }
 
 #[async_trait(?Send)]
-impl Read for WriteTransaction {
+impl Read for WriteTransaction<'_> {
     async fn has(&self, key: &str) -> Result<bool> {
         match self.pending.lock().await.get(key) {
             Some(Some(_)) => Ok(true),
@@ -220,10 +379,18 @@ impl Read for WriteTransaction {
             None => self.rt.get(key).await,
         }
     }
+
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<Vec<u8>>>> {
+        get_many_pending(&self.rt, &*self.pending.lock().await, keys).await
+    }
+
+    async fn scan(&self, opts: &ScanOptions<'_>, visit: &mut Visitor<'_>) -> Result<()> {
+        scan_pending(&self.rt, &*self.pending.lock().await, opts, visit).await
+    }
 }
 
 #[async_trait(?Send)]
-impl Write for WriteTransaction {
+impl Write for WriteTransaction<'_> {
     fn as_read(&self) -> &dyn Read {
         self
     }
@@ -1,16 +1,21 @@
-use crate::kv::{Read, Result, Store, Write};
+use crate::kv::tracker::{Guard, Tracker};
+use crate::kv::{
+    get_many_pending, scan_map, scan_pending, Read, Result, ScanOptions, Store, Visitor, Write,
+};
 use async_std::sync::Mutex;
 use async_trait::async_trait;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
 pub struct MemStore {
-    map: Mutex<HashMap<String, Vec<u8>>>,
+    map: Mutex<BTreeMap<String, Vec<u8>>>,
+    tracker: Tracker,
 }
 
 impl MemStore {
     pub fn new() -> MemStore {
         MemStore {
-            map: Mutex::new(HashMap::new()),
+            map: Mutex::new(BTreeMap::new()),
+            tracker: Tracker::default(),
         }
     }
 }
@@ -24,21 +29,30 @@ impl Default for MemStore {
 #[async_trait(?Send)]
 impl Store for MemStore {
     async fn read<'a>(&'a self) -> Result<Box<dyn Read + 'a>> {
-        Ok(Box::new(ReadTransaction::new(self)))
+        Ok(Box::new(ReadTransaction::new(self, "read")?))
     }
 
     async fn write<'a>(&'a self) -> Result<Box<dyn Write + 'a>> {
-        Ok(Box::new(WriteTransaction::new(self)))
+        Ok(Box::new(WriteTransaction::new(self)?))
+    }
+
+    async fn close(&self) -> Result<()> {
+        self.tracker.close().await
     }
 }
 
 struct ReadTransaction<'a> {
     store: &'a MemStore,
+    _guard: Guard<'a>,
 }
 
 impl ReadTransaction<'_> {
-    fn new(store: &MemStore) -> ReadTransaction {
-        ReadTransaction { store }
+    // kind is "read" or "write", see Tracker::begin().
+    fn new<'a>(store: &'a MemStore, kind: &'static str) -> Result<ReadTransaction<'a>> {
+        Ok(ReadTransaction {
+            store,
+            _guard: store.tracker.begin(kind)?,
+        })
     }
 }
 
@@ -54,19 +68,29 @@ impl Read for ReadTransaction<'_> {
             Some(v) => Ok(Some(v.to_vec())),
         }
     }
+
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<Vec<u8>>>> {
+        let map = self.store.map.lock().await;
+        Ok(keys.iter().map(|k| map.get(*k).cloned()).collect())
+    }
+
+    async fn scan(&self, opts: &ScanOptions<'_>, visit: &mut Visitor<'_>) -> Result<()> {
+        scan_map(&*self.store.map.lock().await, opts, visit);
+        Ok(())
+    }
 }
 
 struct WriteTransaction<'a> {
     rt: ReadTransaction<'a>,
-    pending: Mutex<HashMap<String, Option<Vec<u8>>>>,
+    pending: Mutex<BTreeMap<String, Option<Vec<u8>>>>,
 }
 
 impl WriteTransaction<'_> {
-    fn new(store: &MemStore) -> WriteTransaction {
-        WriteTransaction {
-            rt: ReadTransaction { store },
-            pending: Mutex::new(HashMap::new()),
-        }
+    fn new(store: &MemStore) -> Result<WriteTransaction> {
+        Ok(WriteTransaction {
+            rt: ReadTransaction::new(store, "write")?,
+            pending: Mutex::new(BTreeMap::new()),
+        })
     }
 }
 
@@ -87,6 +111,14 @@ impl Read for WriteTransaction<'_> {
             None => self.rt.get(key).await,
         }
     }
+
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<Vec<u8>>>> {
+        get_many_pending(&self.rt, &*self.pending.lock().await, keys).await
+    }
+
+    async fn scan(&self, opts: &ScanOptions<'_>, visit: &mut Visitor<'_>) -> Result<()> {
+        scan_pending(&self.rt, &*self.pending.lock().await, opts, visit).await
+    }
 }
 
 #[async_trait(?Send)]
@@ -180,4 +212,143 @@ mod tests {
 
         Ok(())
     }
+
+    #[async_std::test]
+    async fn get_many() -> std::result::Result<(), StoreError> {
+        let mut ms = MemStore::new();
+        ms.put("a", b"a").await?;
+        ms.put("b", b"b").await?;
+
+        let rt = ms.read().await?;
+        assert_eq!(
+            vec![
+                Some(b"b".to_vec()),
+                None,
+                Some(b"a".to_vec()),
+                Some(b"b".to_vec())
+            ],
+            rt.get_many(&["b", "c", "a", "b"]).await?
+        );
+        assert!(rt.get_many(&[]).await?.is_empty());
+
+        let wt = ms.write().await?;
+        wt.put("c", b"c").await?;
+        wt.del("a").await?;
+        assert_eq!(
+            vec![None, Some(b"b".to_vec()), Some(b"c".to_vec()), None],
+            wt.get_many(&["a", "b", "c", "d"]).await?
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn close() -> std::result::Result<(), StoreError> {
+        let ms = MemStore::new();
+        let rt = ms.read().await?;
+        let wt = ms.write().await?;
+        wt.put("a", b"a").await?;
+
+        // Close waits for both transactions to end.
+        let closed = std::cell::Cell::new(false);
+        let close = async {
+            ms.close().await.unwrap();
+            closed.set(true);
+        };
+        let end = async {
+            async_std::task::yield_now().await;
+            assert!(!closed.get());
+            drop(rt);
+            async_std::task::yield_now().await;
+            assert!(!closed.get());
+            wt.commit().await.unwrap();
+        };
+        futures::join!(close, end);
+        assert!(closed.get());
+
+        assert!(matches!(ms.read().await, Err(StoreError::Closed)));
+        assert!(matches!(ms.write().await, Err(StoreError::Closed)));
+        assert_eq!("closed", ms.has("a").await.unwrap_err().to_string());
+        // Closing again is fine.
+        ms.close().await?;
+        Ok(())
+    }
+
+    async fn scan_keys(r: &dyn Read, opts: ScanOptions<'_>, limit: usize) -> Vec<String> {
+        let mut keys = Vec::new();
+        r.scan(&opts, &mut |k, v| {
+            assert_eq!(k.as_bytes(), v);
+            keys.push(k.to_string());
+            keys.len() < limit
+        })
+        .await
+        .unwrap();
+        keys
+    }
+
+    #[async_std::test]
+    async fn scan() -> std::result::Result<(), StoreError> {
+        let mut ms = MemStore::new();
+        for k in &["a", "b", "ba", "bb", "c"] {
+            ms.put(k, k.as_bytes()).await?;
+        }
+
+        async fn test(
+            r: &dyn Read,
+            prefix: &str,
+            start_key: Option<&str>,
+            start_exclusive: bool,
+            limit: usize,
+            expected: &[&str],
+        ) {
+            let opts = ScanOptions {
+                prefix,
+                start_key,
+                start_exclusive,
+            };
+            assert_eq!(expected, scan_keys(r, opts, limit).await.as_slice());
+        }
+
+        let rt = ms.read().await?;
+        test(&*rt, "", None, false, 100, &["a", "b", "ba", "bb", "c"]).await;
+        test(&*rt, "", None, false, 2, &["a", "b"]).await;
+        test(&*rt, "b", None, false, 100, &["b", "ba", "bb"]).await;
+        test(&*rt, "ba", None, false, 100, &["ba"]).await;
+        test(&*rt, "d", None, false, 100, &[]).await;
+        test(&*rt, "", Some("b"), false, 100, &["b", "ba", "bb", "c"]).await;
+        test(&*rt, "", Some("b"), true, 100, &["ba", "bb", "c"]).await;
+        test(&*rt, "", Some("bab"), true, 100, &["bb", "c"]).await;
+        test(&*rt, "b", Some("a"), true, 100, &["b", "ba", "bb"]).await;
+        test(&*rt, "b", Some("b"), true, 100, &["ba", "bb"]).await;
+        test(&*rt, "b", Some("c"), false, 100, &[]).await;
+
+        // Pending writes are merged into scans inside a write transaction.
+        let wt = ms.write().await?;
+        wt.put("0", b"0").await?;
+        wt.put("b", b"b").await?;
+        wt.put("bab", b"bab").await?;
+        wt.del("bb").await?;
+        wt.put("d", b"d").await?;
+        let w: &dyn Read = wt.as_read();
+        test(
+            w,
+            "",
+            None,
+            false,
+            100,
+            &["0", "a", "b", "ba", "bab", "c", "d"],
+        )
+        .await;
+        test(w, "", None, false, 3, &["0", "a", "b"]).await;
+        test(w, "b", None, false, 100, &["b", "ba", "bab"]).await;
+        test(w, "", Some("b"), true, 100, &["ba", "bab", "c", "d"]).await;
+        test(w, "", Some("bab"), true, 2, &["c", "d"]).await;
+        test(w, "", Some("c"), false, 100, &["c", "d"]).await;
+        test(w, "d", None, false, 100, &["d"]).await;
+        wt.commit().await?;
+
+        let rt = ms.read().await?;
+        test(&*rt, "b", None, false, 100, &["b", "ba", "bab"]).await;
+
+        Ok(())
+    }
 }
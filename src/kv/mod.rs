@@ -1,18 +1,26 @@
+#[cfg(not(target_arch = "wasm32"))]
+pub mod filestore;
 pub mod idbstore;
 pub mod memstore;
+mod tracker;
 
 use async_trait::async_trait;
+use std::collections::BTreeMap;
 use std::fmt;
+use std::ops::Bound;
 
 #[derive(Debug)]
 pub enum StoreError {
     Str(String),
+    // The store was closed, see Store::close().
+    Closed,
 }
 
 impl fmt::Display for StoreError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             StoreError::Str(s) => write!(f, "{}", s),
+            StoreError::Closed => write!(f, "closed"),
         }
     }
 }
@@ -24,6 +32,10 @@ pub trait Store {
     async fn read<'a>(&'a self) -> Result<Box<dyn Read + 'a>>;
     async fn write<'a>(&'a self) -> Result<Box<dyn Write + 'a>>;
 
+    /// Waits for open transactions to end, then closes the store. Later
+    /// transactions fail with StoreError::Closed.
+    async fn close(&self) -> Result<()>;
+
     async fn put(&mut self, key: &str, value: &[u8]) -> Result<()> {
         let wt = self.write().await?;
         wt.put(key, value).await?;
@@ -43,6 +55,153 @@ pub trait Store {
 pub trait Read {
     async fn has(&self, key: &str) -> Result<bool>;
     async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Gets the values of keys, in the order of keys. Unlike awaiting
+    /// get for each key, the gets are issued together.
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<Vec<u8>>>>;
+
+    /// Visits entries matching opts in key order, until visit returns
+    /// false or there are no more entries. Entries are streamed from the
+    /// underlying storage rather than collected up front.
+    async fn scan(&self, opts: &ScanOptions<'_>, visit: &mut Visitor<'_>) -> Result<()>;
+}
+
+// A borrowed Read, such as a Write's as_read(), may be used where an
+// owned one is expected, by a dag::Read say.
+#[async_trait(?Send)]
+impl Read for &dyn Read {
+    async fn has(&self, key: &str) -> Result<bool> {
+        (**self).has(key).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        (**self).get(key).await
+    }
+
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<Vec<u8>>>> {
+        (**self).get_many(keys).await
+    }
+
+    async fn scan(&self, opts: &ScanOptions<'_>, visit: &mut Visitor<'_>) -> Result<()> {
+        (**self).scan(opts, visit).await
+    }
+}
+
+/// Called by Read::scan with each key and value. Returning false ends
+/// the scan.
+pub type Visitor<'a> = dyn FnMut(&str, &[u8]) -> bool + 'a;
+
+#[derive(Debug, Default)]
+pub struct ScanOptions<'a> {
+    /// Only keys starting with prefix are visited.
+    pub prefix: &'a str,
+    /// If set, keys sorting before start_key are skipped.
+    pub start_key: Option<&'a str>,
+    /// If true, start_key itself is skipped too.
+    pub start_exclusive: bool,
+}
+
+impl ScanOptions<'_> {
+    /// Returns the first key that may be visited and whether that key
+    /// itself is excluded.
+    pub fn lower_bound(&self) -> (&str, bool) {
+        match self.start_key {
+            Some(k) if k >= self.prefix => (k, self.start_exclusive),
+            _ => (self.prefix, false),
+        }
+    }
+
+    fn range(&self) -> (Bound<&str>, Bound<&str>) {
+        match self.lower_bound() {
+            (k, true) => (Bound::Excluded(k), Bound::Unbounded),
+            (k, false) => (Bound::Included(k), Bound::Unbounded),
+        }
+    }
+}
+
+/// Scans a map of keys to values in order, as the underlying store of a
+/// Read implementation.
+pub(crate) fn scan_map(
+    map: &BTreeMap<String, Vec<u8>>,
+    opts: &ScanOptions<'_>,
+    visit: &mut Visitor<'_>,
+) {
+    for (k, v) in map.range::<str, _>(opts.range()) {
+        if !k.starts_with(opts.prefix) || !visit(k, v) {
+            break;
+        }
+    }
+}
+
+/// Gets keys from rt with pending (uncommitted) puts and dels layered on
+/// top, as Write implementations need to. Only keys without pending
+/// writes are read from rt.
+pub(crate) async fn get_many_pending(
+    rt: &dyn Read,
+    pending: &BTreeMap<String, Option<Vec<u8>>>,
+    keys: &[&str],
+) -> Result<Vec<Option<Vec<u8>>>> {
+    let unwritten: Vec<&str> = keys
+        .iter()
+        .copied()
+        .filter(|k| !pending.contains_key(*k))
+        .collect();
+    let mut stored = rt.get_many(&unwritten).await?.into_iter();
+    let mut values = Vec::with_capacity(keys.len());
+    for k in keys {
+        values.push(match pending.get(*k) {
+            Some(v) => v.clone(),
+            None => stored.next().unwrap_or(None),
+        });
+    }
+    Ok(values)
+}
+
+/// Scans rt with pending (uncommitted) puts and dels layered on top, as
+/// Write implementations need to.
+pub(crate) async fn scan_pending(
+    rt: &dyn Read,
+    pending: &BTreeMap<String, Option<Vec<u8>>>,
+    opts: &ScanOptions<'_>,
+    visit: &mut Visitor<'_>,
+) -> Result<()> {
+    let mut pending = pending
+        .range::<str, _>(opts.range())
+        .take_while(|(k, _)| k.starts_with(opts.prefix))
+        .peekable();
+    let mut done = false;
+    rt.scan(opts, &mut |key, value| {
+        // Pending entries sorting before key come first. A pending entry
+        // for key itself replaces (or deletes) the stored one.
+        while let Some(&(k, v)) = pending.peek() {
+            if k.as_str() > key {
+                break;
+            }
+            pending.next();
+            if let Some(v) = v {
+                if !visit(k, v) {
+                    done = true;
+                    return false;
+                }
+            }
+            if k == key {
+                return true;
+            }
+        }
+        done = !visit(key, value);
+        !done
+    })
+    .await?;
+    if !done {
+        for (k, v) in pending {
+            if let Some(v) = v {
+                if !visit(k, v) {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
 }
 
 #[async_trait(?Send)]
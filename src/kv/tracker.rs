@@ -0,0 +1,76 @@
+use crate::kv::{Result, StoreError};
+use crate::trace::Span;
+use futures::channel::oneshot;
+use std::sync::Mutex;
+
+/// Tracks the transactions open on a store, so that closing the store can
+/// wait for them to end.
+#[derive(Default)]
+pub(crate) struct Tracker {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    open: usize,
+    closed: bool,
+    // Woken once closed and no transactions are open.
+    waiters: Vec<oneshot::Sender<()>>,
+}
+
+impl Tracker {
+    /// Registers a transaction of kind ("read" or "write"), which is open
+    /// until the returned guard drops. Fails once the store is closed.
+    pub fn begin(&self, kind: &'static str) -> Result<Guard<'_>> {
+        let mut state = self.lock()?;
+        if state.closed {
+            return Err(StoreError::Closed);
+        }
+        state.open += 1;
+        Ok(Guard {
+            tracker: self,
+            _span: Span::new(|| format!("kv {} transaction", kind)),
+        })
+    }
+
+    /// Marks the store closed, then waits for open transactions to end.
+    pub async fn close(&self) -> Result<()> {
+        let receiver = {
+            let mut state = self.lock()?;
+            state.closed = true;
+            if state.open == 0 {
+                return Ok(());
+            }
+            let (sender, receiver) = oneshot::channel();
+            state.waiters.push(sender);
+            receiver
+        };
+        receiver.await?;
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, State>> {
+        self.state
+            .lock()
+            .map_err(|e| StoreError::Str(e.to_string()))
+    }
+}
+
+pub(crate) struct Guard<'a> {
+    tracker: &'a Tracker,
+    // Times the transaction.
+    _span: Span,
+}
+
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.tracker.state.lock() {
+            state.open -= 1;
+            if state.open == 0 {
+                for waiter in state.waiters.drain(..) {
+                    let _ = waiter.send(());
+                }
+            }
+        }
+    }
+}
@@ -5,9 +5,10 @@ extern crate async_std;
 extern crate lazy_static;
 extern crate log;
 
-mod dag;
+pub mod dag;
 mod dispatch;
-mod hash;
+pub mod hash;
+mod json;
 
 #[cfg(not(default))]
 pub mod kv;
@@ -15,4 +16,6 @@ pub mod kv;
 #[cfg(default)]
 mod kv;
 
-mod prolly;
+pub mod prolly;
+mod sync;
+mod trace;
@@ -0,0 +1,376 @@
+//! Storage of large values as blobs of content-defined chunks.
+//!
+//! A blob is a root chunk whose refs are the blob's leaf chunks, in
+//! order. Leaf boundaries are picked by a Chunker from the content
+//! itself, so an edit to a large value only changes the leaves around
+//! the edit (and the root), and identical ranges of different values
+//! share leaves.
+//!
+//! Every chunk's hash is the hash of its data. The root's data is the
+//! concatenation of its refs' sums, so its hash covers the whole blob.
+use super::chunker::{Chunker, ChunkerConfig};
+use crate::dag::chunk::{Chunk, Info, Kind};
+use crate::dag::read::Source;
+use crate::dag::{write, Error};
+use crate::hash::{self, Hash};
+use crate::kv;
+use log::error;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Values smaller than this are stored inline rather than as blobs,
+/// since they would mostly be a single leaf anyway.
+pub const MIN_BLOB_SIZE: usize = 8 * 1024;
+
+// Stored values starting with this byte are blob refs. Values are
+// canonical JSON, which never starts with a zero byte.
+const BLOB_TAG: u8 = 0;
+
+/// Encodes value for storage under a key, writing it to kvw as a blob
+/// chunked per config if it is large. Returns the bytes to store under
+/// the key.
+pub async fn put_value(
+    kvw: &dyn kv::Write,
+    config: &ChunkerConfig,
+    value: &[u8],
+) -> Result<Vec<u8>> {
+    let (stored, chunks) = encode_value(config, value);
+    for c in chunks.iter() {
+        write::put_chunk(kvw, c).await?;
+    }
+    Ok(stored)
+}
+
+/// Like put_value, but returns the blob's chunks, if any, rather than
+/// writing them, so that a caller can do the chunking before it opens
+/// the transaction that writes them.
+pub fn encode_value(config: &ChunkerConfig, value: &[u8]) -> (Vec<u8>, Vec<Chunk>) {
+    if value.len() < MIN_BLOB_SIZE {
+        return (value.to_vec(), Vec::new());
+    }
+    let chunks = chunks(config, value);
+    // The root is the last chunk.
+    let root = chunks[chunks.len() - 1].hash();
+    let mut stored = Vec::with_capacity(1 + hash::BYTE_LENGTH);
+    stored.push(BLOB_TAG);
+    stored.extend_from_slice(&root.sum);
+    (stored, chunks)
+}
+
+/// Decodes bytes stored by put_value, reading the blob they refer to
+/// with read if needed.
+pub async fn get_value(read: &dyn Source, stored: Vec<u8>) -> Result<Vec<u8>> {
+    match blob_ref(&stored) {
+        None => Ok(stored),
+        Some(root) => match get(read, &root).await? {
+            Some(v) => Ok(v),
+            None => {
+                error!("Missing blob root: {}", root);
+                Err(Error::CorruptStore)
+            }
+        },
+    }
+}
+
+/// Decodes many values stored by put_value, like get_value but reading
+/// the blobs they refer to in two batches: roots, then leaves.
+pub async fn get_values(read: &dyn Source, stored: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+    let roots: Vec<Hash> = stored.iter().filter_map(|s| blob_ref(s)).collect();
+    if roots.is_empty() {
+        return Ok(stored);
+    }
+    let mut root_chunks = Vec::with_capacity(roots.len());
+    for (root, c) in roots.iter().zip(read.get_chunks(&roots).await?) {
+        match c {
+            Some(c) => root_chunks.push(c),
+            None => {
+                error!("Missing blob root: {}", root);
+                return Err(Error::CorruptStore);
+            }
+        }
+    }
+    let leaves: Vec<Hash> = root_chunks
+        .iter()
+        .flat_map(|c| c.refs().into_iter().flatten())
+        .collect();
+    let mut leaf_chunks = read.get_chunks(&leaves).await?.into_iter();
+
+    let mut root_chunks = root_chunks.into_iter();
+    let mut values = Vec::with_capacity(stored.len());
+    for s in stored {
+        if blob_ref(&s).is_none() {
+            values.push(s);
+            continue;
+        }
+        let root = root_chunks.next().ok_or(Error::CorruptStore)?;
+        let mut data = Vec::new();
+        for r in root.refs().into_iter().flatten() {
+            match leaf_chunks.next() {
+                Some(Some(leaf)) => data.extend_from_slice(leaf.data()),
+                _ => {
+                    error!("Missing blob leaf: {} of {}", r, root.hash());
+                    return Err(Error::CorruptStore);
+                }
+            }
+        }
+        values.push(data);
+    }
+    Ok(values)
+}
+
+/// Returns the root hash of the blob that stored refers to, if any.
+pub fn blob_ref(stored: &[u8]) -> Option<Hash> {
+    match stored.split_first() {
+        Some((&BLOB_TAG, sum)) if sum.len() == hash::BYTE_LENGTH => Hash::from_slice(sum),
+        _ => None,
+    }
+}
+
+/// Splits data into leaf chunks per config and writes them along with a
+/// root chunk referencing them. Returns the root hash.
+pub async fn put(kvw: &dyn kv::Write, config: &ChunkerConfig, data: &[u8]) -> Result<Hash> {
+    let chunks = chunks(config, data);
+    for c in chunks.iter() {
+        write::put_chunk(kvw, c).await?;
+    }
+    Ok(*chunks[chunks.len() - 1].hash())
+}
+
+// Returns the leaf chunks of data, followed by their root.
+fn chunks(config: &ChunkerConfig, data: &[u8]) -> Vec<Chunk> {
+    let mut chunks: Vec<Chunk> = split(&mut Chunker::new(config), data)
+        .into_iter()
+        .map(|leaf| Chunk::new(Hash::of(leaf), leaf.to_vec(), &[]))
+        .collect();
+    let refs: Vec<Hash> = chunks.iter().map(|c| *c.hash()).collect();
+    let sums: Vec<u8> = refs.iter().flat_map(|r| r.sum.iter().copied()).collect();
+    // Leaves are plain chunks, so that they need no meta.
+    let info = Info {
+        kind: Kind::Blob,
+        ..Default::default()
+    };
+    chunks.push(Chunk::with_info(Hash::of(&sums), sums, &refs, &info));
+    chunks
+}
+
+/// Reads the blob with the given root hash, or None if there is no such
+/// root chunk.
+pub async fn get(read: &dyn Source, root: &Hash) -> Result<Option<Vec<u8>>> {
+    let root = match read.get_chunk(root).await? {
+        Some(c) => c,
+        None => return Ok(None),
+    };
+    let mut data = Vec::new();
+    if let Some(refs) = root.refs() {
+        for r in refs {
+            match read.get_chunk(&r).await? {
+                Some(leaf) => data.extend_from_slice(leaf.data()),
+                None => {
+                    error!("Missing blob leaf: {} of {}", r, root.hash());
+                    return Err(Error::CorruptStore);
+                }
+            }
+        }
+    }
+    Ok(Some(data))
+}
+
+// Splits data at the boundaries chunker finds. Boundary bytes end the
+// chunk they are in.
+fn split<'a>(chunker: &mut Chunker, data: &'a [u8]) -> Vec<&'a [u8]> {
+    let mut leaves = Vec::new();
+    let mut start = 0;
+    for end in chunker.boundaries(data) {
+        leaves.push(&data[start..end]);
+        start = end;
+    }
+    if start < data.len() || leaves.is_empty() {
+        leaves.push(&data[start..]);
+    }
+    leaves
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dag::read::Read;
+    use crate::kv::memstore::MemStore;
+    use crate::kv::{ScanOptions, Store};
+
+    // Deterministic, incompressible-looking test data.
+    fn random_bytes(len: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed;
+        (0..len)
+            .map(|_| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (x >> 16) as u8
+            })
+            .collect()
+    }
+
+    async fn chunk_keys(store: &MemStore) -> Vec<String> {
+        let mut keys = Vec::new();
+        let opts = ScanOptions {
+            prefix: "c/",
+            ..Default::default()
+        };
+        let rt = store.read().await.unwrap();
+        rt.scan(&opts, &mut |k, _| {
+            keys.push(k.to_string());
+            true
+        })
+        .await
+        .unwrap();
+        keys
+    }
+
+    #[test]
+    fn split_boundaries() {
+        let data = random_bytes(100_000, 1);
+        let leaves = split(&mut Chunker::default(), &data);
+        assert!(leaves.len() > 1);
+        assert_eq!(data, leaves.concat());
+
+        assert_eq!(vec![&[] as &[u8]], split(&mut Chunker::default(), &[]));
+        assert_eq!(vec![&[1u8, 2][..]], split(&mut Chunker::default(), &[1, 2]));
+    }
+
+    #[async_std::test]
+    async fn round_trip() {
+        async fn test(len: usize, inline: bool) {
+            let store = MemStore::new();
+            let value = random_bytes(len, len as u32);
+            let wt = store.write().await.unwrap();
+            let stored = put_value(wt.as_ref(), &ChunkerConfig::default(), &value)
+                .await
+                .unwrap();
+            assert_eq!(inline, blob_ref(&stored).is_none());
+            assert_eq!(inline, stored == value);
+            let read = Read::new(Box::new(wt.as_read()));
+            assert_eq!(value, get_value(&read, stored.clone()).await.unwrap());
+            drop(read);
+            wt.commit().await.unwrap();
+
+            let rt = Read::new(store.read().await.unwrap());
+            if let Some(root) = blob_ref(&stored) {
+                let root = rt.get_chunk(&root).await.unwrap().unwrap();
+                assert_eq!(Kind::Blob, root.info().kind);
+            }
+            assert_eq!(value, get_value(&rt, stored).await.unwrap());
+            assert_eq!(inline, chunk_keys(&store).await.is_empty());
+        }
+
+        test(0, true).await;
+        test(10, true).await;
+        test(MIN_BLOB_SIZE - 1, true).await;
+        test(MIN_BLOB_SIZE, false).await;
+        test(1_000_000, false).await;
+    }
+
+    #[async_std::test]
+    async fn edit_rewrites_few_chunks() {
+        let store = MemStore::new();
+        let mut value = random_bytes(1_000_000, 7);
+        let wt = store.write().await.unwrap();
+        let r1 = put(wt.as_ref(), &ChunkerConfig::default(), &value)
+            .await
+            .unwrap();
+        wt.commit().await.unwrap();
+        let before = chunk_keys(&store).await.len();
+
+        value[500_000] ^= 1;
+        let wt = store.write().await.unwrap();
+        let r2 = put(wt.as_ref(), &ChunkerConfig::default(), &value)
+            .await
+            .unwrap();
+        wt.commit().await.unwrap();
+        assert_ne!(r1, r2);
+
+        // Only the leaf with the edit and the root are new, give or take
+        // a leaf where the edit moves a boundary.
+        let added = chunk_keys(&store).await.len() - before;
+        assert!(added <= 3, "{} of {} chunk keys added", added, before);
+
+        let rt = Read::new(store.read().await.unwrap());
+        assert_eq!(Some(value), get(&rt, &r2).await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn shared_ranges_dedupe() {
+        let store = MemStore::new();
+        let shared = random_bytes(200_000, 3);
+        let a = [&random_bytes(1000, 4)[..], &shared].concat();
+        let b = [&shared[..], &random_bytes(1000, 5)].concat();
+
+        let wt = store.write().await.unwrap();
+        let ra = put(wt.as_ref(), &ChunkerConfig::default(), &a)
+            .await
+            .unwrap();
+        wt.commit().await.unwrap();
+        let after_a = chunk_keys(&store).await.len();
+
+        let wt = store.write().await.unwrap();
+        let rb = put(wt.as_ref(), &ChunkerConfig::default(), &b)
+            .await
+            .unwrap();
+        wt.commit().await.unwrap();
+        let added = chunk_keys(&store).await.len() - after_a;
+        assert!(
+            added * 4 < after_a,
+            "{} chunk keys added to {}",
+            added,
+            after_a
+        );
+
+        let rt = Read::new(store.read().await.unwrap());
+        assert_eq!(Some(a), get(&rt, &ra).await.unwrap());
+        assert_eq!(Some(b), get(&rt, &rb).await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn get_many_values() {
+        let store = MemStore::new();
+        let wt = store.write().await.unwrap();
+        let mut values = Vec::new();
+        let mut stored = Vec::new();
+        for (i, len) in [10, 100_000, MIN_BLOB_SIZE, 0, 50_000].iter().enumerate() {
+            let value = random_bytes(*len, i as u32);
+            let s = put_value(wt.as_ref(), &ChunkerConfig::default(), &value)
+                .await
+                .unwrap();
+            values.push(value);
+            stored.push(s);
+        }
+        // The same blob twice.
+        values.push(values[1].clone());
+        stored.push(stored[1].clone());
+        wt.commit().await.unwrap();
+
+        let rt = Read::new(store.read().await.unwrap());
+        assert_eq!(values, get_values(&rt, stored.clone()).await.unwrap());
+        let inline = vec![b"a".to_vec(), vec![]];
+        assert_eq!(inline, get_values(&rt, inline.clone()).await.unwrap());
+
+        let mut missing = vec![BLOB_TAG];
+        missing.extend_from_slice(&Hash::of(b"nope").sum);
+        stored.push(missing);
+        assert!(matches!(
+            get_values(&rt, stored).await,
+            Err(Error::CorruptStore)
+        ));
+    }
+
+    #[async_std::test]
+    async fn missing_chunks() {
+        let store = MemStore::new();
+        let rt = Read::new(store.read().await.unwrap());
+        assert_eq!(None, get(&rt, &Hash::of(b"nope")).await.unwrap());
+        let mut stored = vec![BLOB_TAG];
+        stored.extend_from_slice(&Hash::of(b"nope").sum);
+        assert!(matches!(
+            get_value(&rt, stored).await,
+            Err(Error::CorruptStore)
+        ));
+    }
+}
@@ -1,7 +1,7 @@
 use std::io::{Result, Write};
 use std::vec::Vec;
 
-static BUZHASH_TABLE: &[u32] = &[
+static BUZHASH_TABLE: [u32; 256] = [
     0x12bd9527, 0xf4140cea, 0x987bd6e1, 0x79079850, 0xafbfd539, 0xd350ce0a, 0x82973931, 0x9fc32b9c,
     0x28003b88, 0xc30c13aa, 0x6b678c34, 0x5844ef1d, 0xaa552c18, 0x4a77d3e8, 0xd1f62ea0, 0x6599417c,
     0xfbe30e7a, 0xf9e2d5ee, 0xa1fca42e, 0x41548969, 0x116d5b59, 0xaeda1e1a, 0xc5191c17, 0x54b9a3cb,
@@ -40,7 +40,6 @@ pub struct BuzHash {
     state: u32,
     buf: Vec<u8>,
     bshiftn: u32,
-    bshiftm: u32,
     pos: u32,
     overflow: bool,
 }
@@ -52,24 +51,22 @@ impl BuzHash {
             state: 0,
             buf: vec![0; n as usize],
             bshiftn,
-            bshiftm: 32 - bshiftn,
             pos: 0,
             overflow: false,
         }
     }
 
     pub fn hash_byte(&mut self, b: u8) {
-        if self.pos == self.buf.capacity() as u32 {
+        if self.pos == self.buf.len() as u32 {
             self.overflow = true;
             self.pos = 0;
         }
 
-        let mut s = self.state;
-        s = (s.rotate_left(1)) | (s.rotate_right(31));
+        let mut s = self.state.rotate_left(1);
 
         if self.overflow {
             let toshift = BUZHASH_TABLE[self.buf[self.pos as usize] as usize];
-            s ^= (toshift.rotate_left(self.bshiftn)) | (toshift.rotate_right(self.bshiftm));
+            s ^= toshift.rotate_left(self.bshiftn);
         }
 
         self.buf[self.pos as usize] = b;
@@ -79,6 +76,65 @@ impl BuzHash {
         self.state = s;
     }
 
+    // Adds bytes to the hash.
+    pub fn hash_slice(&mut self, data: &[u8]) {
+        self.hash_until(data, None);
+    }
+
+    // Adds bytes to the hash until one leaves the low bits in pattern
+    // all set. Returns the index of that byte, which is the last one
+    // hashed, or None if all of data was hashed without a match.
+    pub fn find_match(&mut self, data: &[u8], pattern: u32) -> Option<usize> {
+        self.hash_until(data, Some(pattern))
+    }
+
+    // The loop behind all of the above, with state kept in locals so
+    // that slices hash in a tight loop.
+    #[inline(always)]
+    fn hash_until(&mut self, data: &[u8], pattern: Option<u32>) -> Option<usize> {
+        let n = self.buf.len();
+        let mut s = self.state;
+        let mut pos = self.pos as usize;
+        let mut overflow = self.overflow;
+        let mut i = 0;
+        let mut found = None;
+
+        'outer: while i < data.len() {
+            if pos == n {
+                overflow = true;
+                pos = 0;
+            }
+
+            // Hash up to the end of the window buffer or of data, so the
+            // inner loop needs no wrapping. Each byte rotated out of the
+            // window has been rotated n times since it was added.
+            let m = (n - pos).min(data.len() - i);
+            let window = self.buf[pos..pos + m].iter_mut();
+            for (j, (w, &b)) in window.zip(&data[i..i + m]).enumerate() {
+                s = s.rotate_left(1);
+                if overflow {
+                    s ^= BUZHASH_TABLE[*w as usize].rotate_left(self.bshiftn);
+                }
+                *w = b;
+                s ^= BUZHASH_TABLE[b as usize];
+                if let Some(p) = pattern {
+                    if s & p == p {
+                        pos += j + 1;
+                        found = Some(i + j);
+                        break 'outer;
+                    }
+                }
+            }
+            pos += m;
+            i += m;
+        }
+
+        self.state = s;
+        self.pos = pos as u32;
+        self.overflow = overflow;
+        found
+    }
+
     pub fn sum(&self) -> u32 {
         self.state
     }
@@ -92,9 +148,7 @@ impl BuzHash {
 
 impl Write for BuzHash {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        for b in buf {
-            self.hash_byte(*b);
-        }
+        self.hash_slice(buf);
         Ok(buf.len())
     }
 
@@ -373,5 +427,35 @@ pede sit amet augue. In turpis. Pellentesque posuere. Praesent turpis."#;
             h.hash_byte(*b);
             assert_eq!(EXPECTED[i], h.sum())
         }
+
+        // Hashing slices gives the same sums, however the input is split.
+        let data = LOREM_IPSUM_2.as_bytes();
+        for &step in &[1, 2, 7, 32, 33, 100, data.len()] {
+            let mut h = BuzHash::new(32);
+            let mut end = 0;
+            for piece in data.chunks(step) {
+                h.hash_slice(piece);
+                end += piece.len();
+                assert_eq!(EXPECTED[end - 1], h.sum(), "step {}", step);
+            }
+        }
+
+        // And find_match stops at the same bytes a per-byte check would.
+        for &pattern in &[0, 1, 0x3, 0xf, 0x3f, 0xff] {
+            let mut expected = EXPECTED
+                .iter()
+                .enumerate()
+                .filter(|(_, &sum)| sum & pattern == pattern)
+                .map(|(i, _)| i);
+            let mut h = BuzHash::new(32);
+            let mut start = 0;
+            while let Some(i) = h.find_match(&data[start..], pattern) {
+                assert_eq!(expected.next(), Some(start + i), "pattern {}", pattern);
+                assert_eq!(EXPECTED[start + i], h.sum());
+                start += i + 1;
+            }
+            assert_eq!(None, expected.next(), "pattern {}", pattern);
+            assert_eq!(EXPECTED[data.len() - 1], h.sum());
+        }
     }
 }
@@ -1,55 +1,266 @@
+#![allow(clippy::question_mark, clippy::redundant_pattern_matching)] // For derive(DeJson).
+
 use super::buzhash::BuzHash;
+use crate::dag::key::Key;
+use crate::kv::{Store, StoreError};
+use nanoserde::{DeJson, SerJson};
+
+/// Parameters of content-defined chunking. Chunk boundaries depend on
+/// them, so every reader and writer of a database must use the same
+/// config for chunks of equal content to dedupe. See init().
+#[derive(Clone, Debug, PartialEq, DeJson, SerJson)]
+pub struct ChunkerConfig {
+    /// Number of bytes in the rolling hash window.
+    pub window: u32,
+    /// A boundary is found when this many low bits of the rolling hash
+    /// are set, so chunks average about 2^target_bits bytes past
+    /// min_size.
+    pub target_bits: u32,
+    /// Chunks are never shorter than this, except the last one.
+    pub min_size: usize,
+    /// Chunks are never longer than this.
+    pub max_size: usize,
+}
+
+// The config used in production.
+impl Default for ChunkerConfig {
+    fn default() -> ChunkerConfig {
+        // The window and target are what Noms uses, but the min and max
+        // sizes are not: Noms has no min and a much larger max. So these
+        // boundaries differ from Noms' wherever Noms would make a chunk
+        // shorter than 1024 bytes (skipped here) or longer than 64K
+        // (split here), and only match its output elsewhere.
+        // TODO: It's likely we'd like bigger chunks, but we can
+        // profile that later.
+        ChunkerConfig {
+            window: 67,
+            target_bits: 12, // ~4kb chunks
+            min_size: 1024,
+            max_size: 64 * 1024,
+        }
+    }
+}
+
+impl ChunkerConfig {
+    // Special small chunk config for testing.
+    #[allow(dead_code)]
+    pub fn smol() -> ChunkerConfig {
+        ChunkerConfig {
+            window: 67,
+            target_bits: 8, // ~256b chunks
+            min_size: 64,
+            max_size: 4 * 1024,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.window == 0 {
+            return Err("window must be positive".into());
+        }
+        if self.target_bits == 0 || self.target_bits > 31 {
+            return Err(format!("target_bits {} not in 1..=31", self.target_bits));
+        }
+        if self.max_size == 0 || self.min_size > self.max_size {
+            return Err(format!(
+                "min_size {} and max_size {} must satisfy 0 < min_size <= max_size",
+                self.min_size, self.max_size
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the chunker config of the database in store, persisting the
+/// default config if the database does not have one yet.
+pub async fn init(store: &dyn Store) -> Result<ChunkerConfig, StoreError> {
+    let key = Key::ChunkerConfig.to_string();
+    let wt = store.write().await?;
+    if let Some(bytes) = wt.get(&key).await? {
+        wt.rollback().await?;
+        return parse_config(&bytes)
+            .map_err(|e| StoreError::Str(format!("Invalid chunker config: {}", e)));
+    }
+    let config = ChunkerConfig::default();
+    wt.put(&key, SerJson::serialize_json(&config).as_bytes())
+        .await?;
+    wt.commit().await?;
+    Ok(config)
+}
+
+fn parse_config(bytes: &[u8]) -> Result<ChunkerConfig, String> {
+    let s = std::str::from_utf8(bytes).map_err(|e| e.to_string())?;
+    let config: ChunkerConfig = DeJson::deserialize_json(s).map_err(|e| e.to_string())?;
+    config.validate()?;
+    Ok(config)
+}
 
 pub struct Chunker {
     bh: BuzHash,
     pattern: u32,
+    min_size: usize,
+    max_size: usize,
+    // Length of the current chunk, including the last byte hashed.
+    len: usize,
+}
+
+// The chunker used in production
+impl Default for Chunker {
+    fn default() -> Chunker {
+        Chunker::new(&ChunkerConfig::default())
+    }
 }
 
 impl Chunker {
-    fn new(window: u32, pattern: u32) -> Chunker {
+    // Panics if config is invalid.
+    pub fn new(config: &ChunkerConfig) -> Chunker {
+        if let Err(e) = config.validate() {
+            panic!("Invalid chunker config: {}", e);
+        }
         Chunker {
-            bh: BuzHash::new(window),
-            pattern,
+            bh: BuzHash::new(config.window),
+            pattern: (1 << config.target_bits) - 1,
+            min_size: config.min_size,
+            max_size: config.max_size,
+            len: 0,
         }
     }
 
-    // The chunker used in production
-    pub fn default() -> Chunker {
-        // This is the configuration Noms uses, which allows us
-        // to test against its output.
-        // TODO: It's likely we'd like bigger chunks, but we can
-        // profile that later.
-        Chunker::new(67, (1 << 12) - 1) // ~4kb chunks
-    }
-
     // Special small chunk chunker for testing
     #[allow(dead_code)]
     pub fn smol() -> Chunker {
-        Chunker::new(67, (1 << 8) - 1) // ~256b chunks
+        Chunker::new(&ChunkerConfig::smol())
     }
 
     // Adds a byte to the rolling hasher. Returns true if the byte
     // was a boundary, false otherwise.
     pub fn hash_byte(&mut self, b: u8) -> bool {
         self.bh.hash_byte(b);
-        if self.bh.sum() & self.pattern == self.pattern {
-            self.bh.reset();
+        self.len += 1;
+        if self.len >= self.max_size
+            || (self.len >= self.min_size && self.bh.sum() & self.pattern == self.pattern)
+        {
+            self.reset();
             return true;
         }
         false
     }
+
+    /// Adds bytes to the rolling hasher up to and including the first
+    /// boundary, and returns the number of bytes added. Returns None if
+    /// there is no boundary in data, in which case all of it was added
+    /// and the current chunk continues into the next call.
+    ///
+    /// Finds the same boundaries as calling hash_byte() on each byte,
+    /// but much faster.
+    pub fn find_boundary(&mut self, data: &[u8]) -> Option<usize> {
+        // Bytes that leave the chunk shorter than min_size can't end it,
+        // so just hash them.
+        let skip = (self.min_size.saturating_sub(self.len + 1)).min(data.len());
+        self.bh.hash_slice(&data[..skip]);
+        self.len += skip;
+
+        // The rest can, until the chunk reaches max_size.
+        let rest = &data[skip..];
+        let limit = (self.max_size - self.len).min(rest.len());
+        match self.bh.find_match(&rest[..limit], self.pattern) {
+            Some(i) => {
+                self.reset();
+                Some(skip + i + 1)
+            }
+            None => {
+                self.len += limit;
+                if self.len < self.max_size {
+                    return None;
+                }
+                self.reset();
+                Some(skip + limit)
+            }
+        }
+    }
+
+    /// Returns an iterator over the ends of the chunks in data, ie the
+    /// offsets just past each boundary, as find_boundary() finds them.
+    /// Bytes after the last boundary are added to the current chunk.
+    pub fn boundaries<'a>(&'a mut self, data: &'a [u8]) -> Boundaries<'a> {
+        Boundaries {
+            chunker: self,
+            data,
+            offset: 0,
+        }
+    }
+
+    // Like boundaries(), but hashing data byte by byte, for the
+    // benchmarks that compare the two.
+    #[cfg(feature = "bench")]
+    pub fn boundaries_bytewise(&mut self, data: &[u8]) -> Vec<usize> {
+        let mut ends = Vec::new();
+        for (i, b) in data.iter().enumerate() {
+            if self.hash_byte(*b) {
+                ends.push(i + 1);
+            }
+        }
+        ends
+    }
+
+    fn reset(&mut self) {
+        self.bh.reset();
+        self.len = 0;
+    }
+}
+
+pub struct Boundaries<'a> {
+    chunker: &'a mut Chunker,
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for Boundaries<'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let n = self.chunker.find_boundary(&self.data[self.offset..])?;
+        self.offset += n;
+        Some(self.offset)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, RngCore, SeedableRng};
+
+    fn config(window: u32, target_bits: u32, min_size: usize, max_size: usize) -> ChunkerConfig {
+        ChunkerConfig {
+            window,
+            target_bits,
+            min_size,
+            max_size,
+        }
+    }
+
+    // Returns the sizes of the chunks data splits into.
+    fn chunk_sizes(c: &mut Chunker, data: &[u8]) -> Vec<usize> {
+        let mut sizes = Vec::new();
+        let mut start = 0;
+        for (i, b) in data.iter().enumerate() {
+            if c.hash_byte(*b) {
+                sizes.push(i + 1 - start);
+                start = i + 1;
+            }
+        }
+        if start < data.len() {
+            sizes.push(data.len() - start);
+        }
+        sizes
+    }
 
     #[test]
     fn test_hash_byte() {
         const S: &str = "Test hash byte";
         let mut expected = [3, 5].iter();
 
-        let mut c = Chunker::new(4, (1 << 4) - 1);
+        let mut c = Chunker::new(&config(4, 4, 1, usize::MAX));
         for (i, b) in S.as_bytes().iter().enumerate() {
             if c.hash_byte(*b) {
                 assert_eq!(Some(&i), expected.next());
@@ -57,4 +268,163 @@ mod tests {
         }
         assert_eq!(None, expected.next());
     }
+
+    // Returns the boundaries hash_byte finds in data, as offsets past
+    // each boundary byte.
+    fn boundaries_bytewise(c: &mut Chunker, data: &[u8]) -> Vec<usize> {
+        let mut ends = Vec::new();
+        for (i, b) in data.iter().enumerate() {
+            if c.hash_byte(*b) {
+                ends.push(i + 1);
+            }
+        }
+        ends
+    }
+
+    #[test]
+    fn find_boundary() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut data = vec![0u8; 100_000];
+        rng.fill_bytes(&mut data);
+        let configs = [
+            ChunkerConfig::default(),
+            ChunkerConfig::smol(),
+            config(67, 8, 0, usize::MAX),
+            config(4, 4, 1, 1),
+            config(32, 6, 100, 100),
+            config(16, 10, 10, 300),
+        ];
+        for cfg in configs.iter() {
+            let expected = boundaries_bytewise(&mut Chunker::new(cfg), &data);
+            assert_eq!(
+                expected,
+                Chunker::new(cfg).boundaries(&data).collect::<Vec<_>>(),
+                "{:?}",
+                cfg
+            );
+
+            // Chunks continue across calls.
+            for &step in &[1, 5, 64, 1000, 4096] {
+                let mut c = Chunker::new(cfg);
+                let mut ends = Vec::new();
+                for (i, piece) in data.chunks(step).enumerate() {
+                    let start = i * step;
+                    ends.extend(c.boundaries(piece).map(|end| start + end));
+                }
+                assert_eq!(expected, ends, "{:?} step {}", cfg, step);
+            }
+        }
+
+        let mut c = Chunker::default();
+        assert_eq!(None, c.find_boundary(&[]));
+        assert_eq!(None, c.boundaries(&[]).next());
+    }
+
+    #[test]
+    fn validate() {
+        assert!(ChunkerConfig::default().validate().is_ok());
+        assert!(ChunkerConfig::smol().validate().is_ok());
+        assert!(config(1, 1, 0, 1).validate().is_ok());
+        assert!(config(0, 12, 1, 2).validate().is_err());
+        assert!(config(67, 0, 1, 2).validate().is_err());
+        assert!(config(67, 32, 1, 2).validate().is_err());
+        assert!(config(67, 12, 0, 0).validate().is_err());
+        assert!(config(67, 12, 3, 2).validate().is_err());
+    }
+
+    #[test]
+    fn size_bounds() {
+        // Low-entropy input would otherwise make for tiny or huge chunks.
+        let inputs = [
+            vec![0u8; 10_000],
+            vec![0xffu8; 10_000],
+            b"ab".repeat(5_000),
+            (0..10_000).map(|i| (i % 251) as u8).collect(),
+        ];
+        for data in inputs.iter() {
+            let sizes = chunk_sizes(&mut Chunker::new(&config(8, 2, 16, 100)), data);
+            let (_, rest) = sizes.split_last().unwrap();
+            assert!(rest.iter().all(|s| (16..=100).contains(s)), "{:?}", sizes);
+        }
+    }
+
+    #[test]
+    fn size_distribution() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut data = vec![0u8; 1 << 20];
+        for _ in 0..20 {
+            let target_bits = rng.gen_range(4, 11);
+            let target = 1usize << target_bits;
+            let min_size = rng.gen_range(0, target);
+            let max_size = min_size.max(1) + rng.gen_range(1, 4 * target);
+            let cfg = config(rng.gen_range(1, 100), target_bits, min_size, max_size);
+            rng.fill_bytes(&mut data);
+
+            let sizes = chunk_sizes(&mut Chunker::new(&cfg), &data);
+            assert_eq!(data.len(), sizes.iter().sum::<usize>());
+            let (last, rest) = sizes.split_last().unwrap();
+            assert!(*last <= max_size);
+            for &s in rest {
+                assert!(s >= min_size.max(1) && s <= max_size, "{:?}: {}", cfg, s);
+            }
+
+            // Past min_size, each byte is a boundary with probability
+            // 2^-target_bits, up to max_size.
+            let m = min_size.max(1);
+            let q = 1.0 - 1.0 / target as f64;
+            let expected = m as f64 + q * (1.0 - q.powi((max_size - m) as i32)) * target as f64;
+            let mean = data.len() as f64 / sizes.len() as f64;
+            assert!(
+                (mean - expected).abs() < expected * 0.2,
+                "{:?}: mean {} expected {}",
+                cfg,
+                mean,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn default_size_distribution() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut data = vec![0u8; 4 << 20];
+        rng.fill_bytes(&mut data);
+        let cfg = ChunkerConfig::default();
+        let sizes = chunk_sizes(&mut Chunker::default(), &data);
+        let mean = data.len() as f64 / sizes.len() as f64;
+        let expected = (cfg.min_size + (1 << cfg.target_bits)) as f64;
+        assert!((mean - expected).abs() < expected * 0.1, "mean {}", mean);
+        // Hitting the max size is rare with random input.
+        let maxed = sizes.iter().filter(|&&s| s == cfg.max_size).count();
+        assert!(maxed * 100 < sizes.len(), "{} of {}", maxed, sizes.len());
+    }
+
+    #[test]
+    fn parse() {
+        let cfg = ChunkerConfig::smol();
+        let json = SerJson::serialize_json(&cfg);
+        assert_eq!(Ok(cfg), parse_config(json.as_bytes()));
+        assert!(parse_config(b"{}").is_err());
+        assert!(parse_config(b"\xff").is_err());
+        let invalid = SerJson::serialize_json(&config(0, 12, 1, 2));
+        assert!(parse_config(invalid.as_bytes()).is_err());
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[async_std::test]
+    async fn init_persists() {
+        use crate::kv::memstore::MemStore;
+
+        let mut store = MemStore::new();
+        assert_eq!(ChunkerConfig::default(), init(&store).await.unwrap());
+
+        // A stored config wins over the default.
+        let key = Key::ChunkerConfig.to_string();
+        let smol = SerJson::serialize_json(&ChunkerConfig::smol());
+        store.put(&key, smol.as_bytes()).await.unwrap();
+        assert_eq!(ChunkerConfig::smol(), init(&store).await.unwrap());
+
+        store.put(&key, b"{}").await.unwrap();
+        assert!(init(&store).await.is_err());
+    }
 }
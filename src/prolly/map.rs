@@ -0,0 +1,690 @@
+//! Sorted maps of keys to values, stored as trees of chunks.
+//!
+//! Leaves hold entries in key order, and the nodes above them hold the
+//! last key and hash of each of their children. Where a node ends
+//! depends only on the key it ends with (see is_boundary()), so a map's
+//! chunks depend only on its entries, not on the puts that made it, and
+//! a put only rewrites the nodes on the paths to the keys it puts.
+//!
+//! Values are opaque, but leaves refer to the blobs of values stored by
+//! blob::put_value, so that blobs are reachable from the map's root.
+use super::blob;
+use crate::dag::chunk::{Chunk, Info, Kind};
+use crate::dag::read::Source;
+use crate::dag::{write, Error};
+use crate::hash::{self, Hash};
+use crate::kv;
+use futures::future::{FutureExt, LocalBoxFuture};
+use log::error;
+use std::convert::TryInto;
+
+type Result<T> = std::result::Result<T, Error>;
+
+// A key and value, or, above the leaves, a child's last key and hash sum.
+type Entry = (String, Vec<u8>);
+
+// The average number of entries in a node.
+const FANOUT: u64 = 32;
+
+// A node as stored in a chunk:
+//
+//   level    u8, 0 for leaves
+//   count    u32
+//   entries  count times:
+//     key    u32 length, then UTF-8
+//     value  u32 length, then bytes: a value in a leaf, or the sum of a
+//            child's hash above
+//
+// Integers are little-endian.
+#[derive(Debug, Default)]
+struct Node {
+    level: u8,
+    entries: Vec<Entry>,
+}
+
+impl Node {
+    fn encode(&self) -> Vec<u8> {
+        let mut data = vec![self.level];
+        data.extend_from_slice(&(self.entries.len() as u32).to_le_bytes());
+        for (key, value) in self.entries.iter() {
+            data.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            data.extend_from_slice(key.as_bytes());
+            data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            data.extend_from_slice(value);
+        }
+        data
+    }
+
+    fn decode(data: &[u8]) -> Option<Node> {
+        fn take<'a>(data: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+            if data.len() < n {
+                return None;
+            }
+            let (v, rest) = data.split_at(n);
+            *data = rest;
+            Some(v)
+        }
+        fn take_len(data: &mut &[u8]) -> Option<usize> {
+            Some(u32::from_le_bytes(take(data, 4)?.try_into().ok()?) as usize)
+        }
+
+        let mut data = data;
+        let level = take(&mut data, 1)?[0];
+        let count = take_len(&mut data)?;
+        let mut entries = Vec::with_capacity(count.min(data.len()));
+        for _ in 0..count {
+            let len = take_len(&mut data)?;
+            let key = String::from_utf8(take(&mut data, len)?.to_vec()).ok()?;
+            let len = take_len(&mut data)?;
+            let value = take(&mut data, len)?.to_vec();
+            if level > 0 && Hash::from_slice(&value).is_none() {
+                return None;
+            }
+            entries.push((key, value));
+        }
+        // Only leaves may be empty: an internal node has a child for
+        // every key in its range.
+        if !data.is_empty() || (level > 0 && entries.is_empty()) {
+            return None;
+        }
+        Some(Node { level, entries })
+    }
+
+    fn to_chunk(&self) -> Chunk {
+        let data = self.encode();
+        let (kind, refs): (Kind, Vec<Hash>) = match self.level {
+            0 => (
+                Kind::MapLeaf,
+                self.entries
+                    .iter()
+                    .filter_map(|(_, v)| blob::blob_ref(v))
+                    .collect(),
+            ),
+            _ => (
+                Kind::MapInternal,
+                self.entries
+                    .iter()
+                    .filter_map(|(_, v)| Hash::from_slice(v))
+                    .collect(),
+            ),
+        };
+        let info = Info {
+            kind,
+            ..Default::default()
+        };
+        Chunk::with_info(Hash::of(&data), data, &refs, &info)
+    }
+
+    // The index of the child whose range includes key: the first child
+    // whose last key is not before key, if any.
+    fn child_index(&self, key: &str) -> Option<usize> {
+        let i = match self.entries.binary_search_by(|(k, _)| k.as_str().cmp(key)) {
+            Ok(i) => i,
+            Err(i) => i,
+        };
+        if i < self.entries.len() {
+            Some(i)
+        } else {
+            None
+        }
+    }
+
+    fn child(&self, i: usize) -> Hash {
+        // Checked by decode().
+        Hash::from_slice(&self.entries[i].1).unwrap_or_else(Hash::empty)
+    }
+}
+
+// Whether an entry with key ends the node it is in at level. Keys do so
+// with a probability of 1 / FANOUT^(level + 1), so nodes have FANOUT
+// entries on average, and a boundary at a level is one at every level
+// below it too.
+fn is_boundary(key: &str, level: u8) -> bool {
+    let h = Hash::of(key.as_bytes());
+    let mut n = [0u8; 8];
+    n.copy_from_slice(&h.sum[..8]);
+    match FANOUT.checked_pow(u32::from(level) + 1) {
+        Some(d) => u64::from_le_bytes(n) % d == 0,
+        None => false,
+    }
+}
+
+async fn load(source: &dyn Source, hash: &Hash, level: Option<u8>) -> Result<Node> {
+    let c = match source.get_chunk(hash).await? {
+        Some(c) => c,
+        None => {
+            error!("Missing map node: {}", hash);
+            return Err(Error::CorruptStore);
+        }
+    };
+    match Node::decode(c.data()) {
+        Some(node) if level.is_none() || level == Some(node.level) => Ok(node),
+        _ => {
+            error!("Invalid map node: {}", hash);
+            Err(Error::CorruptStore)
+        }
+    }
+}
+
+/// Gets the value of key in the map with root, if it has one.
+pub async fn get(source: &dyn Source, root: &Hash, key: &str) -> Result<Option<Vec<u8>>> {
+    let mut node = load(source, root, None).await?;
+    while node.level > 0 {
+        node = match node.child_index(key) {
+            Some(i) => load(source, &node.child(i), Some(node.level - 1)).await?,
+            None => return Ok(None),
+        };
+    }
+    match node.entries.binary_search_by(|(k, _)| k.as_str().cmp(key)) {
+        Ok(i) => Ok(Some(node.entries.swap_remove(i).1)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Visits the entries of the map with root that match opts in key order,
+/// until visit returns false or there are no more entries. Leaves are
+/// read as the scan reaches them.
+pub async fn scan(
+    source: &dyn Source,
+    root: &Hash,
+    opts: &kv::ScanOptions<'_>,
+    visit: &mut kv::Visitor<'_>,
+) -> Result<()> {
+    let (lower, exclusive) = opts.lower_bound();
+
+    // The nodes above the current leaf, each with the index of the
+    // child to read once the current one is done.
+    let mut stack: Vec<(Node, usize)> = Vec::new();
+    let mut node = load(source, root, None).await?;
+    while node.level > 0 {
+        let i = match node.child_index(lower) {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+        let child = load(source, &node.child(i), Some(node.level - 1)).await?;
+        stack.push((node, i + 1));
+        node = child;
+    }
+    let mut start = match node
+        .entries
+        .binary_search_by(|(k, _)| k.as_str().cmp(lower))
+    {
+        Ok(i) if exclusive => i + 1,
+        Ok(i) => i,
+        Err(i) => i,
+    };
+    loop {
+        for (key, value) in node.entries[start..].iter() {
+            if !key.starts_with(opts.prefix) || !visit(key, value) {
+                return Ok(());
+            }
+        }
+
+        // On to the next leaf: up to the first node with children left,
+        // then down the first child of each node below it.
+        let (parent, i) = loop {
+            match stack.pop() {
+                Some((parent, i)) if i < parent.entries.len() => break (parent, i),
+                Some(_) => (),
+                None => return Ok(()),
+            }
+        };
+        node = load(source, &parent.child(i), Some(parent.level - 1)).await?;
+        stack.push((parent, i + 1));
+        while node.level > 0 {
+            let child = load(source, &node.child(0), Some(node.level - 1)).await?;
+            stack.push((node, 1));
+            node = child;
+        }
+        start = 0;
+    }
+}
+
+/// Puts entries into the map with root, or into an empty map if root is
+/// None, and returns the root of the resulting map. Where entries have
+/// the same key, the last one wins. Nodes are read from source and the
+/// nodes of the resulting map are written to kvw.
+pub async fn put(
+    kvw: &dyn kv::Write,
+    source: &dyn Source,
+    root: Option<&Hash>,
+    mut entries: Vec<Entry>,
+) -> Result<Hash> {
+    entries.reverse();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    entries.dedup_by(|a, b| a.0 == b.0);
+
+    let node = match root {
+        Some(root) => load(source, root, None).await?,
+        None => Node::default(),
+    };
+    let mut level = node.level;
+    let mut nodes = put_node(kvw, source, node, &entries).await?;
+    // Each level up has fewer nodes, until there is only the root.
+    while nodes.len() > 1 {
+        level += 1;
+        nodes = write_nodes(kvw, level, nodes).await?;
+    }
+    match nodes.pop() {
+        Some((_, sum)) => Ok(Hash::from_slice(&sum).unwrap_or_else(Hash::empty)),
+        None => Err(Error::CorruptStore),
+    }
+}
+
+// Puts sorted entries with distinct keys into the subtree at node, and
+// returns the last key and hash sum of each node it becomes. Puts only
+// add keys, so a node's last key stays its last, and the nodes it
+// becomes never need joining with their neighbors.
+fn put_node<'a>(
+    kvw: &'a dyn kv::Write,
+    source: &'a dyn Source,
+    node: Node,
+    entries: &'a [Entry],
+) -> LocalBoxFuture<'a, Result<Vec<Entry>>> {
+    async move {
+        if node.level == 0 {
+            let merged = merge(node.entries, entries.to_vec());
+            return write_nodes(kvw, 0, merged).await;
+        }
+
+        // The entries that go to each child. Keys past the last child's
+        // last key go to the last child.
+        let mut groups: Vec<(usize, &[Entry])> = Vec::new();
+        let mut rest = entries;
+        while let Some((key, _)) = rest.first() {
+            let i = node.child_index(key).unwrap_or(node.entries.len() - 1);
+            let n = match node.entries.get(i + 1) {
+                Some(_) => rest
+                    .iter()
+                    .take_while(|(k, _)| *k <= node.entries[i].0)
+                    .count(),
+                None => rest.len(),
+            };
+            groups.push((i, &rest[..n]));
+            rest = &rest[n..];
+        }
+
+        let hashes: Vec<Hash> = groups.iter().map(|(i, _)| node.child(*i)).collect();
+        let mut children = Vec::with_capacity(hashes.len());
+        for (h, c) in hashes.iter().zip(source.get_chunks(&hashes).await?) {
+            match c.and_then(|c| Node::decode(c.data())) {
+                Some(child) if child.level + 1 == node.level => children.push(child),
+                _ => {
+                    error!("Invalid map node: {}", h);
+                    return Err(Error::CorruptStore);
+                }
+            }
+        }
+
+        // Each child with entries is replaced by the nodes it becomes.
+        let level = node.level;
+        let mut old = node.entries.into_iter();
+        let mut updated = Vec::new();
+        let mut next = 0;
+        for ((i, group), child) in groups.into_iter().zip(children) {
+            updated.extend(old.by_ref().take(i - next));
+            old.next();
+            next = i + 1;
+            updated.extend(put_node(kvw, source, child, group).await?);
+        }
+        updated.extend(old);
+        write_nodes(kvw, level, updated).await
+    }
+    .boxed_local()
+}
+
+// Merges sorted entries into sorted old entries, replacing old entries
+// with the same key.
+fn merge(old: Vec<Entry>, new: Vec<Entry>) -> Vec<Entry> {
+    let mut merged = Vec::with_capacity(old.len() + new.len());
+    let mut old = old.into_iter().peekable();
+    for e in new {
+        while let Some(o) = old.peek() {
+            if o.0 > e.0 {
+                break;
+            }
+            if o.0 < e.0 {
+                merged.extend(old.next());
+            } else {
+                old.next();
+            }
+        }
+        merged.push(e);
+    }
+    merged.extend(old);
+    merged
+}
+
+// Splits entries into nodes at level, writes them, and returns the last
+// key and hash sum of each, to make the entries of the level above.
+async fn write_nodes(kvw: &dyn kv::Write, level: u8, entries: Vec<Entry>) -> Result<Vec<Entry>> {
+    let mut nodes = Vec::new();
+    let mut node = Node {
+        level,
+        entries: Vec::new(),
+    };
+    let len = entries.len();
+    for (i, (key, value)) in entries.into_iter().enumerate() {
+        let end = is_boundary(&key, level) || i + 1 == len;
+        node.entries.push((key, value));
+        if end {
+            nodes.push(std::mem::replace(
+                &mut node,
+                Node {
+                    level,
+                    entries: Vec::new(),
+                },
+            ));
+        }
+    }
+    // An empty map is an empty leaf.
+    if nodes.is_empty() {
+        nodes.push(node);
+    }
+
+    let mut summaries = Vec::with_capacity(nodes.len());
+    for node in nodes {
+        let c = node.to_chunk();
+        write::put_chunk(kvw, &c).await?;
+        let key = node
+            .entries
+            .last()
+            .map(|(k, _)| k.clone())
+            .unwrap_or_default();
+        let mut sum = Vec::with_capacity(hash::BYTE_LENGTH);
+        sum.extend_from_slice(&c.hash().sum);
+        summaries.push((key, sum));
+    }
+    Ok(summaries)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dag::read::Read;
+    use crate::kv::memstore::MemStore;
+    use crate::kv::{ScanOptions, Store};
+    use std::collections::BTreeMap;
+
+    fn entries(keys: impl Iterator<Item = usize>) -> Vec<Entry> {
+        keys.map(|i| (format!("k{:05}", i), format!("v{}", i).into_bytes()))
+            .collect()
+    }
+
+    async fn put_all(store: &MemStore, root: Option<&Hash>, entries: Vec<Entry>) -> Hash {
+        let wt = store.write().await.unwrap();
+        let read = Read::new(Box::new(wt.as_read()));
+        let root = put(wt.as_ref(), &read, root, entries).await.unwrap();
+        drop(read);
+        wt.commit().await.unwrap();
+        root
+    }
+
+    async fn scan_all(store: &MemStore, root: &Hash, opts: &ScanOptions<'_>) -> Vec<String> {
+        let read = Read::new(store.read().await.unwrap());
+        let mut keys = Vec::new();
+        scan(&read, root, opts, &mut |k, _| {
+            keys.push(k.to_string());
+            true
+        })
+        .await
+        .unwrap();
+        keys
+    }
+
+    async fn height(store: &MemStore, root: &Hash) -> u8 {
+        let read = Read::new(store.read().await.unwrap());
+        load(&read, root, None).await.unwrap().level
+    }
+
+    #[test]
+    fn node_encoding() {
+        let node = Node {
+            level: 0,
+            entries: vec![("a".into(), b"1".to_vec()), ("".into(), vec![])],
+        };
+        let data = node.encode();
+        let decoded = Node::decode(&data).unwrap();
+        assert_eq!((node.level, node.entries), (decoded.level, decoded.entries));
+
+        assert!(Node::decode(&[]).is_none());
+        assert!(Node::decode(&data[..data.len() - 1]).is_none());
+        assert!(Node::decode(&[data.clone(), vec![0]].concat()).is_none());
+        // Above the leaves, values must be hashes, and there must be some.
+        assert!(Node::decode(&[&[1], &data[1..]].concat()).is_none());
+        assert!(Node::decode(&[0, 0, 0, 0, 0]).is_some());
+        assert!(Node::decode(&[1, 0, 0, 0, 0]).is_none());
+    }
+
+    #[async_std::test]
+    async fn get_and_put() {
+        let store = MemStore::new();
+        let empty = put_all(&store, None, vec![]).await;
+        let read = Read::new(store.read().await.unwrap());
+        assert_eq!(None, get(&read, &empty, "k").await.unwrap());
+        drop(read);
+
+        // Enough entries for a few levels.
+        let mut expected: BTreeMap<String, Vec<u8>> = entries(0..5000).into_iter().collect();
+        let mut root = put_all(&store, Some(&empty), entries((0..5000).step_by(2))).await;
+        root = put_all(&store, Some(&root), entries((1..5000).step_by(2))).await;
+        assert!(height(&store, &root).await >= 2);
+
+        // Later puts of a key win.
+        let overwrites = vec![
+            ("k00010".to_string(), b"x".to_vec()),
+            ("k00010".to_string(), b"y".to_vec()),
+            ("k99999".to_string(), b"z".to_vec()),
+            ("".to_string(), b"".to_vec()),
+        ];
+        for (k, v) in overwrites.iter() {
+            expected.insert(k.clone(), v.clone());
+        }
+        root = put_all(&store, Some(&root), overwrites).await;
+
+        let read = Read::new(store.read().await.unwrap());
+        for (k, v) in expected.iter() {
+            assert_eq!(Some(v), get(&read, &root, k).await.unwrap().as_ref());
+        }
+        assert_eq!(None, get(&read, &root, "k00010a").await.unwrap());
+        assert_eq!(None, get(&read, &root, "l").await.unwrap());
+        drop(read);
+
+        let keys: Vec<String> = expected.keys().cloned().collect();
+        assert_eq!(keys, scan_all(&store, &root, &ScanOptions::default()).await);
+    }
+
+    #[async_std::test]
+    async fn canonical() {
+        // The same entries make the same root, however they are put.
+        let store = MemStore::new();
+        let all = put_all(&store, None, entries(0..3000)).await;
+        let mut root = put_all(&store, None, entries(2000..3000)).await;
+        for i in (0..2000).step_by(250).rev() {
+            root = put_all(&store, Some(&root), entries(i..i + 250)).await;
+        }
+        assert_eq!(all, root);
+    }
+
+    #[async_std::test]
+    async fn put_rewrites_few_chunks() {
+        let store = MemStore::new();
+        let root = put_all(&store, None, entries(0..5000)).await;
+        let h = height(&store, &root).await;
+        let before = store.read().await.unwrap();
+        let mut count = 0;
+        let opts = ScanOptions {
+            prefix: "c/",
+            ..Default::default()
+        };
+        before
+            .scan(&opts, &mut |_, _| {
+                count += 1;
+                true
+            })
+            .await
+            .unwrap();
+        drop(before);
+
+        let update = vec![("k02500".to_string(), b"new".to_vec())];
+        let root = put_all(&store, Some(&root), update).await;
+        let mut after = 0;
+        let rt = store.read().await.unwrap();
+        rt.scan(&opts, &mut |_, _| {
+            after += 1;
+            true
+        })
+        .await
+        .unwrap();
+        // A new node for each level, on the path to the key.
+        assert_eq!(after - count, h as usize + 1);
+        assert_eq!(h, height(&store, &root).await);
+    }
+
+    #[async_std::test]
+    async fn scan_options() {
+        let store = MemStore::new();
+        let root = put_all(&store, None, entries(0..2000)).await;
+        async fn test(
+            store: &MemStore,
+            root: &Hash,
+            prefix: &str,
+            start_key: Option<&str>,
+            start_exclusive: bool,
+            expected: &[usize],
+        ) {
+            let opts = ScanOptions {
+                prefix,
+                start_key,
+                start_exclusive,
+            };
+            let expected: Vec<String> = expected.iter().map(|i| format!("k{:05}", i)).collect();
+            assert_eq!(expected, scan_all(store, root, &opts).await);
+        }
+
+        test(
+            &store,
+            &root,
+            "k0199",
+            None,
+            false,
+            &[1990, 1991, 1992, 1993, 1994, 1995, 1996, 1997, 1998, 1999],
+        )
+        .await;
+        test(
+            &store,
+            &root,
+            "k0199",
+            Some("k01995"),
+            false,
+            &[1995, 1996, 1997, 1998, 1999],
+        )
+        .await;
+        test(
+            &store,
+            &root,
+            "k0199",
+            Some("k01995"),
+            true,
+            &[1996, 1997, 1998, 1999],
+        )
+        .await;
+        test(
+            &store,
+            &root,
+            "k0199",
+            Some("k019955"),
+            true,
+            &[1996, 1997, 1998, 1999],
+        )
+        .await;
+        test(
+            &store,
+            &root,
+            "k0199",
+            Some("k00000"),
+            false,
+            &[1990, 1991, 1992, 1993, 1994, 1995, 1996, 1997, 1998, 1999],
+        )
+        .await;
+        test(&store, &root, "k0199", Some("k02"), false, &[]).await;
+        test(&store, &root, "k02", None, false, &[]).await;
+        test(&store, &root, "", Some("k01999"), false, &[1999]).await;
+        test(&store, &root, "", Some("k01999"), true, &[]).await;
+
+        // Scans end when visit returns false, across leaves too.
+        let read = Read::new(store.read().await.unwrap());
+        let mut keys = Vec::new();
+        scan(&read, &root, &ScanOptions::default(), &mut |k, _| {
+            keys.push(k.to_string());
+            keys.len() < 500
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            entries(0..500)
+                .into_iter()
+                .map(|(k, _)| k)
+                .collect::<Vec<_>>(),
+            keys
+        );
+    }
+
+    #[async_std::test]
+    async fn refs() {
+        let store = MemStore::new();
+        let wt = store.write().await.unwrap();
+        let big = vec![b'1'; blob::MIN_BLOB_SIZE];
+        let config = crate::prolly::chunker::ChunkerConfig::default();
+        let stored = blob::put_value(wt.as_ref(), &config, &big).await.unwrap();
+        let read = Read::new(Box::new(wt.as_read()));
+        let entries = vec![
+            ("a".to_string(), b"1".to_vec()),
+            ("b".to_string(), stored.clone()),
+        ];
+        let root = put(wt.as_ref(), &read, None, entries).await.unwrap();
+
+        // Leaves refer to the blobs in them.
+        let leaf = read.get_chunk(&root).await.unwrap().unwrap();
+        assert_eq!(Kind::MapLeaf, leaf.info().kind);
+        let refs: Vec<Hash> = leaf.refs().into_iter().flatten().collect();
+        assert_eq!(vec![blob::blob_ref(&stored).unwrap()], refs);
+        let value = get(&read, &root, "b").await.unwrap().unwrap();
+        assert_eq!(big, blob::get_value(&read, value).await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn corrupt_nodes() {
+        let store = MemStore::new();
+        let wt = store.write().await.unwrap();
+        let c = Chunk::new(Hash::of(b"junk"), b"junk".to_vec(), &[]);
+        write::put_chunk(wt.as_ref(), &c).await.unwrap();
+        let empty = Node {
+            level: 1,
+            entries: vec![],
+        }
+        .to_chunk();
+        write::put_chunk(wt.as_ref(), &empty).await.unwrap();
+        wt.commit().await.unwrap();
+
+        let roots = [*c.hash(), *empty.hash(), Hash::of(b"nope")];
+        let read = Read::new(store.read().await.unwrap());
+        for root in roots.iter() {
+            assert!(matches!(
+                get(&read, root, "k").await,
+                Err(Error::CorruptStore)
+            ));
+        }
+        drop(read);
+        let wt = store.write().await.unwrap();
+        let read = Read::new(Box::new(wt.as_read()));
+        for root in roots.iter() {
+            let entries = vec![("k".to_string(), b"v".to_vec())];
+            assert!(matches!(
+                put(wt.as_ref(), &read, Some(root), entries).await,
+                Err(Error::CorruptStore)
+            ));
+        }
+    }
+}
@@ -1,2 +1,4 @@
+pub mod blob;
 mod buzhash;
 pub mod chunker;
+pub mod map;
@@ -0,0 +1,75 @@
+use crate::dag::key::Key;
+use crate::hash::Hash;
+use crate::kv::{Store, StoreError};
+
+/// Returns the client id of the database in store, generating and
+/// persisting a new random one if the database does not have one yet.
+///
+/// The check and the write happen in a single write transaction, so
+/// connections opened concurrently (eg in two tabs) agree on the id.
+pub async fn init(store: &dyn Store) -> Result<String, StoreError> {
+    let key = Key::ClientID.to_string();
+    let wt = store.write().await?;
+    if let Some(bytes) = wt.get(&key).await? {
+        wt.rollback().await?;
+        return match String::from_utf8(bytes) {
+            Ok(id) => Ok(id),
+            Err(e) => Err(StoreError::Str(format!("Invalid client id: {}", e))),
+        };
+    }
+    let id = new_client_id();
+    wt.put(&key, id.as_bytes()).await?;
+    wt.commit().await?;
+    Ok(id)
+}
+
+fn new_client_id() -> String {
+    let mut seed = Vec::with_capacity(32);
+    for _ in 0..4 {
+        seed.extend_from_slice(&random_u64().to_le_bytes());
+    }
+    Hash::of(&seed).to_string()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn random_u64() -> u64 {
+    // Math.random() yields 52 random bits; the time guards against a
+    // poorly seeded generator.
+    (js_sys::Math::random() * (1u64 << 52) as f64) as u64 ^ js_sys::Date::now() as u64
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn random_u64() -> u64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    use std::time::SystemTime;
+
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(d) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(d.as_nanos());
+    }
+    hasher.finish()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::memstore::MemStore;
+
+    #[async_std::test]
+    async fn init_persists() {
+        let store = MemStore::new();
+        let id = init(&store).await.unwrap();
+        assert_eq!(32, id.len());
+        assert!(Hash::parse(&id).is_ok());
+        assert_eq!(
+            Some(id.as_bytes().to_vec()),
+            store.get("sys/cid").await.unwrap()
+        );
+        assert_eq!(id, init(&store).await.unwrap());
+
+        let other = MemStore::new();
+        assert_ne!(id, init(&other).await.unwrap());
+    }
+}
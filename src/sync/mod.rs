@@ -0,0 +1,4 @@
+//! State and protocols for syncing a database with other replicas.
+pub mod client_id;
+#[allow(dead_code)] // Until there is a network transport.
+pub mod pull;
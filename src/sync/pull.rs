@@ -0,0 +1,325 @@
+//! Pulls the chunks reachable from a remote root that the local dag
+//! lacks.
+//!
+//! Chunks are content-addressed, so a chunk that is present locally
+//! implies its whole subtree is too, and pull only descends into refs
+//! that are missing. Chunks are requested from the peer a level of the
+//! dag at a time, in batches, and written once all have arrived.
+use crate::dag;
+use crate::dag::chunk::Chunk;
+use crate::dag::verify::verify_chunk;
+use crate::dag::write::Write;
+use crate::hash::Hash;
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// The most chunks pull asks a transport for at once.
+pub const MAX_BATCH: usize = 100;
+
+/// Fetches chunks from a peer.
+#[async_trait(?Send)]
+pub trait Transport {
+    /// Returns the chunks with the given hashes that the peer has, in
+    /// any order.
+    async fn get_chunks(&self, hashes: &[Hash]) -> std::result::Result<Vec<Chunk>, String>;
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Dag(dag::Error),
+    Transport(String),
+    // The peer does not have a chunk reachable from the root.
+    MissingChunk(Hash),
+    // The peer sent a chunk that does not match its hash, that has
+    // invalid meta, or that was not asked for.
+    UnexpectedChunk(Hash),
+}
+
+impl From<dag::Error> for Error {
+    fn from(err: dag::Error) -> Error {
+        Error::Dag(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Dag(e) => write!(f, "{:?}", e),
+            Error::Transport(e) => write!(f, "Transport error: {}", e),
+            Error::MissingChunk(h) => write!(f, "Peer is missing chunk {}", h),
+            Error::UnexpectedChunk(h) => write!(f, "Peer sent unexpected chunk {}", h),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Fetches the chunks reachable from root that write's dag does not
+/// have from transport, and writes them in topological order, refs
+/// before the chunks referring to them. The caller commits. Returns
+/// the number of chunks written.
+pub async fn pull(write: &mut Write<'_>, transport: &dyn Transport, root: &Hash) -> Result<usize> {
+    let mut received = HashMap::new();
+    let mut wanted = Vec::new();
+    if !write.has_chunk(root).await? {
+        wanted.push(*root);
+    }
+    let mut seen: HashSet<Hash> = wanted.iter().copied().collect();
+
+    while !wanted.is_empty() {
+        let mut next = Vec::new();
+        for batch in wanted.chunks(MAX_BATCH) {
+            let chunks = transport
+                .get_chunks(batch)
+                .await
+                .map_err(Error::Transport)?;
+            for c in chunks {
+                let h = *c.hash();
+                if !batch.contains(&h) || Hash::of(c.data()) != h || !verify_chunk(&c) {
+                    return Err(Error::UnexpectedChunk(h));
+                }
+                if let Some(refs) = c.refs() {
+                    for r in refs {
+                        if seen.insert(r) && !write.has_chunk(&r).await? {
+                            next.push(r);
+                        }
+                    }
+                }
+                received.insert(h, c);
+            }
+            if let Some(h) = batch.iter().find(|h| !received.contains_key(h)) {
+                return Err(Error::MissingChunk(*h));
+            }
+        }
+        wanted = next;
+    }
+
+    let order = topological_order(root, &received);
+    for h in order.iter() {
+        write.put_chunk(&received[h]).await?;
+    }
+    Ok(order.len())
+}
+
+// Returns the hashes of chunks reachable from root, refs first. Refs not
+// in chunks are skipped.
+fn topological_order(root: &Hash, chunks: &HashMap<Hash, Chunk>) -> Vec<Hash> {
+    let mut order = Vec::with_capacity(chunks.len());
+    let mut visited = HashSet::new();
+    // Each entry is a chunk and whether its refs have been pushed.
+    let mut stack = vec![(*root, false)];
+    while let Some((h, expanded)) = stack.pop() {
+        if expanded {
+            order.push(h);
+            continue;
+        }
+        let c = match chunks.get(&h) {
+            Some(c) => c,
+            None => continue,
+        };
+        if !visited.insert(h) {
+            continue;
+        }
+        stack.push((h, true));
+        if let Some(refs) = c.refs() {
+            stack.extend(refs.filter(|r| !visited.contains(r)).map(|r| (r, false)));
+        }
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::memstore::MemStore;
+    use crate::kv::Store;
+    use std::cell::RefCell;
+
+    fn chunk(data: &[u8], refs: &[Hash]) -> Chunk {
+        Chunk::new(Hash::of(data), data.to_vec(), refs)
+    }
+
+    fn copy(c: &Chunk) -> Chunk {
+        Chunk::read(*c.hash(), c.data().to_vec(), c.meta().map(|m| m.to_vec()))
+    }
+
+    // A peer with chunks in memory, which records what it is asked for.
+    #[derive(Default)]
+    struct FakePeer {
+        chunks: HashMap<Hash, Chunk>,
+        requests: RefCell<Vec<Vec<Hash>>>,
+        // Sent in response to every request, if set.
+        extra: Option<Chunk>,
+    }
+
+    impl FakePeer {
+        fn add(&mut self, c: Chunk) -> Hash {
+            let h = *c.hash();
+            self.chunks.insert(h, c);
+            h
+        }
+
+        fn requested(&self) -> HashSet<Hash> {
+            self.requests.borrow().iter().flatten().copied().collect()
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl Transport for FakePeer {
+        async fn get_chunks(&self, hashes: &[Hash]) -> std::result::Result<Vec<Chunk>, String> {
+            self.requests.borrow_mut().push(hashes.to_vec());
+            let mut chunks: Vec<Chunk> = hashes
+                .iter()
+                .filter_map(|h| self.chunks.get(h))
+                .map(copy)
+                .collect();
+            chunks.extend(self.extra.as_ref().map(copy));
+            Ok(chunks)
+        }
+    }
+
+    // Adds a tree to peer with the given fan-out at each level, whose
+    // leaves are labeled with prefix. Returns its root.
+    fn tree(peer: &mut FakePeer, prefix: &str, fan_out: &[usize]) -> Hash {
+        match fan_out.split_first() {
+            None => peer.add(chunk(prefix.as_bytes(), &[])),
+            Some((n, rest)) => {
+                let refs: Vec<Hash> = (0..*n)
+                    .map(|i| tree(peer, &format!("{}/{}", prefix, i), rest))
+                    .collect();
+                peer.add(chunk(format!("{}/", prefix).as_bytes(), &refs))
+            }
+        }
+    }
+
+    async fn pull_into(store: &MemStore, peer: &FakePeer, root: &Hash) -> Result<usize> {
+        let mut w = Write::new(store.write().await.unwrap());
+        let result = pull(&mut w, peer, root).await;
+        w.commit().await.unwrap();
+        result
+    }
+
+    async fn has_all(store: &MemStore, peer: &FakePeer) -> bool {
+        let r = dag::read::Read::new(store.read().await.unwrap());
+        for h in peer.chunks.keys() {
+            if !r.has_chunk(h).await.unwrap() {
+                return false;
+            }
+        }
+        true
+    }
+
+    #[async_std::test]
+    async fn pull_all() {
+        let mut peer = FakePeer::default();
+        let root = tree(&mut peer, "t", &[3, 4, 5]);
+        let store = MemStore::new();
+        assert_eq!(
+            peer.chunks.len(),
+            pull_into(&store, &peer, &root).await.unwrap()
+        );
+        assert!(has_all(&store, &peer).await);
+        // One request per level.
+        assert_eq!(4, peer.requests.borrow().len());
+
+        // Nothing more to pull.
+        peer.requests.borrow_mut().clear();
+        assert_eq!(0, pull_into(&store, &peer, &root).await.unwrap());
+        assert!(peer.requests.borrow().is_empty());
+    }
+
+    #[async_std::test]
+    async fn skips_present_subtrees() {
+        let mut peer = FakePeer::default();
+        let shared = tree(&mut peer, "shared", &[10, 10]);
+        let old_root = peer.add(chunk(b"old", &[shared]));
+        let new_leaf = peer.add(chunk(b"new leaf", &[]));
+        let new_root = peer.add(chunk(b"new", &[shared, new_leaf]));
+
+        let store = MemStore::new();
+        pull_into(&store, &peer, &old_root).await.unwrap();
+        peer.requests.borrow_mut().clear();
+
+        assert_eq!(2, pull_into(&store, &peer, &new_root).await.unwrap());
+        let expected: HashSet<Hash> = [new_root, new_leaf].iter().copied().collect();
+        assert_eq!(expected, peer.requested());
+        assert!(has_all(&store, &peer).await);
+    }
+
+    #[async_std::test]
+    async fn batches() {
+        let mut peer = FakePeer::default();
+        let root = tree(&mut peer, "t", &[MAX_BATCH * 2 + 1]);
+        let store = MemStore::new();
+        assert_eq!(
+            peer.chunks.len(),
+            pull_into(&store, &peer, &root).await.unwrap()
+        );
+        let sizes: Vec<usize> = peer.requests.borrow().iter().map(|r| r.len()).collect();
+        assert_eq!(vec![1, MAX_BATCH, MAX_BATCH, 1], sizes);
+    }
+
+    #[async_std::test]
+    async fn peer_errors() {
+        let mut peer = FakePeer::default();
+        let leaf = Hash::of(b"leaf");
+        let root = peer.add(chunk(b"root", &[leaf]));
+        let store = MemStore::new();
+        assert!(matches!(
+            pull_into(&store, &peer, &root).await,
+            Err(Error::MissingChunk(h)) if h == leaf
+        ));
+        // Nothing was written.
+        let r = dag::read::Read::new(store.read().await.unwrap());
+        assert!(!r.has_chunk(&root).await.unwrap());
+
+        // Chunks that were not asked for.
+        peer.add(chunk(b"leaf", &[]));
+        peer.extra = Some(chunk(b"extra", &[]));
+        assert!(matches!(
+            pull_into(&store, &peer, &root).await,
+            Err(Error::UnexpectedChunk(h)) if h == Hash::of(b"extra")
+        ));
+
+        // Chunks that don't match their hash.
+        peer.extra = None;
+        peer.chunks
+            .insert(leaf, Chunk::new(leaf, b"not leaf".to_vec(), &[]));
+        assert!(matches!(
+            pull_into(&store, &peer, &root).await,
+            Err(Error::UnexpectedChunk(h)) if h == leaf
+        ));
+
+        // Chunks with invalid meta.
+        peer.chunks.insert(
+            leaf,
+            Chunk::read(leaf, b"leaf".to_vec(), Some(vec![0xff, 0, 0, 0])),
+        );
+        assert!(matches!(
+            pull_into(&store, &peer, &root).await,
+            Err(Error::UnexpectedChunk(h)) if h == leaf
+        ));
+    }
+
+    #[test]
+    fn topological() {
+        let mut peer = FakePeer::default();
+        let d = peer.add(chunk(b"d", &[]));
+        let c = peer.add(chunk(b"c", &[d]));
+        let b = peer.add(chunk(b"b", &[d, c]));
+        let a = peer.add(chunk(b"a", &[c, b, Hash::of(b"absent")]));
+        assert_eq!(vec![d, c, b, a], topological_order(&a, &peer.chunks));
+
+        let root = tree(&mut peer, "t", &[4, 3, 2]);
+        let order = topological_order(&root, &peer.chunks);
+        assert_eq!(1 + 4 + 12 + 24, order.len());
+        let position: HashMap<Hash, usize> =
+            order.iter().enumerate().map(|(i, h)| (*h, i)).collect();
+        for (h, i) in position.iter() {
+            for r in peer.chunks[h].refs().into_iter().flatten() {
+                assert!(position[&r] < *i);
+            }
+        }
+    }
+}
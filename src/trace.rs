@@ -0,0 +1,204 @@
+//! Logging and timing of requests.
+//!
+//! The logger forwards to the console, like console_log, at a level that
+//! can be changed at runtime (see dispatch's setLogLevel). It can also
+//! keep the most recent events in memory, for the debug RPC to return
+//! after the fact. Spans time a request or a kv transaction, logging how
+//! long it took once it ends.
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// The level logged at until set otherwise.
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+lazy_static! {
+    static ref LOGGER: Logger = Logger::new();
+}
+
+/// Installs the logger, at DEFAULT_LEVEL.
+pub fn init() -> Result<(), log::SetLoggerError> {
+    log::set_logger(&*LOGGER)?;
+    log::set_max_level(DEFAULT_LEVEL);
+    Ok(())
+}
+
+/// Sets the level to log at and, if capacity is given, how many events
+/// to keep in memory. A capacity of 0 keeps none, which is the default.
+pub fn set_level(level: LevelFilter, capacity: Option<usize>) {
+    log::set_max_level(level);
+    if let Some(capacity) = capacity {
+        LOGGER.set_capacity(capacity);
+    }
+}
+
+/// The events kept in memory, oldest first.
+pub fn events() -> Vec<String> {
+    LOGGER.events()
+}
+
+struct Logger {
+    events: Mutex<Events>,
+}
+
+#[derive(Default)]
+struct Events {
+    capacity: usize,
+    buffer: VecDeque<String>,
+}
+
+impl Logger {
+    fn new() -> Logger {
+        Logger {
+            events: Mutex::new(Events::default()),
+        }
+    }
+
+    fn set_capacity(&self, capacity: usize) {
+        if let Ok(mut events) = self.events.lock() {
+            events.capacity = capacity;
+            while events.buffer.len() > capacity {
+                events.buffer.pop_front();
+            }
+        }
+    }
+
+    fn events(&self) -> Vec<String> {
+        match self.events.lock() {
+            Ok(events) => events.buffer.iter().cloned().collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    // Keeps record in memory, evicting the oldest event if full.
+    fn keep(&self, record: &Record) {
+        if let Ok(mut events) = self.events.lock() {
+            if events.capacity == 0 {
+                return;
+            }
+            if events.buffer.len() == events.capacity {
+                events.buffer.pop_front();
+            }
+            events.buffer.push_back(format!(
+                "{:.0} {} {} {}",
+                now(),
+                record.level(),
+                record.target(),
+                record.args()
+            ));
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.keep(record);
+            console_log::log(record);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Times something, from when the span is made until it drops, and logs
+/// how long it took at debug level. Nothing is logged or even formatted
+/// unless debug is enabled.
+pub struct Span {
+    // What is timed, when it started and how it failed, if it did.
+    label: Option<(String, f64, Option<String>)>,
+}
+
+impl Span {
+    pub fn new(label: impl FnOnce() -> String) -> Span {
+        if !log::log_enabled!(Level::Debug) {
+            return Span { label: None };
+        }
+        let label = label();
+        log::trace!("{} began", label);
+        Span {
+            label: Some((label, now(), None)),
+        }
+    }
+
+    /// Records that what is timed failed with error.
+    pub fn fail(&mut self, error: &str) {
+        if let Some((_, _, failure)) = self.label.as_mut() {
+            *failure = Some(error.into());
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some((label, start, failure)) = self.label.take() {
+            let elapsed = now() - start;
+            match failure {
+                None => log::debug!("{} took {:.1}ms", label, elapsed),
+                Some(e) => log::debug!("{} failed after {:.1}ms: {}", label, elapsed, e),
+            }
+        }
+    }
+}
+
+// Milliseconds since the epoch.
+#[cfg(target_arch = "wasm32")]
+fn now() -> f64 {
+    js_sys::Date::now()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn now() -> f64 {
+    use std::time::SystemTime;
+
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(d) => d.as_secs_f64() * 1000.0,
+        Err(_) => 0.0,
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keep(logger: &Logger, message: &str) {
+        logger.keep(
+            &Record::builder()
+                .args(format_args!("{}", message))
+                .level(Level::Warn)
+                .target("t")
+                .build(),
+        );
+    }
+
+    fn messages(logger: &Logger) -> Vec<String> {
+        logger
+            .events()
+            .iter()
+            .map(|e| e[e.find(' ').unwrap() + 1..].to_string())
+            .collect()
+    }
+
+    #[test]
+    fn ring_buffer() {
+        let logger = Logger::new();
+        keep(&logger, "none kept");
+        assert!(logger.events().is_empty());
+
+        logger.set_capacity(2);
+        keep(&logger, "a");
+        keep(&logger, "b");
+        keep(&logger, "c");
+        assert_eq!(vec!["WARN t b", "WARN t c"], messages(&logger));
+
+        logger.set_capacity(1);
+        assert_eq!(vec!["WARN t c"], messages(&logger));
+        logger.set_capacity(0);
+        assert!(logger.events().is_empty());
+    }
+}
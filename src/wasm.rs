@@ -1,13 +1,25 @@
+use async_std::sync::Mutex;
+use futures::future::{LocalBoxFuture, Shared};
+use futures::FutureExt;
+use js_sys::{Object, Reflect, JSON};
 use log::warn;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::rc::Rc;
 use std::sync::Once;
 use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
 use wasm_bindgen::JsValue;
+use wasm_bindgen_futures::{future_to_promise, spawn_local};
 
 use crate::dag::{chunk, key};
-use crate::dispatch;
+use crate::dispatch::{self, Reply, Rpc};
+use crate::hash::Hash;
 use crate::kv::idbstore::IdbStore;
 use crate::kv::Store;
 use crate::prolly::chunker::Chunker;
+use crate::trace;
 
 // Use `wee_alloc` as the global allocator.
 #[global_allocator]
@@ -16,15 +28,12 @@ static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 #[wasm_bindgen]
 pub async fn exercise_dag() {
     init_panic_hook();
-    let c = chunk::Chunk::new("h1".into(), vec![0, 1], &["r1"]);
-    let k1 = key::Key::parse("c/h1/d").unwrap();
-    let k2 = key::Key::parse("c/h1/m").unwrap();
+    let c = chunk::Chunk::new(Hash::of(&[0, 1]), vec![0, 1], &[Hash::of(b"r1")]);
+    let (s1, s2) = (format!("c/{}/d", c.hash()), format!("c/{}/m", c.hash()));
+    let k1 = key::Key::parse(&s1).unwrap();
+    let k2 = key::Key::parse(&s2).unwrap();
     let k3 = key::Key::parse("h/n1").unwrap();
-    let c2 = chunk::Chunk::read(
-        c.hash().into(),
-        c.data().to_vec(),
-        c.meta().map(|b| b.to_vec()),
-    );
+    let c2 = chunk::Chunk::read(*c.hash(), c.data().to_vec(), c.meta().map(|b| b.to_vec()));
     warn!("{:?} {:?} {:?} {:?} {:?}", c, c2, k1, k2, k3);
 }
 
@@ -53,13 +62,407 @@ pub async fn dispatch(db_name: String, rpc: String, args: String) -> Result<Stri
     }
 }
 
+// The class API: typed wrappers around the RPCs that dispatch() takes as
+// strings, without the JSON encoding of their arguments and responses.
+
+#[wasm_bindgen(typescript_custom_section)]
+const TYPES: &'static str = r#"
+export interface OpenOptions {
+    backend?: "idb" | "mem";
+}
+
+export interface ScanOptions {
+    prefix?: string;
+    start?: { key: string; exclusive?: boolean };
+    limit?: number;
+}
+
+export interface ScanItem {
+    key: string;
+    value: any;
+}
+
+export interface ScanIterator {
+    [Symbol.asyncIterator](): ScanIterator;
+}
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "OpenOptions")]
+    pub type OpenOptions;
+    #[wasm_bindgen(method, getter)]
+    fn backend(this: &OpenOptions) -> Option<String>;
+
+    #[wasm_bindgen(typescript_type = "ScanOptions")]
+    pub type ScanOptions;
+    #[wasm_bindgen(method, getter)]
+    fn prefix(this: &ScanOptions) -> Option<String>;
+    #[wasm_bindgen(method, getter)]
+    fn start(this: &ScanOptions) -> Option<ScanStart>;
+    #[wasm_bindgen(method, getter)]
+    fn limit(this: &ScanOptions) -> Option<f64>;
+
+    pub type ScanStart;
+    #[wasm_bindgen(method, getter)]
+    fn key(this: &ScanStart) -> String;
+    #[wasm_bindgen(method, getter)]
+    fn exclusive(this: &ScanStart) -> Option<bool>;
+
+    #[wasm_bindgen(typescript_type = "Promise<Database>")]
+    pub type DatabasePromise;
+    #[wasm_bindgen(typescript_type = "Promise<void>")]
+    pub type VoidPromise;
+    #[wasm_bindgen(typescript_type = "Promise<any>")]
+    pub type ValuePromise;
+    #[wasm_bindgen(typescript_type = "Promise<boolean>")]
+    pub type BoolPromise;
+    #[wasm_bindgen(typescript_type = "Promise<IteratorResult<ScanItem>>")]
+    pub type ScanItemPromise;
+    #[wasm_bindgen(typescript_type = "ScanIterator")]
+    pub type ScanIteratorValue;
+}
+
+/// An open database. Its methods are the RPCs of dispatch() on it.
+#[wasm_bindgen]
+pub struct Database {
+    name: String,
+    client_id: String,
+    backend: String,
+}
+
+#[wasm_bindgen]
+impl Database {
+    /// Opens the database name, with the idb backend unless options say
+    /// otherwise.
+    pub fn open(name: String, options: Option<OpenOptions>) -> DatabasePromise {
+        init_panic_hook();
+        let backend = options.and_then(|o| o.backend());
+        promise(async move {
+            match dispatch::call(name.clone(), Rpc::Open { backend }).await? {
+                Reply::Open { client_id, backend } => Ok(Database {
+                    name,
+                    client_id,
+                    backend,
+                }
+                .into()),
+                _ => Err(unexpected()),
+            }
+        })
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    #[wasm_bindgen(getter = clientId)]
+    pub fn client_id(&self) -> String {
+        self.client_id.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn backend(&self) -> String {
+        self.backend.clone()
+    }
+
+    /// Resolves to the value of key, or undefined if it has none.
+    pub fn get(&self, key: String) -> ValuePromise {
+        promise(get(self.name.clone(), key, None))
+    }
+
+    pub fn has(&self, key: String) -> BoolPromise {
+        promise(has(self.name.clone(), key, None))
+    }
+
+    pub fn put(&self, key: String, value: JsValue) -> VoidPromise {
+        let name = self.name.clone();
+        promise(async move { put(name, vec![(key, stringify(&value)?)]).await })
+    }
+
+    /// Closes the database, once the requests in flight on it end.
+    pub fn close(&self) -> VoidPromise {
+        let name = self.name.clone();
+        promise(async move {
+            dispatch::call(name, Rpc::Close).await?;
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    /// Returns an async iterator over the items options select, in key
+    /// order. It reads the items in pages, each in a transaction of its
+    /// own, but every page reads the entries as they were when the first
+    /// one was read, whatever is written while it iterates.
+    pub fn scan(&self, options: Option<ScanOptions>) -> ScanIteratorValue {
+        let mut scan = dispatch::Scan::default();
+        let mut limit = None;
+        if let Some(options) = options {
+            scan.prefix = options.prefix().unwrap_or_default();
+            if let Some(start) = options.start() {
+                scan.start_key = Some(start.key());
+                scan.start_exclusive = start.exclusive() == Some(true);
+            }
+            limit = options.limit().map(|v| v as u64);
+        }
+        let iter = ScanIterator {
+            state: Rc::new(Mutex::new(ScanState {
+                db_name: self.name.clone(),
+                scan,
+                limit,
+                items: VecDeque::new(),
+                done: false,
+            })),
+        };
+        async_iterable(iter.into()).unchecked_into()
+    }
+
+    /// Begins a transaction, reading the entries as they are now.
+    pub fn transaction(&self) -> Transaction {
+        let db_name = self.name.clone();
+        let root = async move {
+            match dispatch::call(db_name, Rpc::Root).await? {
+                Reply::Root(root) => Ok(root),
+                _ => Err(unexpected()),
+            }
+        };
+        let root = root.boxed_local().shared();
+        // Read the root now rather than on first use.
+        spawn_local(root.clone().map(drop));
+        Transaction {
+            db_name: self.name.clone(),
+            root,
+            pending: Rc::new(RefCell::new(BTreeMap::new())),
+        }
+    }
+}
+
+/// Reads and puts on the entries as they were when the transaction
+/// began. Reads see the transaction's own puts, and no one else's until
+/// it commits. Commit writes the puts at once, and fails if anything was
+/// written to the database since the transaction began.
+#[wasm_bindgen]
+pub struct Transaction {
+    db_name: String,
+    // The root of the map of entries read, if there were any entries.
+    root: Shared<LocalBoxFuture<'static, Result<Option<Hash>, String>>>,
+    // Keys and JSON values put and not yet committed.
+    pending: Rc<RefCell<BTreeMap<String, String>>>,
+}
+
+#[wasm_bindgen]
+impl Transaction {
+    pub fn get(&self, key: String) -> ValuePromise {
+        if let Some(value) = self.pending.borrow().get(&key) {
+            let value = parse(value);
+            return promise(async move { value });
+        }
+        let (db_name, root) = (self.db_name.clone(), self.root.clone());
+        promise(async move {
+            match root.await? {
+                Some(root) => get(db_name, key, Some(root)).await,
+                None => Ok(JsValue::UNDEFINED),
+            }
+        })
+    }
+
+    pub fn has(&self, key: String) -> BoolPromise {
+        if self.pending.borrow().contains_key(&key) {
+            return promise(async { Ok(JsValue::TRUE) });
+        }
+        let (db_name, root) = (self.db_name.clone(), self.root.clone());
+        promise(async move {
+            match root.await? {
+                Some(root) => has(db_name, key, Some(root)).await,
+                None => Ok(JsValue::FALSE),
+            }
+        })
+    }
+
+    /// Buffers value for key. Throws if value has no JSON form.
+    pub fn put(&self, key: String, value: JsValue) -> Result<(), JsValue> {
+        let value = stringify(&value).map_err(|e| JsValue::from_str(&e))?;
+        self.pending.borrow_mut().insert(key, value);
+        Ok(())
+    }
+
+    /// Writes the buffered puts in one transaction, and clears them.
+    /// Rejects, writing nothing, if the database was written to since
+    /// the transaction began.
+    pub fn commit(&self) -> VoidPromise {
+        let entries = std::mem::take(&mut *self.pending.borrow_mut());
+        let (db_name, root) = (self.db_name.clone(), self.root.clone());
+        promise(async move {
+            let rpc = Rpc::Commit(entries.into_iter().collect(), root.await?);
+            dispatch::call(db_name, rpc).await?;
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+}
+
+// Makes o its own async iterator, as for await expects of the value it
+// iterates. Set from JS, as wasm-bindgen exports no symbol-keyed methods.
+#[wasm_bindgen(inline_js = "export function async_iterable(o) {
+    o[Symbol.asyncIterator] = function() { return this; };
+    return o;
+}")]
+extern "C" {
+    fn async_iterable(o: JsValue) -> JsValue;
+}
+
+// Items are fetched this many at a time.
+const SCAN_PAGE_SIZE: u64 = 100;
+
+#[wasm_bindgen]
+pub struct ScanIterator {
+    // Locked by each call to next(), so that calls made without awaiting
+    // the previous one still return items in order.
+    state: Rc<Mutex<ScanState>>,
+}
+
+struct ScanState {
+    db_name: String,
+    // Where the next page starts, and the map root pages scan.
+    scan: dispatch::Scan,
+    // How many more items to return, if limited.
+    limit: Option<u64>,
+    items: VecDeque<(String, String)>,
+    // Whether there are no more pages to fetch.
+    done: bool,
+}
+
+#[wasm_bindgen]
+impl ScanIterator {
+    pub fn next(&self) -> ScanItemPromise {
+        let state = self.state.clone();
+        promise(async move {
+            let mut state = state.lock().await;
+            if state.items.is_empty() && !state.done {
+                state.fetch().await?;
+            }
+            let result = Object::new();
+            let item = match state.items.pop_front() {
+                Some(v) => v,
+                None => {
+                    set(&result, "done", &JsValue::TRUE)?;
+                    return Ok(result.into());
+                }
+            };
+            let value = Object::new();
+            set(&value, "key", &item.0.into())?;
+            set(&value, "value", &parse(&item.1)?)?;
+            set(&result, "done", &JsValue::FALSE)?;
+            set(&result, "value", &value)?;
+            Ok(result.into())
+        })
+    }
+}
+
+impl ScanState {
+    async fn fetch(&mut self) -> Result<(), String> {
+        let page = match self.limit {
+            Some(limit) => limit.min(SCAN_PAGE_SIZE),
+            None => SCAN_PAGE_SIZE,
+        };
+        if page == 0 {
+            self.done = true;
+            return Ok(());
+        }
+        let scan = dispatch::Scan {
+            prefix: self.scan.prefix.clone(),
+            start_key: self.scan.start_key.clone(),
+            start_exclusive: self.scan.start_exclusive,
+            limit: Some(page),
+            index_name: None,
+            root: self.scan.root,
+        };
+        let (items, cursor) = match dispatch::call(self.db_name.clone(), Rpc::Scan(scan)).await? {
+            Reply::Scan {
+                items,
+                cursor,
+                root,
+            } => {
+                // Later pages scan the map the first one did.
+                self.scan.root = root;
+                (items, cursor)
+            }
+            _ => return Err(unexpected()),
+        };
+        if let Some(limit) = self.limit.as_mut() {
+            *limit -= items.len() as u64;
+        }
+        self.items.extend(items);
+        match cursor {
+            Some(key) => {
+                self.scan.start_key = Some(key);
+                self.scan.start_exclusive = true;
+            }
+            None => self.done = true,
+        }
+        Ok(())
+    }
+}
+
+// Reads the map with the given root, if any, rather than the main
+// head's.
+async fn get(db_name: String, key: String, root: Option<Hash>) -> Result<JsValue, String> {
+    match dispatch::call(db_name, Rpc::Get(key, root)).await? {
+        Reply::Get(Some(value)) => parse(&value),
+        Reply::Get(None) => Ok(JsValue::UNDEFINED),
+        _ => Err(unexpected()),
+    }
+}
+
+async fn has(db_name: String, key: String, root: Option<Hash>) -> Result<JsValue, String> {
+    match dispatch::call(db_name, Rpc::Has(key, root)).await? {
+        Reply::Has(has) => Ok(has.into()),
+        _ => Err(unexpected()),
+    }
+}
+
+async fn put(db_name: String, entries: Vec<(String, String)>) -> Result<JsValue, String> {
+    dispatch::call(db_name, Rpc::Put(entries)).await?;
+    Ok(JsValue::UNDEFINED)
+}
+
+// Runs f as a promise of type T, rejecting with the error message if f
+// fails.
+fn promise<T: JsCast>(f: impl Future<Output = Result<JsValue, String>> + 'static) -> T {
+    future_to_promise(async move { f.await.map_err(|e| JsValue::from_str(&e)) }).unchecked_into()
+}
+
+fn parse(json: &str) -> Result<JsValue, String> {
+    JSON::parse(json).map_err(|e| format!("Invalid JSON value: {:?}", e))
+}
+
+// JSON.stringify() returns undefined rather than throwing for values
+// with no JSON form, such as undefined and functions.
+fn stringify(value: &JsValue) -> Result<String, String> {
+    match JSON::stringify(value) {
+        Ok(v) => JsValue::from(v)
+            .as_string()
+            .ok_or_else(|| "Invalid JSON value".to_string()),
+        Err(e) => Err(format!("Invalid JSON value: {:?}", e)),
+    }
+}
+
+fn set(target: &Object, key: &str, value: &JsValue) -> Result<(), String> {
+    match Reflect::set(target, &key.into(), value) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("{:?}", e)),
+    }
+}
+
+fn unexpected() -> String {
+    "Unexpected reply".into()
+}
+
 static INIT: Once = Once::new();
 
 fn init_panic_hook() {
     #[cfg(feature = "console_error_panic_hook")]
     console_error_panic_hook::set_once();
     INIT.call_once(|| {
-        if let Err(e) = console_log::init_with_level(log::Level::Info) {
+        if let Err(e) = trace::init() {
             web_sys::console::error_1(&format!("Error registering console_log: {}", e).into());
         }
     });
@@ -16,6 +16,22 @@ async fn dispatch(db: &str, rpc: &str, data: &str) -> Result<String, String> {
     }
 }
 
+// Opens db_name and returns its client id.
+async fn open(db_name: &str) -> String {
+    open_with(db_name, "", "idb").await
+}
+
+// Opens db_name with options and returns its client id, checking the
+// backend it was opened with.
+async fn open_with(db_name: &str, options: &str, backend: &str) -> String {
+    let response = dispatch(db_name, "open", options).await.unwrap();
+    let prefix = "{\"clientId\":\"";
+    let suffix = format!("\",\"backend\":\"{}\"}}", backend);
+    assert!(response.starts_with(prefix), "{}", response);
+    assert!(response.ends_with(&suffix), "{}", response);
+    response[prefix.len()..response.len() - suffix.len()].to_string()
+}
+
 #[wasm_bindgen_test]
 async fn test_dispatch() {
     assert_eq!(dispatch("", "debug", "open_dbs").await.unwrap(), "[]");
@@ -23,14 +39,28 @@ async fn test_dispatch() {
         dispatch("", "open", "").await.unwrap_err(),
         "db_name must be non-empty"
     );
-    assert_eq!(dispatch("db", "open", "").await.unwrap(), "");
+    let client_id = open("db").await;
     assert_eq!(dispatch("", "debug", "open_dbs").await.unwrap(), "[\"db\"]");
-    assert_eq!(dispatch("db2", "open", "").await.unwrap(), "");
+    let client_id2 = open("db2").await;
     assert_eq!(
         dispatch("", "debug", "open_dbs").await.unwrap(),
         "[\"db\", \"db2\"]"
     );
+
+    // Client ids are per database, and stable across opens.
+    assert_ne!(client_id, client_id2);
+    assert_eq!(client_id, open("db").await);
+    assert_eq!(
+        dispatch("db", "getClientId", "").await.unwrap(),
+        format!("{{\"clientId\":\"{}\"}}", client_id)
+    );
+    assert_eq!(dispatch("db", "close", "").await.unwrap(), "");
     assert_eq!(dispatch("db", "close", "").await.unwrap(), "");
+    assert_eq!(
+        dispatch("db", "getClientId", "").await.unwrap_err(),
+        "\"db\" not open"
+    );
+    assert_eq!(client_id, open("db").await);
     assert_eq!(dispatch("db", "close", "").await.unwrap(), "");
     assert_eq!(
         dispatch("", "debug", "open_dbs").await.unwrap(),
@@ -40,13 +70,107 @@ async fn test_dispatch() {
     assert_eq!(dispatch("", "debug", "open_dbs").await.unwrap(), "[]");
 }
 
+#[wasm_bindgen_test]
+async fn test_open_backends() {
+    assert_eq!(
+        dispatch("b", "open", "{\"backend\": \"floppy\"}")
+            .await
+            .unwrap_err(),
+        "Unknown backend \"floppy\""
+    );
+    assert_eq!(
+        dispatch("b", "open", "nope").await.unwrap_err(),
+        "Failed to parse request"
+    );
+
+    let client_id = open_with("b", "{\"backend\": \"mem\"}", "mem").await;
+    assert_eq!(
+        client_id,
+        open_with("b", "{\"backend\": \"mem\"}", "mem").await
+    );
+    assert_eq!(
+        dispatch("b", "open", "").await.unwrap_err(),
+        "\"b\" is already open with backend \"mem\""
+    );
+    dispatch("b", "put", "{\"key\": \"k\", \"value\": \"1\"}")
+        .await
+        .unwrap();
+    assert_eq!(
+        dispatch("b", "get", "{\"key\": \"k\"}").await.unwrap(),
+        "{\"value\":\"1\",\"has\":true}"
+    );
+    assert_eq!(dispatch("b", "close", "").await.unwrap(), "");
+
+    // Memory databases are gone once closed, client id and all.
+    assert_ne!(
+        client_id,
+        open_with("b", "{\"backend\": \"mem\"}", "mem").await
+    );
+    assert_eq!(
+        dispatch("b", "get", "{\"key\": \"k\"}").await.unwrap(),
+        "{\"has\":false}"
+    );
+    assert_eq!(dispatch("b", "close", "").await.unwrap(), "");
+}
+
+#[wasm_bindgen_test]
+async fn test_drop_list() {
+    async fn list() -> String {
+        dispatch("", "list", "").await.unwrap()
+    }
+    fn listed(list: &str, name: &str) -> bool {
+        list.contains(&format!("\"{}\"", name))
+    }
+
+    let client_id = open("dropped").await;
+    open_with("dropped-mem", "{\"backend\": \"mem\"}", "mem").await;
+    dispatch("dropped", "put", "{\"key\": \"k\", \"value\": \"1\"}")
+        .await
+        .unwrap();
+    let l = list().await;
+    assert!(l.starts_with("{\"databases\":["), "{}", l);
+    assert!(listed(&l, "dropped") && listed(&l, "dropped-mem"), "{}", l);
+    assert!(!listed(&l, "repc-databases"), "{}", l);
+
+    assert_eq!(dispatch("dropped", "drop", "").await.unwrap(), "");
+    assert_eq!(dispatch("dropped-mem", "drop", "").await.unwrap(), "");
+    let l = list().await;
+    assert!(
+        !listed(&l, "dropped") && !listed(&l, "dropped-mem"),
+        "{}",
+        l
+    );
+    assert_eq!(
+        dispatch("dropped", "get", "{\"key\": \"k\"}")
+            .await
+            .unwrap_err(),
+        "\"dropped\" not open"
+    );
+
+    // Its storage is gone, client id and all.
+    assert_ne!(client_id, open("dropped").await);
+    assert_eq!(
+        dispatch("dropped", "get", "{\"key\": \"k\"}")
+            .await
+            .unwrap(),
+        "{\"has\":false}"
+    );
+    assert_eq!(dispatch("dropped", "drop", "").await.unwrap(), "");
+    // Dropping what does not exist succeeds.
+    assert_eq!(dispatch("dropped", "drop", "").await.unwrap(), "");
+    assert_eq!(
+        dispatch("repc-databases", "drop", "").await.unwrap_err(),
+        "\"repc-databases\" is reserved"
+    );
+}
+
 #[wasm_bindgen_test]
 async fn test_get_put() {
     assert_eq!(
         dispatch("db", "put", "{\"k\", \"v\"}").await.unwrap_err(),
         "\"db\" not open"
     );
-    assert_eq!(dispatch("db", "open", "").await.unwrap(), "");
+    open("db").await;
 
     // Check request parsing, both missing and unexpected fields.
     assert_eq!(
@@ -64,16 +188,72 @@ async fn test_get_put() {
 
     // Simple put then get test.
     // TODO(nate): Resolve how to pass non-UTF-8 sequences through the API.
+    assert_eq!(
+        dispatch(
+            "db",
+            "put",
+            "{\"key\": \"Hello\", \"value\": \"\\\"世界\\\"\"}"
+        )
+        .await
+        .unwrap(),
+        ""
+    );
+    assert_eq!(
+        dispatch("db", "get", "{\"key\": \"Hello\"}").await.unwrap(),
+        "{\"value\":\"\\\"世界\\\"\",\"has\":true}"
+    );
+
+    // Values must be JSON, and are stored canonically.
     assert_eq!(
         dispatch("db", "put", "{\"key\": \"Hello\", \"value\": \"世界\"}")
             .await
-            .unwrap(),
+            .unwrap_err(),
+        "Invalid JSON value: unexpected character at 0"
+    );
+    assert_eq!(
+        dispatch(
+            "db",
+            "put",
+            "{\"key\": \"obj\", \"value\": \"{\\\"b\\\": 1.0, \\\"a\\\": []}\"}"
+        )
+        .await
+        .unwrap(),
         ""
     );
     assert_eq!(
-        dispatch("db", "get", "{\"key\": \"Hello\"}").await.unwrap(),
-        "{\"value\":\"世界\",\"has\":true}"
+        dispatch("db", "get", "{\"key\": \"obj\"}").await.unwrap(),
+        "{\"value\":\"{\\\"a\\\":[],\\\"b\\\":1}\",\"has\":true}"
+    );
+
+    // Large values are stored as blobs and read back whole.
+    let big: String = (0..20_000).map(|i| format!("{},", i)).collect();
+    let big = format!("[{}0]", big);
+    assert_eq!(
+        dispatch(
+            "db",
+            "put",
+            &format!("{{\"key\": \"big\", \"value\": \"{}\"}}", big)
+        )
+        .await
+        .unwrap(),
+        ""
     );
+    assert_eq!(
+        dispatch("db", "get", "{\"key\": \"big\"}").await.unwrap(),
+        format!("{{\"value\":\"{}\",\"has\":true}}", big)
+    );
+    // The blob's chunks are reachable from the value.
+    let report = dispatch("db", "debug", "check").await.unwrap();
+    assert!(report.ends_with(" 0 problems"), "{}", report);
+
+    // Reading it again is from the cache.
+    async fn cache_hits() -> u64 {
+        let stats = dispatch("db", "debug", "cache").await.unwrap();
+        stats.split(' ').next().unwrap().parse().unwrap()
+    }
+    let hits = cache_hits().await;
+    dispatch("db", "get", "{\"key\": \"big\"}").await.unwrap();
+    assert!(cache_hits().await > hits);
 
     // Verify functioning of non-ASCII keys.
     assert_eq!(
@@ -85,9 +265,13 @@ async fn test_get_put() {
         "{\"has\":false}"
     );
     assert_eq!(
-        dispatch("db", "put", "{\"key\": \"你好\", \"value\": \"world\"}")
-            .await
-            .unwrap(),
+        dispatch(
+            "db",
+            "put",
+            "{\"key\": \"你好\", \"value\": \"\\\"world\\\"\"}"
+        )
+        .await
+        .unwrap(),
         ""
     );
     assert_eq!(
@@ -96,8 +280,524 @@ async fn test_get_put() {
     );
     assert_eq!(
         dispatch("db", "get", "{\"key\": \"你好\"}").await.unwrap(),
-        "{\"value\":\"world\",\"has\":true}"
+        "{\"value\":\"\\\"world\\\"\",\"has\":true}"
     );
 
     assert_eq!(dispatch("db", "close", "").await.unwrap(), "");
 }
+
+#[wasm_bindgen_test]
+async fn test_scan() {
+    open("scan").await;
+    for key in &["a", "b", "ba", "bb", "c"] {
+        let data = format!("{{\"key\": \"{}\", \"value\": \"\\\"{}\\\"\"}}", key, key);
+        assert_eq!(dispatch("scan", "put", &data).await.unwrap(), "");
+    }
+    let all = dispatch("scan", "scan", "{}").await.unwrap();
+    let root = all.split("\"root\":\"").nth(1).unwrap();
+    let root = root[..root.find('"').unwrap()].to_string();
+
+    async fn test(root: &str, data: &str, expected_keys: &[&str], expected_cursor: Option<&str>) {
+        let items = expected_keys
+            .iter()
+            .map(|k| format!("{{\"key\":\"{}\",\"value\":\"\\\"{}\\\"\"}}", k, k))
+            .collect::<Vec<String>>()
+            .join(",");
+        let expected = match expected_cursor {
+            Some(c) => format!(
+                "{{\"cursor\":\"{}\",\"root\":\"{}\",\"items\":[{}]}}",
+                c, root, items
+            ),
+            None => format!("{{\"root\":\"{}\",\"items\":[{}]}}", root, items),
+        };
+        assert_eq!(dispatch("scan", "scan", data).await.unwrap(), expected);
+    }
+
+    test(&root, "{}", &["a", "b", "ba", "bb", "c"], None).await;
+    test(&root, "{\"prefix\": \"b\"}", &["b", "ba", "bb"], None).await;
+    test(&root, "{\"prefix\": \"d\"}", &[], None).await;
+    test(&root, "{\"limit\": 5}", &["a", "b", "ba", "bb", "c"], None).await;
+    // More than fits in 32 bits.
+    test(
+        &root,
+        "{\"limit\": 4294967296}",
+        &["a", "b", "ba", "bb", "c"],
+        None,
+    )
+    .await;
+
+    // Page through with the returned cursor.
+    test(&root, "{\"limit\": 2}", &["a", "b"], Some("b")).await;
+    test(
+        &root,
+        "{\"start\": {\"key\": \"b\", \"exclusive\": true}, \"limit\": 2}",
+        &["ba", "bb"],
+        Some("bb"),
+    )
+    .await;
+    test(
+        &root,
+        "{\"start\": {\"key\": \"bb\", \"exclusive\": true}, \"limit\": 2}",
+        &["c"],
+        None,
+    )
+    .await;
+    test(&root, "{\"start\": {\"key\": \"bb\"}}", &["bb", "c"], None).await;
+    test(
+        &root,
+        "{\"prefix\": \"b\", \"start\": {\"key\": \"b\", \"exclusive\": true}}",
+        &["ba", "bb"],
+        None,
+    )
+    .await;
+
+    // Pages that pass the root back see the entries the first page did.
+    let data = "{\"key\": \"bc\", \"value\": \"1\"}";
+    assert_eq!(dispatch("scan", "put", data).await.unwrap(), "");
+    let data = format!(
+        "{{\"start\": {{\"key\": \"bb\", \"exclusive\": true}}, \"root\": \"{}\"}}",
+        root
+    );
+    test(&root, &data, &["c"], None).await;
+    assert_eq!(
+        dispatch("scan", "scan", "{\"root\": \"nope\"}")
+            .await
+            .unwrap_err(),
+        "Invalid root"
+    );
+
+    assert_eq!(
+        dispatch("scan", "scan", "{\"indexName\": \"idx\"}")
+            .await
+            .unwrap_err(),
+        "Unknown index \"idx\""
+    );
+
+    assert_eq!(dispatch("scan", "close", "").await.unwrap(), "");
+}
+
+#[wasm_bindgen_test]
+async fn test_archive() {
+    open("archive").await;
+    assert_eq!(
+        dispatch("archive", "export", "{\"head\": \"main\"}")
+            .await
+            .unwrap_err(),
+        "Unknown head \"main\""
+    );
+    assert_eq!(
+        dispatch("archive", "import", "{\"archive\": \"!\"}")
+            .await
+            .unwrap_err()
+            .split(':')
+            .next(),
+        Some("Invalid archive encoding")
+    );
+    assert_eq!(
+        dispatch("archive", "import", "{\"archive\": \"AAAA\"}")
+            .await
+            .unwrap_err(),
+        "Not an archive"
+    );
+
+    // An empty archive imports, and sets no heads.
+    let empty = [&b"repcdag\0"[..], &[1, 0, 0, 0], &[0; 4], &[0; 4]].concat();
+    let data = format!(
+        "{{\"archive\": \"{}\"}}",
+        data_encoding::base64::encode(&empty)
+    );
+    assert_eq!(dispatch("archive", "import", &data).await.unwrap(), "");
+    assert_eq!(
+        dispatch("archive", "export", "{\"head\": \"main\"}")
+            .await
+            .unwrap_err(),
+        "Unknown head \"main\""
+    );
+
+    // Entries, blobs included, survive an export and import into
+    // another database.
+    let big: String = (0..5000).map(|i| format!("{},", i)).collect();
+    let big = format!("[{}0]", big);
+    for (key, value) in [("a", "1"), ("big", big.as_str())].iter() {
+        let data = format!("{{\"key\": \"{}\", \"value\": \"{}\"}}", key, value);
+        assert_eq!(dispatch("archive", "put", &data).await.unwrap(), "");
+    }
+    let archive = dispatch("archive", "export", "{}").await.unwrap();
+    open("archive_copy").await;
+    assert_eq!(
+        dispatch("archive_copy", "import", &archive).await.unwrap(),
+        ""
+    );
+    assert_eq!(
+        dispatch("archive_copy", "get", "{\"key\": \"big\"}")
+            .await
+            .unwrap(),
+        format!("{{\"value\":\"{}\",\"has\":true}}", big)
+    );
+    let page = dispatch("archive_copy", "scan", "{\"limit\": 1}")
+        .await
+        .unwrap();
+    assert!(
+        page.starts_with("{\"cursor\":\"a\",\"root\":\""),
+        "{}",
+        page
+    );
+    assert!(
+        page.ends_with("\"items\":[{\"key\":\"a\",\"value\":\"1\"}]}"),
+        "{}",
+        page
+    );
+
+    assert_eq!(dispatch("archive", "close", "").await.unwrap(), "");
+    assert_eq!(dispatch("archive_copy", "close", "").await.unwrap(), "");
+}
+
+#[wasm_bindgen_test]
+async fn test_concurrent_dispatch() {
+    use futures::future::join_all;
+    use std::cell::RefCell;
+
+    open("slow").await;
+    open("fast").await;
+    let put = |db: &'static str, key: &str, value: &str| {
+        let data = format!("{{\"key\": \"{}\", \"value\": \"{}\"}}", key, value);
+        async move { dispatch(db, "put", &data).await }
+    };
+    put("fast", "k", "1").await.unwrap();
+    put("slow", "k", "1").await.unwrap();
+
+    // Reads, of this database or another, complete while a long write
+    // is in flight.
+    let big: String = (0..200_000).map(|i| format!("{},", i)).collect();
+    let big = format!("[{}0]", big);
+    let done = RefCell::new(Vec::new());
+    let log = |name: &'static str, response: Result<String, String>| {
+        done.borrow_mut().push(name);
+        response
+    };
+    let write = async { log("write", put("slow", "big", &big).await) };
+    let reads = join_all(
+        (0..5).map(|_| async { log("read", dispatch("fast", "get", "{\"key\": \"k\"}").await) }),
+    );
+    let (write, reads) = futures::join!(write, reads);
+    write.unwrap();
+    for r in reads {
+        assert_eq!("{\"value\":\"1\",\"has\":true}", r.unwrap());
+    }
+    assert_eq!(Some(&"write"), done.borrow().last(), "{:?}", done.borrow());
+
+    // So do reads of the database being written, which see it as it was
+    // before the write.
+    done.borrow_mut().clear();
+    let big = big.replace("[", "[-1,");
+    let write = async { log("write", put("slow", "big", &big).await) };
+    let reads = join_all(
+        (0..5).map(|_| async { log("read", dispatch("slow", "get", "{\"key\": \"k\"}").await) }),
+    );
+    let (write, reads) = futures::join!(write, reads);
+    write.unwrap();
+    for r in reads {
+        assert_eq!("{\"value\":\"1\",\"has\":true}", r.unwrap());
+    }
+    assert_eq!(Some(&"write"), done.borrow().last(), "{:?}", done.borrow());
+    assert!(dispatch("slow", "get", "{\"key\": \"big\"}")
+        .await
+        .unwrap()
+        .starts_with("{\"value\":\"[-1,0,"));
+
+    // Writes to a database complete in the order they were dispatched,
+    // and reads see either the state before or after a write in flight.
+    let writes = join_all((0..10).map(|i| put("slow", "k", &i.to_string())));
+    let read = dispatch("slow", "get", "{\"key\": \"k\"}");
+    let (writes, read) = futures::join!(writes, read);
+    assert!(writes.iter().all(|r| r.is_ok()));
+    assert!(read.unwrap().starts_with("{\"value\":\""));
+    assert_eq!(
+        "{\"value\":\"9\",\"has\":true}",
+        dispatch("slow", "get", "{\"key\": \"k\"}").await.unwrap()
+    );
+
+    assert_eq!(dispatch("slow", "close", "").await.unwrap(), "");
+    assert_eq!(dispatch("fast", "close", "").await.unwrap(), "");
+}
+
+#[wasm_bindgen_test]
+async fn test_log_level() {
+    assert_eq!(
+        dispatch("", "setLogLevel", "{\"level\": \"loud\"}")
+            .await
+            .unwrap_err(),
+        "Unknown log level \"loud\""
+    );
+    assert_eq!(
+        dispatch("", "setLogLevel", "").await.unwrap_err(),
+        "Failed to parse request"
+    );
+
+    // No events are kept until asked for.
+    assert_eq!(dispatch("", "debug", "events").await.unwrap(), "");
+    let set = "{\"level\": \"debug\", \"bufferSize\": 100}";
+    assert_eq!(dispatch("", "setLogLevel", set).await.unwrap(), "");
+    open_with("log", "{\"backend\": \"mem\"}", "mem").await;
+    dispatch("log", "get", "{\"key\": \"k\"}").await.unwrap();
+    dispatch("log", "put", "{\"key\": \"k\"}")
+        .await
+        .unwrap_err();
+
+    // Requests and the kv transactions they run are timed, and failures
+    // logged with their error.
+    let events = dispatch("", "debug", "events").await.unwrap();
+    let has = |pattern: &str| events.lines().any(|e| e.contains(pattern));
+    assert!(has(" get \"log\" took "), "{}", events);
+    assert!(has("kv read transaction took "), "{}", events);
+    assert!(has(" put \"log\" failed after "), "{}", events);
+    assert!(events.lines().all(|e| !e.contains(" TRACE ")), "{}", events);
+
+    let set = "{\"level\": \"info\", \"bufferSize\": 0}";
+    assert_eq!(dispatch("", "setLogLevel", set).await.unwrap(), "");
+    assert_eq!(dispatch("", "debug", "events").await.unwrap(), "");
+    assert_eq!(dispatch("log", "close", "").await.unwrap(), "");
+}
+
+// Exercises the class API the way JS sees it, calling methods by name.
+#[wasm_bindgen_test]
+async fn test_class_api() {
+    use js_sys::{Array, Function, Reflect, Symbol, JSON};
+    use wasm::Database;
+    use wasm_bindgen::{JsCast, JsValue};
+    use wasm_bindgen_futures::JsFuture;
+
+    fn json(s: &str) -> JsValue {
+        JSON::parse(s).unwrap()
+    }
+    fn to_json(v: &JsValue) -> String {
+        JSON::stringify(v).unwrap().into()
+    }
+    fn field(v: &JsValue, name: &str) -> JsValue {
+        Reflect::get(v, &name.into()).unwrap()
+    }
+    fn call(this: &JsValue, method: &str, args: &[JsValue]) -> Result<JsValue, JsValue> {
+        let f: Function = field(this, method).unchecked_into();
+        f.apply(this, &args.iter().collect::<Array>())
+    }
+    // Calls a method that returns a promise, returning a future of its
+    // result.
+    fn call_async(
+        this: &JsValue,
+        method: &str,
+        args: &[JsValue],
+    ) -> impl std::future::Future<Output = Result<JsValue, String>> {
+        let promise: js_sys::Promise = call(this, method, args).unwrap().unchecked_into();
+        async move {
+            JsFuture::from(promise)
+                .await
+                .map_err(|e| e.as_string().unwrap())
+        }
+    }
+
+    let options = json("{\"backend\": \"mem\"}").unchecked_into();
+    let db = JsFuture::from(
+        Database::open("class".into(), Some(options)).unchecked_into::<js_sys::Promise>(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(Some("class".into()), field(&db, "name").as_string());
+    assert_eq!(Some("mem".into()), field(&db, "backend").as_string());
+    assert_eq!(
+        format!(
+            "{{\"clientId\":\"{}\"}}",
+            field(&db, "clientId").as_string().unwrap()
+        ),
+        dispatch("class", "getClientId", "").await.unwrap()
+    );
+
+    // Values go in and come out as JS values, not JSON strings.
+    let get = |key: &str| call_async(&db, "get", &[key.into()]);
+    call_async(&db, "put", &["a".into(), json("{\"x\": [1, 2]}")])
+        .await
+        .unwrap();
+    assert_eq!("{\"x\":[1,2]}", to_json(&get("a").await.unwrap()));
+    assert!(get("nope").await.unwrap().is_undefined());
+    assert_eq!(
+        Some(true),
+        call_async(&db, "has", &["a".into()])
+            .await
+            .unwrap()
+            .as_bool()
+    );
+    assert_eq!(
+        "Invalid JSON value",
+        call_async(&db, "put", &["b".into(), JsValue::UNDEFINED])
+            .await
+            .unwrap_err()
+    );
+
+    // Transactions read their own puts, which others see on commit.
+    let tx = call(&db, "transaction", &[]).unwrap();
+    call(&tx, "put", &["b".into(), json("\"b\"")]).unwrap();
+    call(&tx, "put", &["c".into(), json("3")]).unwrap();
+    assert!(call(&tx, "put", &["d".into(), JsValue::UNDEFINED]).is_err());
+    let tx_get = |key: &str| call_async(&tx, "get", &[key.into()]);
+    assert_eq!("\"b\"", to_json(&tx_get("b").await.unwrap()));
+    assert_eq!("{\"x\":[1,2]}", to_json(&tx_get("a").await.unwrap()));
+    assert!(get("b").await.unwrap().is_undefined());
+    call_async(&tx, "commit", &[]).await.unwrap();
+    assert_eq!("3", to_json(&get("c").await.unwrap()));
+
+    // They read the entries as they were when they began, and fail to
+    // commit once others have written since.
+    let tx = call(&db, "transaction", &[]).unwrap();
+    let tx_get = |key: &str| call_async(&tx, "get", &[key.into()]);
+    call_async(&db, "put", &["c".into(), json("4")])
+        .await
+        .unwrap();
+    assert_eq!("3", to_json(&tx_get("c").await.unwrap()));
+    assert_eq!(
+        Some(false),
+        call_async(&tx, "has", &["e".into()])
+            .await
+            .unwrap()
+            .as_bool()
+    );
+    call(&tx, "put", &["e".into(), json("5")]).unwrap();
+    assert_eq!(
+        "Transaction conflicts with a write made since it began",
+        call_async(&tx, "commit", &[]).await.unwrap_err()
+    );
+    assert!(get("e").await.unwrap().is_undefined());
+
+    // Scans are async iterators, paging through any number of items.
+    let tx = call(&db, "transaction", &[]).unwrap();
+    for i in 0..250 {
+        call(&tx, "put", &[format!("p/{:03}", i).into(), i.into()]).unwrap();
+    }
+    call_async(&tx, "commit", &[]).await.unwrap();
+    let keys = |options: &str| {
+        let scan = call(&db, "scan", &[json(options)]).unwrap();
+        async move {
+            let f: Function = Reflect::get(&scan, &Symbol::async_iterator())
+                .unwrap()
+                .unchecked_into();
+            let iter = f.call0(&scan).unwrap();
+            let mut keys = Vec::new();
+            loop {
+                let result = call_async(&iter, "next", &[]).await.unwrap();
+                if field(&result, "done").as_bool() == Some(true) {
+                    return keys;
+                }
+                keys.push(field(&field(&result, "value"), "key").as_string().unwrap());
+            }
+        }
+    };
+    let all = keys("{\"prefix\": \"p/\"}").await;
+    assert_eq!(250, all.len());
+    assert_eq!(("p/000", "p/249"), (&all[0][..], &all[249][..]));
+    let some = keys(
+        "{\"prefix\": \"p/\", \"start\": {\"key\": \"p/050\", \"exclusive\": true}, \"limit\": 120}",
+    )
+    .await;
+    assert_eq!(120, some.len());
+    assert_eq!(("p/051", "p/170"), (&some[0][..], &some[119][..]));
+    assert_eq!(vec!["a", "b", "c"], keys("{\"limit\": 3}").await);
+
+    call_async(&db, "close", &[]).await.unwrap();
+    assert_eq!("\"class\" not open", get("a").await.unwrap_err());
+}
+
+// Not a test as such, but wasm counterpart of benches/chunker.rs, so it
+// only runs with the bench feature. Look for the timings in the console
+// output.
+#[cfg(feature = "bench")]
+#[wasm_bindgen_test]
+fn bench_chunker() {
+    use replicache_client::prolly::chunker::Chunker;
+    const LEN: usize = 4 << 20;
+    let mut x: u32 = 1;
+    let data: Vec<u8> = (0..LEN)
+        .map(|_| {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (x >> 16) as u8
+        })
+        .collect();
+
+    let start = js_sys::Date::now();
+    let expected = Chunker::default().boundaries_bytewise(&data);
+    let bytewise = js_sys::Date::now() - start;
+    let start = js_sys::Date::now();
+    let ends: Vec<usize> = Chunker::default().boundaries(&data).collect();
+    let slicewise = js_sys::Date::now() - start;
+    assert_eq!(expected, ends);
+
+    console_log!(
+        "{} MB, {} chunks: hash_byte {}ms, boundaries {}ms",
+        LEN >> 20,
+        ends.len(),
+        bytewise,
+        slicewise
+    );
+}
+
+// Compares reading chunks from IDB as the data and meta keys of the old
+// layout with reading them as one record. Look for the timings in the
+// console output.
+#[wasm_bindgen_test]
+async fn bench_chunk_layout() {
+    use replicache_client::dag::chunk::Chunk;
+    use replicache_client::dag::key::Key;
+    use replicache_client::dag::write;
+    use replicache_client::hash::Hash;
+
+    const N: usize = 500;
+    let store = wasm::new_idbstore("bench_chunk_layout".into())
+        .await
+        .unwrap();
+    let chunks: Vec<Chunk> = (0..N)
+        .map(|i| {
+            let data = format!("chunk {}", i).repeat(20).into_bytes();
+            Chunk::new(Hash::of(&data), data, &[Hash::of(b"r")])
+        })
+        .collect();
+    let wt = store.write().await.unwrap();
+    for c in chunks.iter() {
+        wt.put(&Key::ChunkData(*c.hash()).to_string(), c.data())
+            .await
+            .unwrap();
+        wt.put(&Key::ChunkMeta(*c.hash()).to_string(), c.meta().unwrap())
+            .await
+            .unwrap();
+        write::put_chunk(wt.as_ref(), c).await.unwrap();
+    }
+    wt.commit().await.unwrap();
+
+    let rt = store.read().await.unwrap();
+    let start = js_sys::Date::now();
+    for c in chunks.iter() {
+        let d = rt.get(&Key::ChunkData(*c.hash()).to_string()).await;
+        let m = rt.get(&Key::ChunkMeta(*c.hash()).to_string()).await;
+        assert!(d.unwrap().is_some() && m.unwrap().is_some());
+    }
+    let two_keys = js_sys::Date::now() - start;
+    let start = js_sys::Date::now();
+    for c in chunks.iter() {
+        let r = rt.get(&Key::Chunk(*c.hash()).to_string()).await;
+        assert!(r.unwrap().is_some());
+    }
+    let record = js_sys::Date::now() - start;
+    let keys: Vec<String> = chunks
+        .iter()
+        .map(|c| Key::Chunk(*c.hash()).to_string())
+        .collect();
+    let keys: Vec<&str> = keys.iter().map(|k| k.as_str()).collect();
+    let start = js_sys::Date::now();
+    let records = rt.get_many(&keys).await.unwrap();
+    let batch = js_sys::Date::now() - start;
+    assert!(records.iter().all(|r| r.is_some()));
+
+    console_log!(
+        "{} chunks: data and meta keys {}ms, one record {}ms, get_many {}ms",
+        N,
+        two_keys,
+        record,
+        batch
+    );
+}
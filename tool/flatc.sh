@@ -12,6 +12,6 @@ trap atexit EXIT
 
 flatc --rust -o $TMP src/dag/meta.fbs
 rustfmt $TMP/meta_generated.rs
-echo "#![allow(clippy::redundant_field_names)]\n" | \
+echo "#![allow(clippy::redundant_field_names, clippy::redundant_static_lifetimes)]\n" | \
     cat - $TMP/meta_generated.rs > $TMP/meta_generated.rs.clippy
 mv $TMP/meta_generated.rs.clippy src/dag/meta_generated.rs
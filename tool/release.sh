@@ -3,9 +3,21 @@
 DIR="$( cd "$( dirname "$0" )" >/dev/null 2>&1 && pwd )"
 ROOT=$DIR/../
 
+# Prints the size of each bundle file, and how it changed since the
+# previous build, which is kept in pkg.prev/.
 report() {
-    (cd pkg/ && ls -l replicache_client.js* replicache_client_bg.was*[mr] |
-        awk '{print $9 ": " $5}')
+    for f in replicache_client.js replicache_client.js.br \
+        replicache_client_bg.wasm replicache_client_bg.wasm.br; do
+        size=$(wc -c < pkg/$f)
+        if [ -f pkg.prev/$f ]; then
+            prev=$(wc -c < pkg.prev/$f)
+            echo "$f: $size ($((size - prev)) since previous build)"
+        else
+            echo "$f: $size"
+        fi
+    done
+    # Chunk data compression, whose codec is part of the bundle.
+    cargo bench -q --bench compression
 }
 
 if [ $# -eq 1 -a "$1" == "--report" ]; then
@@ -27,7 +39,10 @@ fi
     # So hack hack hack for now.
     sed -i .bak 's/crate-type = \["cdylib", "rlib"\]/crate-type = ["cdylib"]/' Cargo.toml
 
-    rm -rf pkg
+    rm -rf pkg.prev
+    if [ -d pkg ]; then
+        mv pkg pkg.prev
+    fi
     wasm-pack build --profiling -t web -- --no-default-features
     mv pkg/replicache_client_bg.wasm pkg/replicache_client_bg.wasm.debug
     wasm-pack build --release -t web -- --no-default-features